@@ -0,0 +1,257 @@
+// Store introspection and maintenance.
+//
+// crb-store task <path> namespaces
+// crb-store task <path> show <key>
+// crb-store task <path> errors
+// crb-store task <path> delete-prefix <prefix>
+// crb-store task <path> compact
+// crb-store task <path> migrate
+//
+// crb-store bot <path> namespaces
+// crb-store bot <path> show <prefix>        (use 0x.. for a hex encoded prefix)
+// crb-store bot <path> delete-prefix <prefix>
+// crb-store bot <path> compact
+// crb-store bot <path> migrate [subscription path] [notification path]   (the subscription db defaults to <path>,
+//                                                                        user hashes are only migrated with the notification db)
+// crb-store bot <path> role <user hash> <user|admin>   (<path> is the subscription db)
+//
+// sled has no read-only mode and locks the database, stop the bot before running this tool.
+// Only `delete-prefix`, `compact`, `migrate` and `role` modify the store.
+
+use std::collections::BTreeMap;
+
+use cosmos_rust_interface::utils::entry::db::{access, audit, load_sled_db, migration, CosmosRustBotStore, RetrievalMethod, SubscriptionStore, TaskMemoryStore};
+use cosmos_rust_interface::utils::entry::{CosmosRustBotValue, Maybe, Role};
+use cosmos_rust_interface::utils::response::ResponseResult;
+
+const USAGE: &str = "Usage: crb-store <task|bot> <path> <namespaces|show|errors|delete-prefix|compact|migrate|role> [arg] [arg]";
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        return Err(anyhow::anyhow!(USAGE));
+    }
+    let (kind, path, command, arg) = (args[1].as_str(), args[2].as_str(), args[3].as_str(), args.get(4).map(|x| x.as_str()));
+
+    if command == "compact" {
+        return compact(path);
+    }
+
+    match (kind, command, arg) {
+        ("task", "namespaces", _) => task_namespaces(path),
+        ("task", "show", Some(key)) => task_show(path, key),
+        ("task", "errors", _) => task_errors(path),
+        ("task", "delete-prefix", Some(prefix)) => task_delete_prefix(path, prefix),
+        ("bot", "namespaces", _) => bot_namespaces(path),
+        ("bot", "show", Some(prefix)) => bot_show(path, prefix),
+        ("bot", "delete-prefix", Some(prefix)) => bot_delete_prefix(path, prefix),
+        #[cfg(feature = "interface")]
+        ("task", "migrate", _) => task_migrate(path),
+        ("bot", "migrate", subscription_path) => bot_migrate(path, subscription_path, args.get(5).map(|x| x.as_str())),
+        ("bot", "role", Some(user_hash)) => bot_role(path, user_hash, args.get(5).map(|x| x.as_str())),
+        _ => Err(anyhow::anyhow!(USAGE)),
+    }
+}
+
+// hashes are replaced by `*`, e.g. GPT3_embedding_1234567890 -> GPT3_embedding_*
+fn task_namespace(key: &str) -> String {
+    let mut namespace = String::new();
+    let mut digits = String::new();
+    for c in key.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+        } else {
+            namespace.push_str(if digits.len() >= 6 { "*" } else { &digits });
+            digits.clear();
+            namespace.push(c);
+        }
+    }
+    namespace.push_str(if digits.len() >= 6 { "*" } else { &digits });
+    namespace
+}
+
+fn bot_namespace(key: &[u8]) -> String {
+    key.iter().take_while(|x| x.is_ascii_alphabetic()).map(|x| *x as char).collect()
+}
+
+fn print_namespaces(namespaces: BTreeMap<String,(usize,usize)>) {
+    println!("{:<60} {:>10} {:>14}", "namespace", "count", "bytes");
+    for (namespace, (count, size)) in namespaces {
+        println!("{:<60} {:>10} {:>14}", namespace, count, size);
+    }
+}
+
+fn task_namespaces(path: &str) -> anyhow::Result<()> {
+    let task_store = TaskMemoryStore::new(Some(path.to_string()))?;
+    let mut namespaces: BTreeMap<String,(usize,usize)> = BTreeMap::new();
+    for (key, _rev, size) in task_store.revision_iter() {
+        let entry = namespaces.entry(task_namespace(&key)).or_insert((0,0));
+        entry.0 += 1;
+        entry.1 += size;
+    }
+    print_namespaces(namespaces);
+    Ok(())
+}
+
+fn task_show(path: &str, key: &str) -> anyhow::Result<()> {
+    let task_store = TaskMemoryStore::new(Some(path.to_string()))?;
+    let revisions = task_store.get_revisions::<ResponseResult>(key)?;
+    if revisions.is_empty() {
+        println!("key does not exist: {}", key);
+    }
+    for (rev, value) in revisions {
+        println!("rev {}:\n{}", rev, serde_json::to_string_pretty(&value)?);
+    }
+    Ok(())
+}
+
+fn task_errors(path: &str) -> anyhow::Result<()> {
+    let task_store = TaskMemoryStore::new(Some(path.to_string()))?;
+    for (key, value) in task_store.value_iter::<ResponseResult>(&RetrievalMethod::Get) {
+        if let Maybe { data: Err(err), timestamp } = value {
+            println!("{} [{}]: {}", key, timestamp, err);
+        }
+    }
+    Ok(())
+}
+
+fn task_delete_prefix(path: &str, prefix: &str) -> anyhow::Result<()> {
+    let task_store = TaskMemoryStore::new(Some(path.to_string()))?;
+    let keys = task_store.key_iter().filter(|x| x.starts_with(prefix)).collect::<Vec<String>>();
+    for key in &keys {
+        task_store.remove_key(key)?;
+    }
+    audit::record(task_store.get_tree(), None, audit::AuditAction::DeletePrefix, format!("task {}: {} keys", prefix, keys.len()));
+    task_store.get_tree().flush()?;
+    println!("deleted {} keys", keys.len());
+    Ok(())
+}
+
+fn parse_prefix(prefix: &str) -> anyhow::Result<Vec<u8>> {
+    match prefix.strip_prefix("0x") {
+        Some(hex) => {
+            (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or(""), 16).map_err(|err| anyhow::anyhow!(err)))
+                .collect()
+        },
+        None => Ok(prefix.as_bytes().to_vec()),
+    }
+}
+
+fn bot_namespaces(path: &str) -> anyhow::Result<()> {
+    let db = load_sled_db(path);
+    let mut namespaces: BTreeMap<String,(usize,usize)> = BTreeMap::new();
+    for item in db.iter() {
+        let (key, value) = item?;
+        let entry = namespaces.entry(bot_namespace(&key)).or_insert((0,0));
+        entry.0 += 1;
+        entry.1 += key.len() + value.len();
+    }
+    print_namespaces(namespaces);
+    Ok(())
+}
+
+fn bot_show(path: &str, prefix: &str) -> anyhow::Result<()> {
+    let db = load_sled_db(path);
+    for item in db.scan_prefix(parse_prefix(prefix)?) {
+        let (key, value) = item?;
+        let key_hex = key.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+        match CosmosRustBotValue::try_from(value.to_vec()) {
+            Ok(value) => println!("0x{}:\n{}", key_hex, serde_json::to_string_pretty(&value)?),
+            Err(err) => println!("0x{}: {}", key_hex, err),
+        }
+    }
+    Ok(())
+}
+
+fn bot_delete_prefix(path: &str, prefix: &str) -> anyhow::Result<()> {
+    let db = load_sled_db(path);
+    let keys = db.scan_prefix(parse_prefix(prefix)?).keys().collect::<sled::Result<Vec<sled::IVec>>>()?;
+    for key in &keys {
+        db.remove(key)?;
+    }
+    audit::record(&db, None, audit::AuditAction::DeletePrefix, format!("bot {}: {} keys", prefix, keys.len()));
+    db.flush()?;
+    println!("deleted {} keys", keys.len());
+    Ok(())
+}
+
+// the renames are derived from the stored task results, see `migration::migrate_task_memory_store`.
+#[cfg(feature = "interface")]
+fn task_migrate(path: &str) -> anyhow::Result<()> {
+    use cosmos_rust_interface::blockchain::cosmos::gov;
+    use cosmos_rust_interface::services::{gpt3, link_to_text};
+
+    let task_store = TaskMemoryStore::new(Some(path.to_string()))?;
+    let mut renames = link_to_text::legacy_key_renames(&task_store);
+    renames.append(&mut gpt3::legacy_key_renames(&task_store));
+    migration::migrate_task_memory_store(&task_store, renames, gov::legacy_page_keys(&task_store))?;
+    audit::record(task_store.get_tree(), None, audit::AuditAction::Migrate, format!("task {}", path));
+    task_store.get_tree().flush()?;
+    println!("migrated {}", path);
+    Ok(())
+}
+
+// opening the store re-keys the entries and applies the layout migrations,
+// the legacy user hashes are looked up in the user meta data export, or in the given notification db.
+fn bot_migrate(path: &str, subscription_path: Option<&str>, notification_path: Option<&str>) -> anyhow::Result<()> {
+    let entry_index_db = load_sled_db(path);
+    let subscription_db = match subscription_path {
+        Some(subscription_path) if subscription_path != path => load_sled_db(subscription_path),
+        _ => entry_index_db.clone(),
+    };
+    let store = CosmosRustBotStore::new(entry_index_db.clone(), SubscriptionStore::new(&subscription_db))?;
+    if let Some(notification_path) = notification_path {
+        let notification_db = load_sled_db(notification_path);
+        let user_hashes = migration::legacy_user_hashes(&notification_db);
+        migration::migrate_notification_db(&notification_db, &user_hashes)?;
+        migration::migrate_cosmos_rust_bot_store(&store, &user_hashes)?;
+        audit::record(&notification_db, None, audit::AuditAction::Migrate, "stable user hashes".to_string());
+        notification_db.flush()?;
+    }
+    audit::record(&subscription_db, None, audit::AuditAction::Migrate, format!("bot {}", path));
+    entry_index_db.flush()?;
+    subscription_db.flush()?;
+    println!("migrated {}", path);
+    Ok(())
+}
+
+// admin roles only apply to queries with a valid token of the user, see `access::get_role`.
+fn bot_role(path: &str, user_hash: &str, role: Option<&str>) -> anyhow::Result<()> {
+    let user_hash: u64 = user_hash.parse()?;
+    let role = match role {
+        Some("user") => Role::User,
+        Some("admin") => Role::Admin,
+        _ => { return Err(anyhow::anyhow!(USAGE)); },
+    };
+    let db = load_sled_db(path);
+    access::set_role(&SubscriptionStore::new(&db), user_hash, role)?;
+    db.flush()?;
+    println!("user {} is now {}", user_hash, role);
+    Ok(())
+}
+
+// rewrites the database into a new directory and swaps it in, the old one is kept as <path>.bak
+fn compact(path: &str) -> anyhow::Result<()> {
+    let compacted_path = format!("{}.compacted", path);
+    let backup_path = format!("{}.bak", path);
+    // e.g. the backup of a previous run, or the copy of an interrupted one.
+    for existing in [&backup_path, &compacted_path] {
+        if std::path::Path::new(existing).exists() {
+            return Err(anyhow::anyhow!("{} already exists, remove it before compacting {}", existing, path));
+        }
+    }
+    {
+        let db = load_sled_db(path);
+        let size_before = db.size_on_disk()?;
+        let compacted = load_sled_db(&compacted_path);
+        compacted.import(db.export());
+        audit::record(&compacted, None, audit::AuditAction::Compact, format!("{}: {} bytes before", path, size_before));
+        compacted.flush()?;
+        println!("size on disk: {} -> {} bytes", size_before, compacted.size_on_disk()?);
+    }
+    std::fs::rename(path, &backup_path)?;
+    std::fs::rename(&compacted_path, path)?;
+    println!("compacted {}, previous version kept at {}", path, backup_path);
+    Ok(())
+}
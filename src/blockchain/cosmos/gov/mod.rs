@@ -5,8 +5,7 @@ use cosmos_rust_package::api::custom::types::gov::proposal_ext::{ProposalExt, Pr
 use crate::utils::entry::db::{RetrievalMethod, TaskMemoryStore};
 use crate::utils::entry::Maybe;
 use crate::utils::response::{BlockchainQuery, ResponseResult, TaskResult};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use crate::utils::hash::stable_hash;
 use std::time::Duration;
 use cosmos_rust_package::tokio::time::{Instant, sleep, sleep_until};
 
@@ -23,9 +22,13 @@ pub fn get_key_for_params(blockchain_name: &str, params_type: &str) -> String {
 }
 
 fn hash_vec_u8(vec: &Vec<u8>) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    vec.hash(&mut hasher);
-    hasher.finish()
+    stable_hash(vec)
+}
+
+// page keys other than the first one depend on the hash of the continue key, which is not stored.
+// returns all of them, so that they can be dropped and fetched again, see `db::migration`.
+pub fn legacy_page_keys(task_store: &TaskMemoryStore) -> Vec<String> {
+    task_store.key_iter().filter(|key| key.starts_with("page_key_") && !key.starts_with("page_key_0_")).collect()
 }
 
 // TODO: WARNING: if the page count were to decrease for some reason, database will have orphan entries!
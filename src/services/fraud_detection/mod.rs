@@ -22,7 +22,7 @@ pub fn get_key_for_fraud_detection(hash: u64) -> String {
 pub async fn fraud_detection(task_store: TaskMemoryStore, key: String) -> anyhow::Result<TaskResult> {
 
 
-    let mut wtr = csv::Writer::from_path("./tmp/governance_proposal_spam_likelihood.csv").unwrap();
+    let mut wtr = csv::Writer::from_path(&task_store.config().files.spam_likelihood_csv).unwrap();
     wtr.write_record(&["body","label"]).unwrap();
 
     let mut keys: Vec<String> = Vec::new();
@@ -64,7 +64,7 @@ pub async fn fraud_detection(task_store: TaskMemoryStore, key: String) -> anyhow
                         let text =  format!("{}\n\n{}",&title,&description);
 
                         info!("client_send_rust_bert_fraud_detection_request");
-                        let result: anyhow::Result<RustBertFraudDetectionResult> = client_send_rust_bert_fraud_detection_request("./tmp/rust_bert_fraud_detection_socket",vec![text.clone()]);
+                        let result: anyhow::Result<RustBertFraudDetectionResult> = client_send_rust_bert_fraud_detection_request(&task_store.config().sockets.fraud_detection,vec![text.clone()]);
                         info!("RustBertFraudDetectionResult: {:?}",result);
 
 
@@ -10,7 +10,8 @@ use crate::utils::response::{ResponseResult, BlockchainQuery, GPT3ResultStatus,
 use rust_openai_gpt_tools_socket_ipc::ipc::{client_send_openai_gpt_chat_completion_request, client_send_openai_gpt_embedding_request, client_send_openai_gpt_text_completion_request, OpenAIGPTResult};
 use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTChatCompletionResult,OpenAIGPTEmbeddingResult};
 use crate::services::fraud_detection::{get_key_for_fraud_detection, validate_fraud_detection_result};
-use crate::services::link_to_text::{extract_links, get_key_for_link_to_text, link_to_id, string_to_hash, retrieve_embedded_data_from_links, get_embedded_data_from_link};
+use crate::services::link_to_text::{extract_links, get_key_for_link_to_text, link_to_id, legacy_link_to_id, string_to_hash, retrieve_embedded_data_from_links, get_embedded_data_from_link};
+use crate::utils::hash::legacy_hash;
 
 use nnsplit::NNSplitOptions;
 use nnsplit::tract_backend::NNSplit;
@@ -19,9 +20,7 @@ use crate::blockchain::cosmos::gov::get_key_for_tally_result;
 
 const GPT3_PREFIX: &str = "GPT3";
 
-const GPT_4_8K_MODEL: &str ="gpt-4";
-const GPT_4_32K_MODEL: &str ="gpt-4-32k";
-const GPT_3_5_TURBO_MODEL: &str = "gpt-3.5-turbo";
+// the models are configured in `Config::gpt`.
 
 const SYSTEM_RETRIEVE_OPTION: &str = r#"
 As the Rust compiler,
@@ -159,13 +158,13 @@ pub async fn gpt3(task_store: TaskMemoryStore, key: String) -> anyhow::Result<Ta
                                 // SUMMARY
                                 let key_for_hash = get_key_for_gpt3(hash, &format!("SUMMARY_{}", 0));
                                 let prompt = get_prompt_for_gpt3(&context, PromptKind::SUMMARY);
-                                try_get_or_insert_chat_completion_result(&task_store, &key_for_hash, &GPT_4_8K_MODEL, &SYSTEM_SUMMARY, &prompt, 200u16).ok();
+                                try_get_or_insert_chat_completion_result(&task_store, &key_for_hash, &task_store.config().gpt.gpt_4_8k, &SYSTEM_SUMMARY, &prompt, 200u16).ok();
 
 
                                 // BRIEFING
                                 let key_for_hash = get_key_for_gpt3(hash, &format!("BRIEFING_{}", 0));
                                 let prompt = get_prompt_for_gpt3(&context, PromptKind::QUESTIONS);
-                                try_get_or_insert_chat_completion_result(&task_store, &key_for_hash, &GPT_4_8K_MODEL, &SYSTEM_QUESTIONS, &prompt, 800u16).ok();
+                                try_get_or_insert_chat_completion_result(&task_store, &key_for_hash, &task_store.config().gpt.gpt_4_8k, &SYSTEM_QUESTIONS, &prompt, 800u16).ok();
 
                             }
                             Err(err) => {
@@ -204,6 +203,46 @@ pub fn insert_progress(task_store: &TaskMemoryStore, key: &str, keys: &mut Vec<S
 }
 */
 
+// renames embeddings and link to community results stored under a legacy key, see `db::migration`.
+// must be computed before the link to text results are renamed.
+pub fn legacy_key_renames(task_store: &TaskMemoryStore) -> Vec<(String,String)> {
+    let mut texts: HashSet<String> = TOPICS_FOR_EMBEDDING.iter().map(|&s| s.to_string()).collect();
+    let mut link_containing_texts: HashSet<String> = HashSet::new();
+
+    for (_val_key, val) in task_store.value_iter::<ResponseResult>(&RetrievalMethod::GetOk) {
+        if let Maybe { data: Ok(ResponseResult::Blockchain(BlockchainQuery::GovProposals(proposals))), .. } = val {
+            for each in proposals.iter() {
+                let description = each.get_description();
+                texts.extend(LinkToTextResult::new(&description,vec![description.to_string()],vec![vec![true]],300).text_nodes);
+                for link in extract_links(&description) {
+                    for link_key in [get_key_for_link_to_text(&legacy_link_to_id(&link)), get_key_for_link_to_text(&link_to_id(&link))] {
+                        if let Ok(Maybe { data: Ok(ResponseResult::LinkToTextResult(link_to_text_result)), .. }) = task_store.get::<ResponseResult>(&link_key, &RetrievalMethod::GetOk) {
+                            texts.extend(link_to_text_result.text_nodes);
+                            break;
+                        }
+                    }
+                }
+                link_containing_texts.insert(description);
+            }
+        }
+    }
+
+    let mut renames = Vec::new();
+    for text in texts {
+        let legacy_key = get_key_for_gpt3(legacy_hash(text.as_str()), "embedding");
+        if task_store.contains_key(&legacy_key) {
+            renames.push((legacy_key, get_key_for_gpt3(string_to_hash(&text), "embedding")));
+        }
+    }
+    for text in link_containing_texts {
+        let legacy_key = get_key_for_gpt3(legacy_hash(text.as_str()), &format!("link_to_community{}", 0));
+        if task_store.contains_key(&legacy_key) {
+            renames.push((legacy_key, get_key_for_gpt3(string_to_hash(&text), &format!("link_to_community{}", 0))));
+        }
+    }
+    renames
+}
+
 pub fn retrieve_context_from_description_and_community_link_to_text_results_for_prompt(task_store: &TaskMemoryStore, description: &str, text_triggers: Vec<String>) -> anyhow::Result<String> {
 
     let description_text_result =  LinkToTextResult::new(description,vec![description.to_string()],vec![vec![true]],300);
@@ -315,7 +354,7 @@ pub fn get_community_portal_link(task_store: &TaskMemoryStore, link_containing_t
         let key_for_link_to_community = get_key_for_gpt3(string_to_hash(link_containing_text), &format!("link_to_community{}", 0));
         let prompt = get_prompt_for_gpt3(link_containing_text, PromptKind::LINK_TO_COMMUNITY);
 
-        let result = try_get_or_insert_chat_completion_result(&task_store, &key_for_link_to_community, &GPT_3_5_TURBO_MODEL, &SYSTEM_RETRIEVE_OPTION, &prompt, 100u16)?;
+        let result = try_get_or_insert_chat_completion_result(&task_store, &key_for_link_to_community, &task_store.config().gpt.gpt_3_5_turbo, &SYSTEM_RETRIEVE_OPTION, &prompt, 100u16)?;
 
         if result.result.contains("None") || !result.result.contains("Some") {
             Ok(None)
@@ -350,7 +389,7 @@ pub fn try_get_or_insert_chat_completion_result(task_store: &TaskMemoryStore, ke
     if item.is_err() {
 
         info!("Requesting OpenAI GPT Chat Completion for key '{}'", key);
-        let result: anyhow::Result<OpenAIGPTResult> = client_send_openai_gpt_chat_completion_request("./tmp/rust_openai_gpt_tools_socket", model_name.to_owned(),system.to_owned(), prompt.to_owned(), completion_token_limit);
+        let result: anyhow::Result<OpenAIGPTResult> = client_send_openai_gpt_chat_completion_request(&task_store.config().sockets.openai_gpt_tools, model_name.to_owned(),system.to_owned(), prompt.to_owned(), completion_token_limit);
         debug!("Received response from OpenAI GPT: {:?}", result);
 
         let result: Maybe<ResponseResult> = Maybe {
@@ -411,7 +450,7 @@ pub fn try_get_or_insert_embedding_result(task_store: &TaskMemoryStore, key: &st
         if item.is_err() {
 
             info!("Requesting OpenAI GPT embedding for key '{}'", key);
-            let result: anyhow::Result<OpenAIGPTResult> = client_send_openai_gpt_embedding_request("./tmp/rust_openai_gpt_tools_socket", texts);
+            let result: anyhow::Result<OpenAIGPTResult> = client_send_openai_gpt_embedding_request(&task_store.config().sockets.openai_gpt_tools, texts);
             debug!("Received response from OpenAI GPT: {:?}", result);
 
             let result: Maybe<ResponseResult> = Maybe {
@@ -1,6 +1,4 @@
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
-use std::hash::{Hash, Hasher};
 use std::ptr::hash;
 use cosmos_rust_package::chrono::Utc;
 use log::{debug, error, info};
@@ -9,6 +7,7 @@ use cosmos_rust_package::api::custom::query::gov::{LINK_FINDER};
 use crate::utils::entry::db::{RetrievalMethod, TaskMemoryStore};
 use crate::utils::entry::*;
 use crate::utils::response::{ResponseResult, BlockchainQuery, LinkToTextResult, LinkToTextResultStatus, TaskResult};
+use crate::utils::hash::{legacy_hash, stable_hash};
 use rust_link_to_text_socket_ipc::ipc::{client_send_link_to_text_request};
 use rust_link_to_text_socket_ipc::ipc::LinkToTextResult as LinkToTextResultIPC;
 
@@ -37,15 +36,34 @@ pub fn extract_links(text: &str) -> Vec<String> {
 }
 
 pub fn link_to_id(text: &String) -> String {
-    let mut s = DefaultHasher::new();
-    text.hash(&mut s);
-    format!("link{}",s.finish())
+    format!("link{}",stable_hash(text))
 }
 
 pub fn string_to_hash(text: &str) -> u64 {
-    let mut s = DefaultHasher::new();
-    text.hash(&mut s);
-    s.finish()
+    stable_hash(text)
+}
+
+pub fn legacy_link_to_id(text: &String) -> String {
+    format!("link{}",legacy_hash(text))
+}
+
+// renames the link to text results stored under a legacy key, see `db::migration`.
+pub fn legacy_key_renames(task_store: &TaskMemoryStore) -> Vec<(String,String)> {
+    let mut renames = Vec::new();
+    for (_val_key, val) in task_store.value_iter::<ResponseResult>(&RetrievalMethod::GetOk) {
+        if let Maybe { data: Ok(ResponseResult::Blockchain(BlockchainQuery::GovProposals(proposals))), .. } = val {
+            for each in proposals.iter() {
+                for link in extract_links(&each.get_description()) {
+                    let legacy_key = get_key_for_link_to_text(&legacy_link_to_id(&link));
+                    let key = get_key_for_link_to_text(&link_to_id(&link));
+                    if legacy_key != key && task_store.contains_key(&legacy_key) && !renames.contains(&(legacy_key.clone(),key.clone())) {
+                        renames.push((legacy_key, key));
+                    }
+                }
+            }
+        }
+    }
+    renames
 }
 
 
@@ -105,7 +123,7 @@ pub fn insert_link_to_text_result(task_store: &TaskMemoryStore, key: &str, link:
 
     if !task_store.contains_key(&key) {
         info!("Sending request to link-to-text service for key {}", key);
-        let result: anyhow::Result<LinkToTextResultIPC> = client_send_link_to_text_request("./tmp/rust_link_to_text_socket", link.to_owned());
+        let result: anyhow::Result<LinkToTextResultIPC> = client_send_link_to_text_request(&task_store.config().sockets.link_to_text, link.to_owned());
         match result {
             Ok(data) => {
                 info!("Successfully obtained LinkToTextResult for link {}", link);
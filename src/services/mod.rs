@@ -1,4 +1,5 @@
 // query API endpoints that are not on-chain, these are services provided by third parties or dapps.
 pub mod fraud_detection;
 pub mod gpt3;
-pub mod link_to_text;
\ No newline at end of file
+pub mod link_to_text;
+pub mod proposal_embedding;
\ No newline at end of file
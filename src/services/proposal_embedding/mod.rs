@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use cosmos_rust_package::chrono::Utc;
+use log::{error, info};
+use crate::utils::entry::db::{RetrievalMethod, TaskMemoryStore};
+use crate::utils::entry::*;
+use crate::utils::entry::search::vector::{BruteForceIndex, mean_pool, similarity, VectorIndex};
+use crate::utils::response::{ResponseResult, BlockchainQuery, LinkToTextResult, ProposalEmbedding, TaskResult};
+use crate::services::gpt3::{get_key_for_gpt3, try_get_or_insert_embedding_result};
+use crate::services::link_to_text::string_to_hash;
+use crate::utils::hash::stable_hash;
+
+const PROPOSAL_EMBEDDING_PREFIX: &str = "PROPOSAL_EMBEDDING";
+
+pub fn get_key_for_proposal_embedding(hash: u64) -> String {
+    format!("{}_{}", PROPOSAL_EMBEDDING_PREFIX, hash)
+}
+
+/// Computes one embedding per proposal (all statuses) from the embeddings of its title and description chunks.
+/// Chunk embeddings are shared with `gpt3`, only chunks that have not been embedded before are requested.
+pub async fn proposal_embeddings(task_store: TaskMemoryStore, key: String) -> anyhow::Result<TaskResult> {
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut number_of_new_results = 0usize;
+
+    for (_val_key, val) in task_store.value_iter::<ResponseResult>(&RetrievalMethod::GetOk) {
+        if let Maybe { data: Ok(ResponseResult::Blockchain(BlockchainQuery::GovProposals(proposals))), .. } = val {
+            for each in proposals.iter() {
+                let hash = each.object_to_hash();
+                let key_for_hash = get_key_for_proposal_embedding(hash);
+                if task_store.contains_key(&key_for_hash) {
+                    continue;
+                }
+
+                let description = each.get_description();
+                let mut chunks = vec![each.get_title()];
+                chunks.append(&mut LinkToTextResult::new(&description, vec![description.to_string()], vec![vec![true]], 300).text_nodes);
+
+                let mut vectors: Vec<Vec<f32>> = Vec::new();
+                for chunk in chunks.iter().filter(|x| !x.trim().is_empty()) {
+                    let key_for_chunk = get_key_for_gpt3(string_to_hash(chunk), "embedding");
+                    match try_get_or_insert_embedding_result(&task_store, &key_for_chunk, vec![chunk.to_string()]) {
+                        Ok(mut item) => { vectors.append(&mut item.result); },
+                        Err(err) => { error!("Failed to embed chunk of proposal {}: {}", hash, err.to_string()); }
+                    }
+                }
+
+                // proposals with a failed chunk are retried in the next run.
+                if vectors.len() != chunks.iter().filter(|x| !x.trim().is_empty()).count() {
+                    continue;
+                }
+                if let Some(embedding) = mean_pool(&vectors) {
+                    let result: Maybe<ResponseResult> = Maybe {
+                        data: Ok(ResponseResult::ProposalEmbedding(ProposalEmbedding { embedding })),
+                        timestamp: Utc::now().timestamp(),
+                    };
+                    task_store.push(&key_for_hash, result).ok();
+                    keys.push(key_for_hash);
+                    number_of_new_results += 1;
+                }
+            }
+        }
+    }
+    info!("Computed {} new proposal embeddings.", number_of_new_results);
+    Ok(TaskResult{
+        list_of_keys_modified: keys
+    })
+}
+
+pub fn get_proposal_embedding(task_store: &TaskMemoryStore, hash: u64) -> Option<Vec<f32>> {
+    match task_store.get::<ResponseResult>(&get_key_for_proposal_embedding(hash), &RetrievalMethod::GetOk) {
+        Ok(Maybe { data: Ok(ResponseResult::ProposalEmbedding(ProposalEmbedding { embedding })), .. }) => Some(embedding),
+        _ => None,
+    }
+}
+
+/// Lowercase alphanumeric words of title and description, hashed.
+/// Equal for texts that only differ in formatting, punctuation or case.
+pub fn normalized_text_hash(title: &str, description: &str) -> u64 {
+    let normalized = format!("{} {}", title, description)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" ");
+    stable_hash(normalized.as_str())
+}
+
+struct IndexedProposal {
+    proposal: SimilarProposal,
+    submit_time: Option<i64>,
+    text_hash: u64,
+}
+
+/// Vector index over all proposals with an embedding, keyed by the proposal hash.
+/// Proposals without an embedding are only matched by their normalized text hash.
+pub struct ProposalEmbeddingIndex {
+    pub index: BruteForceIndex<u64>,
+    proposals: HashMap<u64, IndexedProposal>,
+}
+
+impl ProposalEmbeddingIndex {
+
+    pub fn load(task_store: &TaskMemoryStore) -> Self {
+        let mut index = BruteForceIndex::new();
+        let mut proposals = HashMap::new();
+        for (_val_key, val) in task_store.value_iter::<ResponseResult>(&RetrievalMethod::GetOk) {
+            if let Maybe { data: Ok(ResponseResult::Blockchain(BlockchainQuery::GovProposals(gov_proposals))), .. } = val {
+                for each in gov_proposals.iter() {
+                    let hash = each.object_to_hash();
+                    if let Some(embedding) = get_proposal_embedding(task_store, hash) {
+                        index.insert(hash, embedding);
+                    }
+                    proposals.insert(hash, IndexedProposal {
+                        proposal: SimilarProposal {
+                            blockchain: each.blockchain.name.to_string(),
+                            proposal_id: each.get_proposal_id(),
+                            title: each.get_title(),
+                            status: each.status.to_string(),
+                            vetoed: each.final_tally_with_no_with_veto_majority(),
+                            similarity: String::new(),
+                        },
+                        submit_time: each.proposal.0.submit_time.clone().map(|t| t.seconds),
+                        text_hash: normalized_text_hash(&each.get_title(), &each.get_description()),
+                    });
+                }
+            }
+        }
+        ProposalEmbeddingIndex { index, proposals }
+    }
+
+    fn is_same_proposal(a: &SimilarProposal, b: &SimilarProposal) -> bool {
+        a.blockchain == b.blockchain && a.proposal_id == b.proposal_id
+    }
+
+    /// The `k` proposals most similar to the given proposal, excluding itself.
+    pub fn similar(&self, hash: u64, k: usize, min_similarity: f32) -> Vec<SimilarProposal> {
+        let (query, this) = match (self.index.get(&hash), self.proposals.get(&hash)) {
+            (Some(vector), Some(this)) => (vector.clone(), this),
+            _ => { return Vec::new(); }
+        };
+        let is_other = |other: &u64, similarity: f32| {
+            similarity >= min_similarity
+                && self.proposals.get(other).map(|x| !Self::is_same_proposal(&x.proposal, &this.proposal)).unwrap_or(false)
+        };
+        self.index.nearest_matching(&query, k, is_other).into_iter()
+            .filter_map(|(other, similarity)| self.proposals.get(&other).map(|x| SimilarProposal { similarity: format!("{:.2}", similarity), ..x.proposal.clone() }))
+            .collect()
+    }
+
+    /// The earlier proposal this proposal most likely resubmits: an identical normalized text,
+    /// or an embedding with at least `min_similarity`. Vetoed originals are preferred.
+    pub fn resubmission_of(&self, hash: u64, min_similarity: f32) -> Option<Resubmission> {
+        let this = self.proposals.get(&hash)?;
+        let is_earlier = |other: &IndexedProposal| {
+            !Self::is_same_proposal(&other.proposal, &this.proposal)
+                && match (other.submit_time, this.submit_time) {
+                    (Some(other_time), Some(this_time)) => other_time < this_time,
+                    _ => false,
+                }
+        };
+
+        // one pass over the proposals, an identical text counts as a similarity of 1.
+        let query = self.index.get(&hash);
+        self.proposals.iter()
+            .filter(|(_, other)| is_earlier(*other))
+            .filter_map(|(other_hash, other)| {
+                if other.text_hash == this.text_hash {
+                    return Some((other, 1.0, true));
+                }
+                let similarity = similarity(query?, self.index.get(other_hash)?);
+                (similarity >= min_similarity).then_some((other, similarity, false))
+            })
+            .max_by(|a, b| a.0.proposal.vetoed.cmp(&b.0.proposal.vetoed).then_with(|| a.1.total_cmp(&b.1)))
+            .map(|(original, similarity, identical_text)| Resubmission {
+                original: SimilarProposal { similarity: format!("{:.2}", similarity), ..original.proposal.clone() },
+                identical_text,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::HashMap;
+    use super::{normalized_text_hash, IndexedProposal, ProposalEmbeddingIndex};
+    use crate::utils::entry::SimilarProposal;
+    use crate::utils::entry::search::vector::{BruteForceIndex, VectorIndex};
+
+    fn index(proposals: Vec<(u64, &str, u64, Vec<f32>)>) -> ProposalEmbeddingIndex {
+        index_with_texts(proposals.into_iter().map(|(hash, blockchain, proposal_id, vector)| (hash, blockchain, proposal_id, Some(vector), hash, false)).collect())
+    }
+
+    // (hash, blockchain, proposal_id, embedding, text_hash, vetoed), proposals are submitted in order of their id.
+    fn index_with_texts(proposals: Vec<(u64, &str, u64, Option<Vec<f32>>, u64, bool)>) -> ProposalEmbeddingIndex {
+        let mut index = BruteForceIndex::new();
+        let mut indexed = HashMap::new();
+        for (hash, blockchain, proposal_id, vector, text_hash, vetoed) in proposals {
+            if let Some(vector) = vector {
+                index.insert(hash, vector);
+            }
+            indexed.insert(hash, IndexedProposal {
+                proposal: SimilarProposal {
+                    blockchain: blockchain.to_string(),
+                    proposal_id,
+                    title: format!("Proposal {}", proposal_id),
+                    status: "Passed".to_string(),
+                    vetoed,
+                    similarity: String::new(),
+                },
+                submit_time: Some(proposal_id as i64),
+                text_hash,
+            });
+        }
+        ProposalEmbeddingIndex { index, proposals: indexed }
+    }
+
+    #[test]
+    pub fn similar_proposals_best_match_first() {
+        let index = index(vec![
+            (1, "osmosis", 1, vec![1.0, 0.0]),
+            (2, "osmosis", 2, vec![1.0, 0.2]),
+            (3, "juno", 3, vec![1.0, 1.0]),
+            (4, "juno", 4, vec![0.0, 1.0]),
+            // the same proposal with a changed text.
+            (5, "osmosis", 1, vec![1.0, 0.01]),
+        ]);
+        let similar = index.similar(1, 2, 0.5);
+        assert_eq!(similar.iter().map(|x| (x.blockchain.as_str(), x.proposal_id)).collect::<Vec<(&str, u64)>>(), vec![("osmosis", 2), ("juno", 3)]);
+        assert_eq!(similar[0].similarity, "0.98");
+        assert_eq!(index.similar(1, 10, 0.5).len(), 2);
+        assert!(index.similar(6, 10, 0.5).is_empty());
+    }
+
+    #[test]
+    pub fn text_hash_ignores_formatting() {
+        let hash = normalized_text_hash("Signaling: Raise the limit", "Raise the *limit* to 10.\n\nThanks!");
+        assert_eq!(hash, normalized_text_hash("signaling raise the LIMIT", "raise the limit to 10 thanks"));
+        assert_ne!(hash, normalized_text_hash("Signaling: Raise the limit", "Raise the limit to 11."));
+        // the words of title and description are joined.
+        assert_eq!(normalized_text_hash("a b", "c"), normalized_text_hash("a", "b c"));
+    }
+
+    #[test]
+    pub fn resubmission_prefers_vetoed_originals() {
+        let index = index_with_texts(vec![
+            (1, "osmosis", 1, Some(vec![1.0, 0.1]), 10, false),
+            (2, "osmosis", 2, Some(vec![1.0, 0.3]), 20, true),
+            (3, "osmosis", 3, Some(vec![1.0, 0.0]), 30, false),
+            // identical text, without an embedding.
+            (4, "osmosis", 4, None, 10, false),
+            (5, "osmosis", 5, Some(vec![0.0, 1.0]), 50, false),
+        ]);
+        // the vetoed proposal 2 is preferred over the more similar proposal 1.
+        let resubmission = index.resubmission_of(3, 0.9).unwrap();
+        assert_eq!((resubmission.original.proposal_id, resubmission.original.similarity.as_str(), resubmission.identical_text), (2, "0.96", false));
+        // later proposals are no originals.
+        assert_eq!(index.resubmission_of(1, 0.9), None);
+        let resubmission = index.resubmission_of(4, 0.9).unwrap();
+        assert_eq!((resubmission.original.proposal_id, resubmission.identical_text), (1, true));
+        assert_eq!(index.resubmission_of(5, 0.9), None);
+    }
+}
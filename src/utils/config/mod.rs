@@ -0,0 +1,209 @@
+use serde::{Serialize,Deserialize};
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+use log::error;
+
+// Paths, urls and settings shared by the tasks, stores and services.
+//
+// The configuration is layered: the defaults place every file and socket in `dir` (./tmp), the JSON
+// file `CRB_CONFIG` (default `<dir>/cosmos_rust_bot_config.json`) overrides any of them, and finally
+// environment variables named after the field override single values, e.g. `CRB_DIR`,
+// `CRB_BASE_URL` or `CRB_SOCKETS_NOTIFICATION`. Setting only `CRB_DIR` moves everything, which is
+// enough to run a second instance (e.g. staging) on the same host.
+
+pub const CRB_CONFIG_ENV: &str = "CRB_CONFIG";
+pub const CRB_CONFIG_JSON: &str = "cosmos_rust_bot_config.json";
+const ENV_PREFIX: &str = "CRB";
+const DEFAULT_DIR: &str = "./tmp";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SocketPaths {
+    pub openai_gpt_tools: String,
+    pub fraud_detection: String,
+    pub link_to_text: String,
+    pub notification: String,
+    pub query: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FilePaths {
+    pub subscriptions_json: String,
+    pub registrations_json: String,
+    pub user_meta_data_json: String,
+    pub smtp_config_json: String,
+    pub spam_likelihood_csv: String,
+    // generated proposal pages and data, served by the web frontend.
+    pub public_dir: String,
+    pub governance_proposals_dir: String,
+    pub fraud_detection_dir: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GptModels {
+    pub gpt_4_8k: String,
+    pub gpt_4_32k: String,
+    pub gpt_3_5_turbo: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Config {
+    // the default location of all files and sockets.
+    pub dir: String,
+    // web frontend with the proposal pages and the login page.
+    pub base_url: String,
+    pub sled_cache_capacity: u64,
+    pub sockets: SocketPaths,
+    pub files: FilePaths,
+    pub gpt: GptModels,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::in_dir(DEFAULT_DIR)
+    }
+}
+
+impl Config {
+    /// The defaults with every file and socket in `dir`.
+    pub fn in_dir(dir: &str) -> Self {
+        let dir = dir.trim_end_matches('/');
+        let path = |name: &str| format!("{}/{}", dir, name);
+        Config {
+            dir: dir.to_string(),
+            base_url: "https://libreai.de".to_string(),
+            sled_cache_capacity: 1024 * 1024 * 1024, // 1gb
+            sockets: SocketPaths {
+                openai_gpt_tools: path("rust_openai_gpt_tools_socket"),
+                fraud_detection: path("rust_bert_fraud_detection_socket"),
+                link_to_text: path("rust_link_to_text_socket"),
+                notification: path("cosmos_rust_bot_notification_socket"),
+                query: path("cosmos_rust_bot_query_socket"),
+            },
+            files: FilePaths {
+                subscriptions_json: path("cosmos_rust_bot_subscriptions.json"),
+                registrations_json: path("cosmos_rust_bot_registrations.json"),
+                user_meta_data_json: path("cosmos_rust_telegram_bot_user_meta_data.json"),
+                smtp_config_json: path("cosmos_rust_bot_smtp_config.json"),
+                spam_likelihood_csv: path("governance_proposal_spam_likelihood.csv"),
+                public_dir: path("public"),
+                governance_proposals_dir: path("governance_proposals"),
+                fraud_detection_dir: path("fraud_detection"),
+            },
+            gpt: GptModels {
+                gpt_4_8k: "gpt-4".to_string(),
+                gpt_4_32k: "gpt-4-32k".to_string(),
+                gpt_3_5_turbo: "gpt-3.5-turbo".to_string(),
+            },
+        }
+    }
+
+    /// Loads the configuration file and applies the environment overrides.
+    pub fn load() -> anyhow::Result<Self> {
+        let dir = std::env::var(format!("{}_DIR", ENV_PREFIX)).unwrap_or(DEFAULT_DIR.to_string());
+        let path = std::env::var(CRB_CONFIG_ENV).unwrap_or(format!("{}/{}", dir.trim_end_matches('/'), CRB_CONFIG_JSON));
+        let file = match std::fs::read_to_string(&path) {
+            Ok(file) => Some(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => { return Err(anyhow::anyhow!("Unable to read the configuration {}: {}", path, err)); },
+        };
+        Config::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+    }
+
+    /// The defaults, overridden by the (partial) JSON file and then by the environment.
+    pub fn from_sources<F: Fn(&str) -> Option<String>>(file: Option<&str>, env: F) -> anyhow::Result<Self> {
+        let file: Value = match file {
+            Some(file) => serde_json::from_str(file)?,
+            None => Value::Object(Default::default()),
+        };
+        let dir = env(&format!("{}_DIR", ENV_PREFIX))
+            .or(file.get("dir").and_then(|x| x.as_str()).map(|x| x.to_string()))
+            .unwrap_or(DEFAULT_DIR.to_string());
+        let mut config = serde_json::to_value(Config::in_dir(&dir))?;
+        merge(&mut config, file);
+        apply_env(&mut config, ENV_PREFIX, &env)?;
+        Ok(serde_json::from_value(config)?)
+    }
+
+    /// The configuration of the process, loaded once. Falls back to the defaults if it can not be loaded.
+    pub fn shared() -> Arc<Config> {
+        static SHARED: OnceLock<Arc<Config>> = OnceLock::new();
+        SHARED.get_or_init(|| {
+            Arc::new(Config::load().unwrap_or_else(|err| {
+                error!("Unable to load the configuration, using the defaults: {}", err.to_string());
+                Config::default()
+            }))
+        }).clone()
+    }
+
+    pub fn proposal_url(&self, blockchain: &str, proposal_id: u64) -> String {
+        format!("{}/cosmos-governance-proposals/{}/{}.html", self.base_url, blockchain.to_lowercase(), proposal_id)
+    }
+
+    // the one-time login code is in the fragment, it is not sent to the web server.
+    pub fn login_url(&self, user_hash: u64, login_code: &str) -> String {
+        format!("{}/public/login.html#user_id={}&code={}", self.base_url, user_hash, login_code)
+    }
+}
+
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); },
+                }
+            }
+        },
+        (base, overrides) => { *base = overrides; },
+    }
+}
+
+// every leaf can be overridden by the environment variable named after its path, e.g. CRB_SOCKETS_QUERY.
+fn apply_env<F: Fn(&str) -> Option<String>>(value: &mut Value, name: &str, env: &F) -> anyhow::Result<()> {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                apply_env(field, &format!("{}_{}", name, key.to_uppercase()), env)?;
+            }
+        },
+        Value::Number(_) => {
+            if let Some(var) = env(name) {
+                let number: u64 = var.parse().map_err(|_| anyhow::anyhow!("{} must be a number, got {:?}", name, var))?;
+                *value = Value::from(number);
+            }
+        },
+        _ => {
+            if let Some(var) = env(name) {
+                *value = Value::String(var);
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::Config;
+    use std::collections::HashMap;
+
+    #[test]
+    pub fn layered_configuration() {
+        assert_eq!(Config::from_sources(None, |_| None).unwrap(), Config::default());
+        assert_eq!(Config::default().sockets.notification, "./tmp/cosmos_rust_bot_notification_socket");
+
+        let env: HashMap<&str, &str> = HashMap::from([("CRB_DIR", "/srv/staging"), ("CRB_SOCKETS_QUERY", "/run/query"), ("CRB_SLED_CACHE_CAPACITY", "1024")]);
+        let file = r#"{"base_url": "https://staging.example.com", "gpt": {"gpt_4_8k": "gpt-4-0613"}}"#;
+        let config = Config::from_sources(Some(file), |name| env.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(config.dir, "/srv/staging");
+        assert_eq!(config.files.public_dir, "/srv/staging/public");
+        assert_eq!(config.sockets.query, "/run/query");
+        assert_eq!(config.sled_cache_capacity, 1024);
+        assert_eq!(config.gpt.gpt_4_8k, "gpt-4-0613");
+        assert_eq!(config.gpt.gpt_4_32k, "gpt-4-32k");
+        assert_eq!(config.proposal_url("Osmosis", 1), "https://staging.example.com/cosmos-governance-proposals/osmosis/1.html");
+
+        assert!(Config::from_sources(None, |name| if name == "CRB_SLED_CACHE_CAPACITY" { Some("1gb".to_string()) } else { None }).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::SubscriptionStore;
+use crate::utils::entry::db::audit::{self, AuditAction};
+use crate::utils::entry::db::auth;
+use cosmos_rust_package::chrono::Utc;
+use log::warn;
+
+// Role-based access control for queries.
+//
+// A query without a user hash is anonymous. Any user hash is a user: the frontends (Telegram) name the
+// user without a token. A `RoleAssignment` (e.g. admin, set with `crb-store bot <path> role`) only
+// applies if the query carries a valid token of the user (`SettingsPart.token`, the HTTP gateway
+// passes the Basic credentials). Query parts and entry origins require a minimum role.
+
+// entries of these origins expose internal keys and error strings.
+const RESTRICTED_ORIGINS: [(&str, Role); 3] = [
+    ("task_meta_data_debug", Role::Admin),
+    ("task_meta_data_errors", Role::Admin),
+    ("task_meta_data_logs", Role::Admin),
+];
+
+/// The role of the sender of the query, assigned roles require a valid token.
+pub fn get_role(store: &SubscriptionStore, settings_part: &SettingsPart) -> Role {
+    let user_hash = match settings_part.user_hash {
+        Some(user_hash) => user_hash,
+        None => { return Role::Anonymous; }
+    };
+    let verified = match (&settings_part.token, get_registration(store, user_hash)) {
+        (Some(token), Some(registration)) => auth::verify_token(&registration, token, Utc::now().timestamp()),
+        _ => false,
+    };
+    if verified { assigned_role(store, user_hash) } else { Role::User }
+}
+
+/// The stored role of the user, e.g. to filter the notifications of a subscription.
+pub fn assigned_role(store: &SubscriptionStore, user_hash: u64) -> Role {
+    match store.0.db.get(RoleAssignment::get_key_for_user_hash(user_hash)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::RoleAssignment(assignment))) => assignment.role,
+        _ => Role::User,
+    }
+}
+
+fn get_registration(store: &SubscriptionStore, user_hash: u64) -> Option<Registration> {
+    match store.0.db.get(Registration::get_key_for_user_hash(user_hash)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::Registration(registration))) => Some(registration),
+        _ => None,
+    }
+}
+
+/// Assigns the role, `Role::User` removes the assignment.
+pub fn set_role(store: &SubscriptionStore, user_hash: u64, role: Role) -> anyhow::Result<()> {
+    let key = RoleAssignment::get_key_for_user_hash(user_hash);
+    if role == Role::User {
+        store.0.db.remove(key)?;
+    } else {
+        let value: Vec<u8> = CosmosRustBotValue::RoleAssignment(RoleAssignment { user_hash, role }).try_into()?;
+        store.0.db.insert(key, value)?;
+    }
+    audit::record(&store.0.db, Some(user_hash), AuditAction::RoleChanged, role.to_string());
+    Ok(())
+}
+
+pub fn origin_role(origin: &str) -> Role {
+    RESTRICTED_ORIGINS.iter().find(|(o, _)| *o == origin).map(|(_, role)| *role).unwrap_or(Role::Anonymous)
+}
+
+/// The role required for the query and the resource name shown when it is denied.
+pub fn required_role(query: &UserQuery) -> (Role, String) {
+    let settings = &query.settings_part;
+    match &query.query_part {
+        QueryPart::EntriesQueryPart(query_part) => {
+            // indices named after a restricted origin select its entries.
+            let restricted = query_part.indices.iter().chain(std::iter::once(&query_part.order_by))
+                .map(|name| (origin_role(name), name))
+                .max_by_key(|(role, _)| *role);
+            match restricted {
+                Some((role, name)) if role > Role::Anonymous => (role, name.to_owned()),
+                _ if settings.subscribe.unwrap_or(false) || settings.unsubscribe.unwrap_or(false) => (Role::User, "subscribing".to_string()),
+                _ => (Role::Anonymous, String::new()),
+            }
+        },
+        QueryPart::SubscriptionsQueryPart(_) => (Role::User, "the subscription list".to_string()),
+        QueryPart::RegisterQueryPart(_) => (Role::User, "registration".to_string()),
+        QueryPart::AuthQueryPart(_) => (Role::Anonymous, String::new()),
+        QueryPart::LoginQueryPart(_) => (Role::Anonymous, String::new()),
+        QueryPart::WebhookQueryPart(_) => (Role::User, "webhooks".to_string()),
+        QueryPart::ChannelQueryPart(_) => (Role::User, "notification channels".to_string()),
+    }
+}
+
+pub fn check_query(role: Role, query: &UserQuery) -> Result<(), AccessDenied> {
+    let (required, resource) = required_role(query);
+    if role < required {
+        warn!("Denied {} to a {} (requires {})", resource, role, required);
+        return Err(AccessDenied { user_hash: query.settings_part.user_hash, required, resource });
+    }
+    Ok(())
+}
+
+pub fn may_view(role: Role, item: &CosmosRustBotValue) -> bool {
+    match item {
+        CosmosRustBotValue::Entry(Entry::Value(value)) => role >= origin_role(&value.origin),
+        _ => true,
+    }
+}
+
+/// Drops the entries the role may not see, e.g. matched by a search or filter rather than by index.
+pub fn retain_visible(role: Role, items: &mut Vec<CosmosRustBotValue>) {
+    items.retain(|item| may_view(role, item));
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{check_query, get_role, origin_role, set_role};
+    use crate::utils::entry::db::auth::issue_token;
+    use crate::utils::entry::db::SubscriptionStore;
+    use crate::utils::entry::{CosmosRustBotValue, QueryPart, Role, SettingsPart, SubscriptionsQueryPart, UserQuery};
+
+    fn entries_query(index: &str, subscribe: bool) -> UserQuery {
+        UserQuery {
+            query_part: serde_json::from_value(serde_json::json!({"EntriesQueryPart": {"message": "errors", "display": "default", "indices": [index], "filter": [], "order_by": "", "limit": 10}})).unwrap(),
+            settings_part: SettingsPart { subscribe: Some(subscribe), user_hash: Some(1), ..SettingsPart::default() },
+        }
+    }
+
+    #[test]
+    pub fn role_gates() {
+        assert!(Role::Anonymous < Role::User && Role::User < Role::Admin);
+        assert_eq!(origin_role("task_meta_data_errors"), Role::Admin);
+        assert_eq!(origin_role("task_meta_data_gov_proposals"), Role::Anonymous);
+
+        assert!(check_query(Role::Anonymous, &entries_query("proposal_status_voting", false)).is_ok());
+        assert_eq!(check_query(Role::Anonymous, &entries_query("proposal_status_voting", true)).unwrap_err().required, Role::User);
+        assert_eq!(check_query(Role::User, &entries_query("task_meta_data_debug", false)).unwrap_err().resource, "task_meta_data_debug");
+        assert!(check_query(Role::Admin, &entries_query("task_meta_data_debug", true)).is_ok());
+
+        let subscriptions = UserQuery { query_part: QueryPart::SubscriptionsQueryPart(SubscriptionsQueryPart { message: String::new() }), settings_part: SettingsPart::default() };
+        assert!(check_query(Role::Anonymous, &subscriptions).is_err());
+        assert!(check_query(Role::User, &subscriptions).is_ok());
+    }
+
+    #[test]
+    pub fn assigned_roles_require_a_token() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SubscriptionStore::new(&db);
+        let (registration, issued) = issue_token(1, cosmos_rust_package::chrono::Utc::now().timestamp());
+        let item = CosmosRustBotValue::Registration(registration);
+        let key = item.key();
+        let value: Vec<u8> = item.try_into().unwrap();
+        db.insert(key, value).unwrap();
+        set_role(&store, 1, Role::Admin).unwrap();
+
+        let settings = |user_hash: Option<u64>, token: Option<&str>| SettingsPart { user_hash, token: token.map(|x| x.to_string()), ..SettingsPart::default() };
+        assert_eq!(get_role(&store, &settings(None, None)), Role::Anonymous);
+        // the hash of an admin alone is not enough.
+        assert_eq!(get_role(&store, &settings(Some(1), None)), Role::User);
+        assert_eq!(get_role(&store, &settings(Some(1), Some("wrong"))), Role::User);
+        assert_eq!(get_role(&store, &settings(Some(1), Some(&issued.token))), Role::Admin);
+        assert_eq!(get_role(&store, &settings(Some(2), Some(&issued.token))), Role::User);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::utils::entry::*;
+use cosmos_rust_package::chrono::Utc;
+use std::thread::JoinHandle;
+use log::info;
+
+use serde::{Serialize,Deserialize};
+
+// Append-only audit log of user actions and admin operations.
+//
+// Records are kept in their own tree of the db they are written to (subscription store for queries,
+// notification db for notifications), keyed by timestamp and a unique id, and are only ever removed
+// by the retention task. A second tree indexes the records by user hash.
+
+const AUDIT_TREE: &str = "audit_log";
+const AUDIT_USER_TREE: &str = "audit_log_user";
+
+pub const DEFAULT_AUDIT_RETENTION_SECONDS: i64 = 180 * 24 * 60 * 60;
+const AUDIT_RETENTION_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum AuditAction {
+    Query,
+    Subscribe,
+    Unsubscribe,
+    Register,
+    RevokeToken,
+    Authenticate,
+    Webhook,
+    AccessDenied,
+    QuotaExceeded,
+    Notified,
+    RoleChanged,
+    ChannelChanged,
+    // admin operations of `crb-store`.
+    DeletePrefix,
+    Migrate,
+    Compact,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuditRecord {
+    pub timestamp: i64,
+    pub user_hash: Option<u64>,
+    pub action: AuditAction,
+    pub summary: String,
+}
+
+impl TryFrom<Vec<u8>> for AuditRecord {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<AuditRecord> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: AuditRecord) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+fn time_key(timestamp: i64) -> [u8; 8] {
+    (timestamp.max(0) as u64).to_be_bytes()
+}
+
+pub fn record(db: &sled::Db, user_hash: Option<u64>, action: AuditAction, summary: String) {
+    let item = AuditRecord { timestamp: Utc::now().timestamp(), user_hash, action, summary };
+    if let Err(err) = append(db, item) {
+        info!("Unable to write the audit log: {}", err.to_string());
+    }
+}
+
+fn append(db: &sled::Db, item: AuditRecord) -> anyhow::Result<()> {
+    let mut key = time_key(item.timestamp).to_vec();
+    key.append(&mut db.generate_id()?.to_be_bytes().to_vec());
+    if let Some(user_hash) = item.user_hash {
+        let mut user_key = user_hash.to_be_bytes().to_vec();
+        user_key.extend_from_slice(&key);
+        db.open_tree(AUDIT_USER_TREE)?.insert(user_key, vec![])?;
+    }
+    let value: Vec<u8> = item.try_into()?;
+    db.open_tree(AUDIT_TREE)?.insert(key, value)?;
+    Ok(())
+}
+
+/// The action and summary of a user query, given its result.
+pub fn describe_query(query: &UserQuery, result: &Vec<CosmosRustBotValue>) -> (AuditAction, String) {
+    let settings = &query.settings_part;
+    let summary = query.query_part.to_string();
+    match result.first() {
+        Some(CosmosRustBotValue::AccessDenied(denied)) => return (AuditAction::AccessDenied, format!("{}: {}", summary, denied.resource)),
+        Some(CosmosRustBotValue::QuotaExceeded(exceeded)) => return (AuditAction::QuotaExceeded, format!("{}: {:?}", summary, exceeded.kind)),
+        _ => {},
+    }
+    match &query.query_part {
+        QueryPart::EntriesQueryPart(_) | QueryPart::SubscriptionsQueryPart(_) if settings.unsubscribe.unwrap_or(false) => (AuditAction::Unsubscribe, summary),
+        QueryPart::EntriesQueryPart(_) if settings.subscribe.unwrap_or(false) => (AuditAction::Subscribe, summary),
+        QueryPart::EntriesQueryPart(_) => (AuditAction::Query, format!("{}: {} results", summary, result.len())),
+        QueryPart::RegisterQueryPart(_) if settings.revoke.unwrap_or(false) => (AuditAction::RevokeToken, summary),
+        QueryPart::RegisterQueryPart(_) if settings.register.unwrap_or(false) => (AuditAction::Register, summary),
+        QueryPart::AuthQueryPart(_) => {
+            let authorized = matches!(result.first(), Some(CosmosRustBotValue::Authorization(Authorization { is_authorized: true, .. })));
+            (AuditAction::Authenticate, format!("{}: {}", summary, if authorized { "authorized" } else { "rejected" }))
+        },
+        QueryPart::LoginQueryPart(_) => {
+            let issued = matches!(result.first(), Some(CosmosRustBotValue::IssuedToken(_)));
+            (AuditAction::Authenticate, format!("{}: {}", summary, if issued { "token issued" } else { "rejected" }))
+        },
+        QueryPart::WebhookQueryPart(query_part) => (AuditAction::Webhook, if query_part.url.is_empty() { "removed".to_string() } else { query_part.url.to_owned() }),
+        QueryPart::ChannelQueryPart(_) => {
+            let accepted = matches!(result.first(), Some(CosmosRustBotValue::ChannelChanged(_)));
+            (AuditAction::ChannelChanged, format!("{}: {}", summary, if accepted { "accepted" } else { "rejected" }))
+        },
+        _ => (AuditAction::Query, summary),
+    }
+}
+
+/// Records between `from` and `to` (inclusive), oldest first.
+pub fn get_records(db: &sled::Db, from: i64, to: i64) -> Vec<AuditRecord> {
+    let tree = match db.open_tree(AUDIT_TREE) {
+        Ok(tree) => tree,
+        Err(_) => { return Vec::new(); }
+    };
+    let end = time_key(to.saturating_add(1));
+    tree.range(time_key(from)..end).values()
+        .filter_map(|x| x.ok())
+        .filter_map(|v| AuditRecord::try_from(v.to_vec()).ok())
+        .collect()
+}
+
+/// Records of the user between `from` and `to` (inclusive), oldest first.
+pub fn get_user_records(db: &sled::Db, user_hash: u64, from: i64, to: i64) -> Vec<AuditRecord> {
+    let (tree, user_tree) = match (db.open_tree(AUDIT_TREE), db.open_tree(AUDIT_USER_TREE)) {
+        (Ok(tree), Ok(user_tree)) => (tree, user_tree),
+        _ => { return Vec::new(); }
+    };
+    let prefix = user_hash.to_be_bytes();
+    let mut start = prefix.to_vec();
+    start.extend_from_slice(&time_key(from));
+    let mut end = prefix.to_vec();
+    end.extend_from_slice(&time_key(to.saturating_add(1)));
+    user_tree.range(start..end).keys()
+        .filter_map(|x| x.ok())
+        .filter_map(|k| tree.get(&k[8..]).ok().flatten())
+        .filter_map(|v| AuditRecord::try_from(v.to_vec()).ok())
+        .collect()
+}
+
+/// Removes the records older than `max_age_seconds`.
+pub fn prune(db: &sled::Db, max_age_seconds: i64) -> anyhow::Result<usize> {
+    let tree = db.open_tree(AUDIT_TREE)?;
+    let user_tree = db.open_tree(AUDIT_USER_TREE)?;
+    let end = time_key(Utc::now().timestamp() - max_age_seconds);
+    let mut count = 0;
+    for (key, value) in tree.range(..end).filter_map(|x| x.ok()) {
+        if let Ok(AuditRecord { user_hash: Some(user_hash), .. }) = AuditRecord::try_from(value.to_vec()) {
+            let mut user_key = user_hash.to_be_bytes().to_vec();
+            user_key.extend_from_slice(&key);
+            user_tree.remove(user_key)?;
+        }
+        tree.remove(key)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn spawn_audit_retention_task(db: &sled::Db, max_age_seconds: i64) -> JoinHandle<()> {
+    info!("Spawning audit log retention task");
+    let db = db.clone();
+    std::thread::spawn(move || {
+        loop {
+            match prune(&db, max_age_seconds) {
+                Ok(count) if count > 0 => info!("Removed {} audit log records.", count),
+                Err(err) => info!("Unable to prune the audit log: {}", err.to_string()),
+                _ => {},
+            }
+            std::thread::sleep(std::time::Duration::from_secs(AUDIT_RETENTION_INTERVAL_SECONDS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{append, get_records, get_user_records, prune, AuditAction, AuditRecord};
+    use cosmos_rust_package::chrono::Utc;
+
+    #[test]
+    pub fn records_by_user_and_time() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = Utc::now().timestamp();
+        let old = now - 400 * 24 * 60 * 60;
+        for (timestamp, user_hash, action) in [(old, Some(1), AuditAction::Subscribe), (now - 60, Some(1), AuditAction::Query), (now - 30, Some(2), AuditAction::Register), (now, None, AuditAction::Query)] {
+            append(&db, AuditRecord { timestamp, user_hash, action, summary: String::new() }).unwrap();
+        }
+
+        assert_eq!(get_records(&db, old, now).len(), 4);
+        assert_eq!(get_records(&db, now - 60, now - 30).len(), 2);
+        let user = get_user_records(&db, 1, 0, now);
+        assert_eq!(user.iter().map(|x| x.action.clone()).collect::<Vec<AuditAction>>(), vec![AuditAction::Subscribe, AuditAction::Query]);
+        assert_eq!(get_user_records(&db, 1, now - 120, now).len(), 1);
+
+        assert_eq!(prune(&db, 180 * 24 * 60 * 60).unwrap(), 1);
+        assert_eq!(get_user_records(&db, 1, 0, now).len(), 1);
+        assert_eq!(get_records(&db, 0, now).len(), 3);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::utils::entry::*;
+use crate::utils::hash::to_hex;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{Rng, thread_rng};
+
+// Authentication tokens of registered users.
+//
+// A token is 128 random bits, hex encoded. Only SHA-256(salt + token) is stored in the `Registration`,
+// a slow password hash is not needed for random tokens of this length.
+// Registering again rotates the token (the previous one stops working), `SettingsPart.revoke` revokes it.
+// The login link sent to the user carries a one-time `LoginCode` instead of the token, the login page
+// exchanges it for a new token (`LoginQueryPart`). Stored messages therefore never contain a usable token.
+
+pub const TOKEN_LIFETIME_SECONDS: i64 = 90 * 24 * 60 * 60;
+// legacy 64 bit tokens keep working this long after the migration, then the user has to register again.
+pub const LEGACY_TOKEN_GRACE_SECONDS: i64 = 14 * 24 * 60 * 60;
+pub const LOGIN_CODE_LIFETIME_SECONDS: i64 = 60 * 60;
+pub const LOGIN_CODE_TREE: &str = "login_code";
+
+fn random_hex() -> String {
+    let bytes: [u8; 16] = thread_rng().gen();
+    to_hex(&bytes)
+}
+
+pub fn hash_token(salt: &str, token: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(salt);
+    sha.input_str(token);
+    sha.result_str()
+}
+
+/// A new token for the user, the Registration to store and the token to hand out once.
+pub fn issue_token(user_hash: u64, now: i64) -> (Registration, IssuedToken) {
+    let token = random_hex();
+    let registration = registration_for(user_hash, &token, now, now + TOKEN_LIFETIME_SECONDS);
+    let issued = IssuedToken { user_hash, token, expires: registration.expires, login_code: String::new() };
+    (registration, issued)
+}
+
+pub fn registration_for(user_hash: u64, token: &str, issued: i64, expires: i64) -> Registration {
+    let salt = random_hex();
+    Registration {
+        user_hash,
+        token_hash: hash_token(&salt, token),
+        salt,
+        issued,
+        expires,
+        revoked: false,
+    }
+}
+
+/// Constant time comparison of the hashes, false once the token expired or was revoked.
+pub fn verify_token(registration: &Registration, token: &str, now: i64) -> bool {
+    let matches = fixed_time_eq(hash_token(&registration.salt, token).as_bytes(), registration.token_hash.as_bytes());
+    matches && !registration.revoked && now < registration.expires
+}
+
+pub fn issue_login_code(user_hash: u64, now: i64) -> (LoginCode, String) {
+    let code = random_hex();
+    let salt = random_hex();
+    let login_code = LoginCode { user_hash, code_hash: hash_token(&salt, &code), salt, expires: now + LOGIN_CODE_LIFETIME_SECONDS };
+    (login_code, code)
+}
+
+pub fn verify_login_code(login_code: &LoginCode, code: &str, now: i64) -> bool {
+    fixed_time_eq(hash_token(&login_code.salt, code).as_bytes(), login_code.code_hash.as_bytes()) && now < login_code.expires
+}
+
+/// Stores a new login code for the user, replacing the previous one, and returns the code to hand out.
+pub fn store_login_code(db: &sled::Db, user_hash: u64, now: i64) -> anyhow::Result<String> {
+    let (login_code, code) = issue_login_code(user_hash, now);
+    let value: Vec<u8> = login_code.try_into()?;
+    db.open_tree(LOGIN_CODE_TREE)?.insert(LoginCode::get_key_for_user_hash(user_hash), value)?;
+    Ok(code)
+}
+
+/// True if the code is valid, the code is removed in the same step: of two concurrent logins only one succeeds.
+pub fn redeem_login_code(db: &sled::Db, user_hash: u64, code: &str, now: i64) -> anyhow::Result<bool> {
+    let tree = db.open_tree(LOGIN_CODE_TREE)?;
+    let key = LoginCode::get_key_for_user_hash(user_hash);
+    let stored = match tree.get(&key)? {
+        Some(stored) => stored,
+        None => { return Ok(false); },
+    };
+    if !verify_login_code(&LoginCode::try_from(stored.to_vec())?, code, now) {
+        return Ok(false);
+    }
+    Ok(tree.compare_and_swap(&key, Some(stored), None as Option<&[u8]>)?.is_ok())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{issue_token, redeem_login_code, store_login_code, verify_token, LOGIN_CODE_LIFETIME_SECONDS, TOKEN_LIFETIME_SECONDS};
+
+    const NOW: i64 = 1672655400;
+
+    #[test]
+    pub fn token_lifecycle() {
+        let (mut registration, issued) = issue_token(1, NOW);
+        assert_eq!(issued.token.len(), 32);
+        assert!(!registration.token_hash.contains(&issued.token));
+        assert!(verify_token(&registration, &issued.token, NOW));
+        assert!(!verify_token(&registration, "00000000000000000000000000000000", NOW));
+        assert!(!verify_token(&registration, &issued.token, NOW + TOKEN_LIFETIME_SECONDS));
+
+        // rotation
+        let (rotated, rotated_issued) = issue_token(1, NOW);
+        assert_ne!(rotated.salt, registration.salt);
+        assert!(!verify_token(&rotated, &issued.token, NOW));
+        assert!(verify_token(&rotated, &rotated_issued.token, NOW));
+
+        registration.revoked = true;
+        assert!(!verify_token(&registration, &issued.token, NOW));
+    }
+
+    #[test]
+    pub fn login_code_is_single_use() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let code = store_login_code(&db, 1, NOW).unwrap();
+        assert!(!redeem_login_code(&db, 2, &code, NOW).unwrap());
+        assert!(!redeem_login_code(&db, 1, "00000000000000000000000000000000", NOW).unwrap());
+        assert!(!redeem_login_code(&db, 1, &code, NOW + LOGIN_CODE_LIFETIME_SECONDS).unwrap());
+        assert!(redeem_login_code(&db, 1, &code, NOW).unwrap());
+        assert!(!redeem_login_code(&db, 1, &code, NOW).unwrap());
+
+        // a new code replaces the previous one.
+        let previous = store_login_code(&db, 1, NOW).unwrap();
+        let code = store_login_code(&db, 1, NOW).unwrap();
+        assert!(!redeem_login_code(&db, 1, &previous, NOW).unwrap());
+        assert!(redeem_login_code(&db, 1, &code, NOW).unwrap());
+    }
+}
@@ -0,0 +1,553 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::CosmosRustBotStore;
+use crate::utils::entry::db::query::CosmosRustBotStoreInquirer;
+use crate::utils::entry::db::notification::channel::email;
+use crate::utils::entry::db::notification::socket::client_send_notification_request;
+use crate::utils::entry::db::stream::{EntryEvent, EntryStream, StreamHub, StreamOwner};
+use cosmos_rust_package::chrono::Utc;
+use cosmos_rust_package::tokio;
+use cosmos_rust_package::tokio::sync::watch;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// HTTP/JSON gateway to the query service (feature "http").
+//
+// Every endpoint builds a `UserQuery` and answers with the `Notification` the Unix socket query
+// service would return, serialized as JSON. Users authenticate with HTTP Basic credentials: the
+// user id and the token of their Registration (the login link carries a one-time code, exchanged at `POST /login`).
+// Quotas, roles and the audit log apply as for any other query, see `CosmosRustBotStoreInquirer::query`.
+// Requests without valid credentials are additionally limited per client address (`ClientQuota`).
+// `GET /entries/stream` pushes the changes of an entries query as server-sent events, see `stream`,
+// the open streams are limited per user or client address and in total.
+// The notification of a `POST /channel` query is forwarded to the notification service, which stores the channel.
+// `GET /unsubscribe` is the target of the unsubscribe links in email summaries, see `email::unsubscribe_query`.
+
+const API_PREFIX: &str = "/api/v1";
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+const ANONYMOUS_REQUESTS_PER_MINUTE: u32 = 20;
+
+/// Requests without valid credentials per client address, counted in one minute windows.
+pub struct ClientQuota {
+    limit: u32,
+    // the current window and the count of every client within it.
+    window: Mutex<(i64, HashMap<IpAddr, u32>)>,
+}
+
+impl ClientQuota {
+    pub fn new(limit: u32) -> Self {
+        ClientQuota { limit, window: Mutex::new((0, HashMap::new())) }
+    }
+
+    pub fn count(&self, client: IpAddr, now: i64) -> Result<(), QuotaExceeded> {
+        let minute = now - now.rem_euclid(60);
+        let mut window = self.window.lock().unwrap();
+        if window.0 != minute {
+            *window = (minute, HashMap::new());
+        }
+        let count = window.1.entry(client).or_insert(0);
+        if *count >= self.limit {
+            return Err(QuotaExceeded { user_hash: None, kind: QuotaKind::QueriesPerMinute, limit: self.limit, global: false });
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// State shared by the requests of the gateway.
+pub struct Gateway {
+    pub store: CosmosRustBotStore,
+    pub clients: ClientQuota,
+    pub streams: StreamHub,
+}
+
+impl Gateway {
+    pub fn new(cosmos_rust_bot_store: &CosmosRustBotStore) -> Self {
+        Gateway {
+            store: cosmos_rust_bot_store.clone(),
+            clients: ClientQuota::new(ANONYMOUS_REQUESTS_PER_MINUTE),
+            streams: StreamHub::new(cosmos_rust_bot_store),
+        }
+    }
+}
+
+/// Returned by `spawn_http_gateway`, stops the server.
+pub struct HttpGatewayHandle {
+    pub local_addr: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl HttpGatewayHandle {
+    /// Stops accepting connections and waits for the open requests to finish.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.send(true).ok();
+        self.task.await?;
+        Ok(())
+    }
+}
+
+/// Serves the gateway at `addr`, must be called from within a tokio runtime.
+pub fn spawn_http_gateway(addr: &SocketAddr, cosmos_rust_bot_store: &CosmosRustBotStore) -> anyhow::Result<HttpGatewayHandle> {
+    let gateway = Arc::new(Gateway::new(cosmos_rust_bot_store));
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let gateway = gateway.clone();
+        let client = conn.remote_addr().ip();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| route(gateway.clone(), client, request)))
+        }
+    });
+    let server = Server::try_bind(addr)?.serve(make_service);
+    let local_addr = server.local_addr();
+    let (shutdown, mut shutdown_receiver) = watch::channel(false);
+    let server = server.with_graceful_shutdown(async move {
+        shutdown_receiver.changed().await.ok();
+    });
+    info!("HTTP gateway listening on {}", local_addr);
+    let task = tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("HTTP gateway failed: {}", err.to_string());
+        }
+    });
+    Ok(HttpGatewayHandle { local_addr, shutdown, task })
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({"error": message}))
+}
+
+fn query_flag(request: &Request<Body>, name: &str) -> Option<bool> {
+    request.uri().query()?.split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((key, value)) if key == name => Some(value == "true" || value == "1"),
+            None if pair == name => Some(true),
+            _ => None,
+        })
+}
+
+fn query_param(request: &Request<Body>, name: &str) -> Option<String> {
+    let url = reqwest::Url::parse(&format!("http://localhost{}", request.uri())).ok()?;
+    url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned())
+}
+
+/// The user id and token of the `Authorization: Basic` header, None without the header.
+pub fn credentials(request: &Request<Body>) -> Option<Result<AuthQueryPart, ()>> {
+    let header = request.headers().get(hyper::header::AUTHORIZATION)?;
+    let parsed = header.to_str().ok()
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            let (user_id, token) = decoded.split_once(':')?;
+            Some(AuthQueryPart { user_hash: user_id.parse().ok()?, token: token.to_owned() })
+        });
+    Some(parsed.ok_or(()))
+}
+
+// the verified credentials, Err(response) for invalid ones.
+fn authenticate(store: &CosmosRustBotStore, request: &Request<Body>) -> Result<Option<AuthQueryPart>, Response<Body>> {
+    match credentials(request) {
+        None => Ok(None),
+        Some(Err(())) => Err(error_response(StatusCode::UNAUTHORIZED, "malformed Basic credentials")),
+        Some(Ok(auth_query_part)) => {
+            let result = CosmosRustBotStoreInquirer(store).execute(&UserQuery::new(QueryPart::AuthQueryPart(auth_query_part.clone())));
+            if let Some(CosmosRustBotValue::Authorization(Authorization { is_authorized: true, .. })) = result.first() {
+                Ok(Some(auth_query_part))
+            } else {
+                Err(error_response(StatusCode::UNAUTHORIZED, "invalid, expired or revoked token"))
+            }
+        },
+    }
+}
+
+async fn read_json<T: for<'a> serde::Deserialize<'a>>(request: Request<Body>) -> Result<T, Response<Body>> {
+    let too_large = request.headers().get(hyper::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok()?.parse::<usize>().ok())
+        .map(|length| length > MAX_BODY_SIZE)
+        .unwrap_or(false);
+    if too_large {
+        return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"));
+    }
+    let bytes = hyper::body::to_bytes(request.into_body()).await
+        .map_err(|err| error_response(StatusCode::BAD_REQUEST, &err.to_string()))?;
+    if bytes.len() > MAX_BODY_SIZE {
+        return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"));
+    }
+    serde_json::from_slice(&bytes).map_err(|err| error_response(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+fn denied_status(item: &CosmosRustBotValue) -> Option<StatusCode> {
+    match item {
+        CosmosRustBotValue::AccessDenied(_) => Some(StatusCode::FORBIDDEN),
+        CosmosRustBotValue::QuotaExceeded(_) => Some(StatusCode::TOO_MANY_REQUESTS),
+        CosmosRustBotValue::CursorExpired(_) => Some(StatusCode::GONE),
+        _ => None,
+    }
+}
+
+// `forward` sends the notification to the notification service, as the socket frontends do.
+async fn answer(store: CosmosRustBotStore, query: UserQuery, forward: bool) -> Response<Body> {
+    let result = tokio::task::spawn_blocking(move || {
+        let notification = CosmosRustBotStoreInquirer(&store).answer(query);
+        if forward {
+            if let Err(err) = client_send_notification_request(&store.config().sockets.notification, CosmosRustServerValue::Notification(notification.clone())) {
+                error!("Failed to send notification: {}", err.to_string());
+            }
+        }
+        notification
+    }).await;
+    let notification = match result {
+        Ok(notification) => notification,
+        Err(_) => { return error_response(StatusCode::INTERNAL_SERVER_ERROR, "the query failed"); },
+    };
+    let status = notification.entries.first().and_then(denied_status).unwrap_or(StatusCode::OK);
+    match serde_json::to_value(&notification) {
+        Ok(value) => json_response(status, &value),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn sse_event(event: &EntryEvent) -> String {
+    format!("event: {}\ndata: {}\n\n", event.name(), serde_json::to_string(event).unwrap_or_default())
+}
+
+async fn stream_entries(gateway: Arc<Gateway>, client: IpAddr, request: &Request<Body>, settings_part: SettingsPart) -> Response<Body> {
+    let query_part: EntriesQueryPart = match query_param(request, "query").map(|query| serde_json::from_str(&query)) {
+        Some(Ok(query_part)) => query_part,
+        Some(Err(err)) => { return error_response(StatusCode::BAD_REQUEST, &err.to_string()); },
+        None => { return error_response(StatusCode::BAD_REQUEST, "the query parameter (an EntriesQueryPart) is required"); },
+    };
+    let owner = match settings_part.user_hash {
+        Some(user_hash) => StreamOwner::User(user_hash),
+        None => StreamOwner::Client(client),
+    };
+    let (mut stream, mut events) = match tokio::task::spawn_blocking(move || EntryStream::open(&gateway.streams, owner, &gateway.store, query_part, settings_part)).await {
+        Ok(Ok(Ok(opened))) => opened,
+        Ok(Ok(Err(denied))) => {
+            let status = denied_status(&denied).unwrap_or(StatusCode::FORBIDDEN);
+            return json_response(status, &serde_json::to_value(&denied).unwrap_or_default());
+        },
+        _ => { return error_response(StatusCode::INTERNAL_SERVER_ERROR, "unable to open the stream"); },
+    };
+
+    let (mut sender, body) = Body::channel();
+    // ends when the client disconnects or falls behind, which releases the stream.
+    tokio::spawn(async move {
+        loop {
+            let chunk = if events.is_empty() { ": keep-alive\n\n".to_string() } else { events.iter().map(sse_event).collect() };
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+            events = match stream.next_events(STREAM_KEEP_ALIVE).await {
+                Ok(events) => events,
+                Err(err) => { info!("Closing entry stream: {}", err.to_string()); break; },
+            };
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+pub async fn route(gateway: Arc<Gateway>, client: IpAddr, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().strip_prefix(API_PREFIX).unwrap_or("").to_owned();
+    let method = request.method().clone();
+
+    if (method.clone(), path.as_str()) == (Method::GET, "/openapi.json") {
+        return Ok(json_response(StatusCode::OK, &openapi()));
+    }
+
+    let store = gateway.store.clone();
+    let authenticated = authenticate(&store, &request);
+    // requests without valid credentials (incl. token and login code guesses) are limited per client.
+    if !matches!(authenticated, Ok(Some(_))) {
+        if let Err(exceeded) = gateway.clients.count(client, Utc::now().timestamp()) {
+            return Ok(json_response(StatusCode::TOO_MANY_REQUESTS, &serde_json::to_value(CosmosRustBotValue::QuotaExceeded(exceeded)).unwrap_or_default()));
+        }
+    }
+    // the token is passed on, roles other than `Role::User` require it.
+    let mut settings_part = match authenticated {
+        Ok(Some(credentials)) => SettingsPart { user_hash: Some(credentials.user_hash), token: Some(credentials.token), ..SettingsPart::default() },
+        Ok(None) => SettingsPart::default(),
+        Err(response) => { return Ok(response); },
+    };
+    if path == "/entries/stream" {
+        if method != Method::GET {
+            return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+        }
+        return Ok(stream_entries(gateway.clone(), client, &request, settings_part).await);
+    }
+    if path == "/unsubscribe" {
+        if method != Method::GET {
+            return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+        }
+        return Ok(unsubscribe(store, &request).await);
+    }
+    let requires_user = matches!(path.as_str(), "/subscriptions" | "/register" | "/channel");
+    if requires_user && settings_part.user_hash.is_none() {
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "Basic credentials required"));
+    }
+
+    let query_part = match (method, path.as_str()) {
+        (Method::POST, "/entries") => {
+            settings_part.subscribe = query_flag(&request, "subscribe");
+            settings_part.unsubscribe = query_flag(&request, "unsubscribe");
+            match read_json::<EntriesQueryPart>(request).await {
+                Ok(query_part) => QueryPart::EntriesQueryPart(query_part),
+                Err(response) => { return Ok(response); },
+            }
+        },
+        (Method::GET, "/subscriptions") => QueryPart::SubscriptionsQueryPart(SubscriptionsQueryPart { message: String::new() }),
+        (Method::DELETE, "/subscriptions") => {
+            settings_part.unsubscribe = Some(true);
+            QueryPart::SubscriptionsQueryPart(SubscriptionsQueryPart { message: String::new() })
+        },
+        (Method::POST, "/register") => {
+            settings_part.register = Some(true);
+            settings_part.revoke = query_flag(&request, "revoke");
+            QueryPart::RegisterQueryPart(RegisterQueryPart {})
+        },
+        (Method::POST, "/auth") => {
+            match read_json::<AuthQueryPart>(request).await {
+                Ok(query_part) => QueryPart::AuthQueryPart(query_part),
+                Err(response) => { return Ok(response); },
+            }
+        },
+        (Method::POST, "/login") => {
+            match read_json::<LoginQueryPart>(request).await {
+                Ok(query_part) => QueryPart::LoginQueryPart(query_part),
+                Err(response) => { return Ok(response); },
+            }
+        },
+        (Method::POST, "/channel") => {
+            match read_json::<ChannelQueryPart>(request).await {
+                Ok(query_part) => QueryPart::ChannelQueryPart(query_part),
+                Err(response) => { return Ok(response); },
+            }
+        },
+        (_, "/entries" | "/subscriptions" | "/register" | "/auth" | "/login" | "/channel") => {
+            return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+        },
+        _ => { return Ok(error_response(StatusCode::NOT_FOUND, "not found")); },
+    };
+
+    let forward = matches!(query_part, QueryPart::ChannelQueryPart(_));
+    Ok(answer(store, UserQuery { query_part, settings_part }, forward).await)
+}
+
+async fn unsubscribe(store: CosmosRustBotStore, request: &Request<Body>) -> Response<Body> {
+    let (user_hash, subscription, token) = match (query_param(request, "user").and_then(|x| x.parse::<u64>().ok()), query_param(request, "subscription"), query_param(request, "token")) {
+        (Some(user_hash), Some(subscription), Some(token)) => (user_hash, subscription, token),
+        _ => { return error_response(StatusCode::BAD_REQUEST, "the user, subscription and token parameters are required"); },
+    };
+    let lookup_store = store.clone();
+    let query = tokio::task::spawn_blocking(move || {
+        let smtp = email::SmtpConfig::load(&lookup_store.config().files.smtp_config_json).ok()?;
+        email::unsubscribe_query(&lookup_store.subscription_store, &smtp, user_hash, &subscription, &token)
+    }).await;
+    match query {
+        Ok(Some(query)) => answer(store, query, false).await,
+        _ => error_response(StatusCode::FORBIDDEN, "invalid unsubscribe link, or already unsubscribed"),
+    }
+}
+
+/// OpenAPI 3 description of the gateway.
+pub fn openapi() -> Value {
+    let notification = json!({"content": {"application/json": {"schema": {"$ref": "#/components/schemas/Notification"}}}});
+    let error = json!({"content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}});
+    let flag = |name: &str, description: &str| json!({"name": name, "in": "query", "required": false, "schema": {"type": "boolean"}, "description": description});
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "cosmos-rust-bot query gateway",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "JSON access to the cosmos-rust-bot query service. Responses are the Notification returned by the query service."
+        },
+        "servers": [{"url": API_PREFIX}],
+        "components": {
+            "securitySchemes": {"basic": {"type": "http", "scheme": "basic", "description": "user id and token of the Registration"}},
+            "schemas": {
+                "Notification": {"type": "object", "properties": {
+                    "query": {"type": "object", "description": "the UserQuery that was answered"},
+                    "entries": {"type": "array", "items": {"type": "object"}},
+                    "user_list": {"type": "array", "items": {"type": "integer", "format": "uint64"}},
+                    "schedules": {"type": "object"}
+                }},
+                "EntriesQueryPart": {"type": "object", "required": ["message", "display", "indices", "filter", "order_by", "limit"], "properties": {
+                    "message": {"type": "string"},
+                    "display": {"type": "string"},
+                    "indices": {"type": "array", "items": {"type": "string"}},
+                    "filter": {"type": "array", "items": {"type": "array", "items": {"type": "array", "items": {"type": "string"}, "minItems": 2, "maxItems": 2}}},
+                    "order_by": {"type": "string"},
+                    "limit": {"type": "integer"}
+                }},
+                "AuthQueryPart": {"type": "object", "required": ["token", "user_hash"], "properties": {
+                    "token": {"type": "string"},
+                    "user_hash": {"type": "integer", "format": "uint64"}
+                }},
+                "LoginQueryPart": {"type": "object", "required": ["user_hash", "code"], "properties": {
+                    "user_hash": {"type": "integer", "format": "uint64"},
+                    "code": {"type": "string", "description": "one-time code of the login link"}
+                }},
+                "ChannelQueryPart": {"description": "\"Telegram\" or an object with a single key", "oneOf": [
+                    {"type": "string", "enum": ["Telegram"]},
+                    {"type": "object", "required": ["Matrix"], "properties": {"Matrix": {"type": "object", "required": ["homeserver", "access_token", "room_id"], "properties": {
+                        "homeserver": {"type": "string"}, "access_token": {"type": "string"}, "room_id": {"type": "string"}
+                    }}}},
+                    {"type": "object", "required": ["Discord"], "properties": {"Discord": {"type": "object", "required": ["webhook_url"], "properties": {"webhook_url": {"type": "string"}}}}},
+                    {"type": "object", "required": ["Email"], "properties": {"Email": {"type": "object", "required": ["address"], "properties": {"address": {"type": "string", "format": "email"}}}}}
+                ]},
+                "Error": {"type": "object", "properties": {"error": {"type": "string"}}}
+            }
+        },
+        "paths": {
+            "/entries": {"post": {
+                "summary": "Query entries, optionally subscribing to the query",
+                "security": [{}, {"basic": []}],
+                "parameters": [flag("subscribe", "subscribe to the query (requires credentials)"), flag("unsubscribe", "unsubscribe from the query")],
+                "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/EntriesQueryPart"}}}},
+                "responses": {"200": notification.clone(), "400": error.clone(), "401": error.clone(), "403": notification.clone(), "410": notification.clone(), "429": notification.clone()}
+            }},
+            "/entries/stream": {"get": {
+                "summary": "Server-sent events with the changes of the query result: `insert` and `remove` events, the first events are the current result",
+                "security": [{}, {"basic": []}],
+                "parameters": [{"name": "query", "in": "query", "required": true, "description": "JSON encoded EntriesQueryPart", "schema": {"type": "string"}}],
+                "responses": {
+                    "200": {"description": "event stream, the data of an event is a JSON encoded EntryEvent", "content": {"text/event-stream": {"schema": {"type": "string"}}}},
+                    "400": error.clone(), "401": error.clone(), "403": {"description": "AccessDenied"}, "410": {"description": "CursorExpired"}, "429": {"description": "QuotaExceeded"}
+                }
+            }},
+            "/subscriptions": {
+                "get": {"summary": "The subscriptions of the user", "security": [{"basic": []}], "responses": {"200": notification.clone(), "401": error.clone()}},
+                "delete": {"summary": "Unsubscribe from all subscriptions", "security": [{"basic": []}], "responses": {"200": notification.clone(), "401": error.clone()}}
+            },
+            "/register": {"post": {
+                "summary": "Rotate the token of the user, or revoke it",
+                "security": [{"basic": []}],
+                "parameters": [flag("revoke", "revoke the token instead of issuing a new one")],
+                "responses": {"200": notification.clone(), "401": error.clone()}
+            }},
+            "/auth": {"post": {
+                "summary": "Verify a user id and token",
+                "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/AuthQueryPart"}}}},
+                "responses": {"200": notification.clone(), "400": error.clone()}
+            }},
+            "/login": {"post": {
+                "summary": "Exchange the one-time code of the login link for a new token (IssuedToken), the previous token stops working",
+                "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/LoginQueryPart"}}}},
+                "responses": {"200": notification.clone(), "400": error.clone()}
+            }},
+            "/channel": {"post": {
+                "summary": "Deliver the notifications of the user to Telegram, Matrix, Discord or by email (ChannelChanged if accepted)",
+                "security": [{"basic": []}],
+                "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/ChannelQueryPart"}}}},
+                "responses": {"200": notification.clone(), "400": error.clone(), "401": error.clone()}
+            }},
+            "/unsubscribe": {"get": {
+                "summary": "Unsubscribe link of an email summary",
+                "parameters": [
+                    {"name": "user", "in": "query", "required": true, "schema": {"type": "integer", "format": "uint64"}},
+                    {"name": "subscription", "in": "query", "required": true, "schema": {"type": "string"}},
+                    {"name": "token", "in": "query", "required": true, "schema": {"type": "string"}}
+                ],
+                "responses": {"200": notification, "400": error.clone(), "403": error}
+            }},
+            "/openapi.json": {"get": {"summary": "This description", "responses": {"200": {"description": "OpenAPI document"}}}}
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{route, ClientQuota, Gateway};
+    use crate::utils::entry::db::auth::issue_token;
+    use crate::utils::entry::db::{CosmosRustBotStore, SubscriptionStore};
+    use crate::utils::entry::CosmosRustBotValue;
+    use cosmos_rust_package::tokio;
+    use hyper::{Body, Method, Request, StatusCode};
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    fn request(method: Method, uri: &str, credentials: Option<(u64, &str)>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some((user_hash, token)) = credentials {
+            builder = builder.header("Authorization", format!("Basic {}", base64::encode(format!("{}:{}", user_hash, token))));
+        }
+        builder.body(Body::from(body.to_owned())).unwrap()
+    }
+
+    async fn call(gateway: &Arc<Gateway>, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = route(gateway.clone(), IpAddr::from([127, 0, 0, 1]), request).await.unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    pub async fn gateway_routes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = CosmosRustBotStore::new(db.clone(), SubscriptionStore::new(&db)).unwrap();
+        let (registration, issued) = issue_token(7, cosmos_rust_package::chrono::Utc::now().timestamp());
+        let item = CosmosRustBotValue::Registration(registration);
+        let key = item.key();
+        let value: Vec<u8> = item.try_into().unwrap();
+        db.insert(key, value).unwrap();
+        let gateway = Arc::new(Gateway::new(&store));
+
+        let (status, openapi) = call(&gateway, request(Method::GET, "/api/v1/openapi.json", None, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(openapi["paths"]["/entries"]["post"].is_object());
+
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/subscriptions", None, "")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/subscriptions", Some((7, "wrong")), "")).await.0, StatusCode::UNAUTHORIZED);
+        let (status, subscriptions) = call(&gateway, request(Method::GET, "/api/v1/subscriptions", Some((7, &issued.token)), "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(subscriptions["entries"], serde_json::json!([]));
+
+        assert_eq!(call(&gateway, request(Method::POST, "/api/v1/entries", None, "{")).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/entries", None, "")).await.0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/entries/stream", None, "")).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(call(&gateway, request(Method::POST, "/api/v1/channel", None, "\"Telegram\"")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/unsubscribe?user=7", None, "")).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(call(&gateway, request(Method::GET, "/api/v1/unsubscribe?user=7&subscription=00&token=00", None, "")).await.0, StatusCode::FORBIDDEN);
+
+        let body = format!("{{\"user_hash\": 7, \"token\": \"{}\"}}", issued.token);
+        let (status, auth) = call(&gateway, request(Method::POST, "/api/v1/auth", None, &body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(auth["entries"][0]["Authorization"]["is_authorized"], serde_json::json!(true));
+    }
+
+    #[test]
+    pub fn anonymous_requests_per_client() {
+        let clients = ClientQuota::new(2);
+        let (a, b) = (IpAddr::from([203, 0, 113, 1]), IpAddr::from([203, 0, 113, 2]));
+        let now = 1672655400;
+        assert!(clients.count(a, now).is_ok());
+        assert!(clients.count(a, now).is_ok());
+        assert_eq!(clients.count(a, now).unwrap_err().limit, 2);
+        assert!(clients.count(b, now).is_ok());
+        // the next minute starts a new window.
+        assert!(clients.count(a, now + 60).is_ok());
+    }
+}
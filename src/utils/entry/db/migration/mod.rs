@@ -0,0 +1,689 @@
+use std::collections::{HashMap, HashSet};
+use log::info;
+use serde::{Serialize,Deserialize};
+use cosmos_rust_package::chrono::Utc;
+
+use crate::utils::entry::*;
+use crate::utils::entry::db::{CosmosRustBotStore, TaskMemoryStore};
+use crate::utils::entry::db::notification::get_user_meta_data;
+use crate::utils::entry::db::auth;
+use crate::utils::hash::legacy_hash;
+use cosmos_rust_package::api::custom::types::gov::tally_ext::TallyResultExt;
+use cosmos_rust_package::api::custom::types::gov::params_ext::ParamsExt;
+use cosmos_rust_package::api::custom::types::staking::pool_ext::PoolExt;
+
+// One-time migration from `DefaultHasher` based keys to `stable_hash` based keys.
+//
+// Markers are kept in a separate tree, the default trees only contain values that deserialize
+// into `CosmosRustBotValue`/`CosmosRustServerValue`.
+//
+// The entry keys, the user hashes and the layout migrations (`migrate_store_layout`) run when the `CosmosRustBotStore` is opened,
+// the user hashes are looked up in the user meta data export of the notification service (`Config.files.user_meta_data_json`).
+// The notification db is migrated when the notification server is spawned.
+// `crb-store bot <path> migrate [<subscription path> <notification path>]` runs them without starting the services,
+// `crb-store task <path> migrate` migrates the task store:
+//
+// let user_hashes = legacy_user_hashes(&notification_db);
+// migrate_notification_db(&notification_db, &user_hashes)?;
+// migrate_cosmos_rust_bot_store(&cosmos_rust_bot_store, &user_hashes)?;
+// let mut renames = link_to_text::legacy_key_renames(&task_store);
+// renames.append(&mut gpt3::legacy_key_renames(&task_store));
+// migrate_task_memory_store(&task_store, renames, gov::legacy_page_keys(&task_store))?;
+
+const MIGRATION_TREE: &str = "migration";
+const STABLE_HASH_MIGRATION: &str = "stable_hash_v1";
+const STABLE_USER_HASH_MIGRATION: &str = "stable_user_hash_v1";
+const SUBSCRIPTION_LAYOUT_MIGRATION: &str = "subscription_layout_v3";
+const REGISTRATION_LAYOUT_MIGRATION: &str = "registration_layout_v2";
+
+fn is_migrated_to(db: &sled::Db, migration: &str) -> anyhow::Result<bool> {
+    Ok(db.open_tree(MIGRATION_TREE)?.contains_key(migration)?)
+}
+
+fn set_migrated_to(db: &sled::Db, migration: &str) -> anyhow::Result<()> {
+    db.open_tree(MIGRATION_TREE)?.insert(migration, Utc::now().timestamp().to_be_bytes().to_vec())?;
+    db.flush()?;
+    Ok(())
+}
+
+fn is_migrated(db: &sled::Db) -> anyhow::Result<bool> {
+    is_migrated_to(db, STABLE_HASH_MIGRATION)
+}
+
+fn set_migrated(db: &sled::Db) -> anyhow::Result<()> {
+    set_migrated_to(db, STABLE_HASH_MIGRATION)
+}
+
+/// Maps the legacy user hash to the stable user hash for every known user.
+pub fn legacy_user_hashes(notification_db: &sled::Db) -> HashMap<u64,u64> {
+    get_user_meta_data(notification_db)
+        .map(|x| (legacy_hash(&x.user_id), UserMetaData::user_hash(x.user_id)))
+        .collect()
+}
+
+/// Same as `legacy_user_hashes`, read from the export written by `notification::export_user_meta_data`.
+pub fn legacy_user_hashes_from_export(path: &str) -> anyhow::Result<HashMap<u64,u64>> {
+    let user_meta_data: Vec<UserMetaData> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(user_meta_data.into_iter()
+        .map(|x| (legacy_hash(&x.user_id), UserMetaData::user_hash(x.user_id)))
+        .collect())
+}
+
+fn map_user_hash(user_hashes: &HashMap<u64,u64>, user_hash: u64) -> u64 {
+    *user_hashes.get(&user_hash).unwrap_or(&user_hash)
+}
+
+// a value already stored under the new key was written after the upgrade (e.g. the user registered again
+// before the migration ran), it is newer than the migrated one and kept.
+fn re_key<T: Into<Vec<u8>>>(tree: &sled::Db, old_key: &[u8], new_key: &[u8], value: T) -> anyhow::Result<()> {
+    if old_key != new_key {
+        tree.remove(old_key)?;
+        if tree.contains_key(new_key)? {
+            return Ok(());
+        }
+    }
+    tree.insert(new_key, value.into())?;
+    Ok(())
+}
+
+/// Re-keys UserMetaData and Notify records and replaces legacy user hashes.
+pub fn migrate_notification_db(db: &sled::Db, user_hashes: &HashMap<u64,u64>) -> anyhow::Result<()> {
+    if is_migrated(db)? {
+        return Ok(());
+    }
+    let items = db.iter().filter_map(|x| x.ok()).collect::<Vec<(sled::IVec,sled::IVec)>>();
+    for (old_key, value) in items {
+        let item = match CosmosRustServerValue::try_from(value.to_vec()) {
+            Ok(CosmosRustServerValue::Notify(mut notify)) => {
+                notify.user_hash = map_user_hash(user_hashes, notify.user_hash);
+                CosmosRustServerValue::Notify(notify)
+            },
+            Ok(item) => item,
+            Err(_) => { continue; }
+        };
+        let new_key = item.key();
+        let value: Vec<u8> = item.try_into()?;
+        re_key(db, &old_key, &new_key, value)?;
+    }
+    info!("Migrated notification db to stable hashes.");
+    set_migrated(db)
+}
+
+/// Re-keys entries and maps the entry keys stored in `Subscription.list` to their new keys,
+/// so that the next `update_items` does not report every subscription as changed.
+/// Entries with the legacy `ProposalData` layout are rewritten with the current layout,
+/// this has to run before `remove_undecodable_items`.
+pub fn migrate_entry_keys(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+
+    let entry_index_db = &store.entry_store.0.db;
+    let subscription_db = &store.subscription_store.0.db;
+
+    if is_migrated(entry_index_db)? {
+        return Ok(());
+    }
+    let mut entry_keys: HashMap<Vec<u8>,Vec<u8>> = HashMap::new();
+    let items = entry_index_db.scan_prefix(&Entry::get_prefix()[..]).filter_map(|x| x.ok()).collect::<Vec<(sled::IVec,sled::IVec)>>();
+    for (old_key, value) in items {
+        let entry = match decode_entry_v1(&value) {
+            Some(entry) => entry,
+            None => match CosmosRustBotValue::try_from(value.to_vec()) {
+                Ok(CosmosRustBotValue::Entry(entry)) => entry,
+                _ => { continue; }
+            }
+        };
+        let item = CosmosRustBotValue::Entry(entry);
+        let new_key = item.key();
+        entry_keys.insert(old_key.to_vec(), new_key.clone());
+        let value: Vec<u8> = item.try_into()?;
+        re_key(entry_index_db, &old_key, &new_key, value)?;
+    }
+    // indices reference the legacy entry keys, they are rebuilt by `update_items`.
+    let index_keys = entry_index_db.scan_prefix(&Index::get_prefix()[..]).keys().collect::<sled::Result<Vec<sled::IVec>>>()?;
+    for key in index_keys {
+        entry_index_db.remove(key)?;
+    }
+
+    let items = subscription_db.scan_prefix(&Subscription::get_prefix()[..]).filter_map(|x| x.ok()).collect::<Vec<(sled::IVec,sled::IVec)>>();
+    for (old_key, value) in items {
+        let mut s = match decode_subscription_v1(&value).or_else(|| decode_subscription_v2(&value)) {
+            Some(s) => s,
+            None => match CosmosRustBotValue::try_from(value.to_vec()) {
+                Ok(CosmosRustBotValue::Subscription(s)) => s,
+                _ => { continue; }
+            }
+        };
+        s.list = s.list.into_iter().map(|x| entry_keys.get(&x).cloned().unwrap_or(x)).collect();
+        let item = CosmosRustBotValue::Subscription(s);
+        let new_key = item.key();
+        let value: Vec<u8> = item.try_into()?;
+        re_key(subscription_db, &old_key, &new_key, value)?;
+    }
+    info!("Migrated {} entries and their subscriptions to stable hashes.", entry_keys.len());
+    set_migrated(entry_index_db)
+}
+
+/// Replaces legacy user hashes in subscriptions and re-keys registrations.
+/// Runs after the layout migrations, i.e. on an opened `CosmosRustBotStore`.
+pub fn migrate_cosmos_rust_bot_store(store: &CosmosRustBotStore, user_hashes: &HashMap<u64,u64>) -> anyhow::Result<()> {
+
+    let subscription_db = &store.subscription_store.0.db;
+
+    if is_migrated_to(subscription_db, STABLE_USER_HASH_MIGRATION)? {
+        return Ok(());
+    }
+    let items = subscription_db.scan_prefix(&Subscription::get_prefix()[..])
+        .chain(subscription_db.scan_prefix(&Registration::get_prefix()[..]))
+        .filter_map(|x| x.ok())
+        .collect::<Vec<(sled::IVec,sled::IVec)>>();
+    for (old_key, value) in items {
+        let item = match CosmosRustBotValue::try_from(value.to_vec()) {
+            Ok(CosmosRustBotValue::Subscription(mut s)) => {
+                s.user_list = s.user_list.into_iter().map(|x| map_user_hash(user_hashes, x)).collect();
+                // a schedule set with the stable user hash is newer than the legacy one.
+                let mut schedules = HashMap::new();
+                for (user_hash, schedule) in s.schedules {
+                    let new_user_hash = map_user_hash(user_hashes, user_hash);
+                    if new_user_hash == user_hash {
+                        schedules.insert(user_hash, schedule);
+                    } else {
+                        schedules.entry(new_user_hash).or_insert(schedule);
+                    }
+                }
+                s.schedules = schedules;
+                CosmosRustBotValue::Subscription(s)
+            },
+            Ok(CosmosRustBotValue::Registration(mut r)) => {
+                r.user_hash = map_user_hash(user_hashes, r.user_hash);
+                CosmosRustBotValue::Registration(r)
+            },
+            _ => { continue; }
+        };
+        let new_key = item.key();
+        let value: Vec<u8> = item.try_into()?;
+        re_key(subscription_db, &old_key, &new_key, value)?;
+    }
+    info!("Migrated subscription store to stable user hashes.");
+    set_migrated_to(subscription_db, STABLE_USER_HASH_MIGRATION)
+}
+
+/// Runs `migrate_cosmos_rust_bot_store` with the user meta data export of the notification service.
+/// Without an export the migration is postponed to the next start.
+pub fn migrate_user_hashes(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+    if is_migrated_to(&store.subscription_store.0.db, STABLE_USER_HASH_MIGRATION)? {
+        return Ok(());
+    }
+    let path = &store.config().files.user_meta_data_json;
+    match legacy_user_hashes_from_export(path) {
+        Ok(user_hashes) => migrate_cosmos_rust_bot_store(store, &user_hashes),
+        Err(err) => {
+            info!("Postponed the stable user hash migration, unable to read {}: {}", path, err.to_string());
+            Ok(())
+        }
+    }
+}
+
+/// Applies the given key renames and removals to the TaskMemoryStore.
+pub fn migrate_task_memory_store(task_store: &TaskMemoryStore, renames: Vec<(String,String)>, removals: Vec<String>) -> anyhow::Result<()> {
+    let db = &task_store.0.db;
+    if is_migrated(db)? {
+        return Ok(());
+    }
+    for (from, to) in renames {
+        task_store.rename_key(&from, &to)?;
+    }
+    for key in removals {
+        task_store.remove_key(&key)?;
+    }
+    info!("Migrated task memory store to stable hashes.");
+    set_migrated(db)
+}
+
+// `EntriesQueryPart` before pagination and ordering options were added.
+#[derive(Serialize,Deserialize)]
+struct EntriesQueryPartV1 {
+    message: String,
+    display: String,
+    indices: Vec<String>,
+    filter: Vec<Vec<(String, String)>>,
+    order_by: String,
+    limit: usize,
+}
+
+// `AuthQueryPart` with the plain 64 bit token.
+#[derive(Serialize,Deserialize)]
+struct AuthQueryPartV1 {
+    token: u64,
+    user_hash: u64,
+}
+
+// the variant order must match `QueryPart`.
+#[derive(Serialize,Deserialize)]
+enum QueryPartV1 {
+    RegisterQueryPart(RegisterQueryPart),
+    AuthQueryPart(AuthQueryPartV1),
+    EntriesQueryPart(EntriesQueryPartV1),
+    SubscriptionsQueryPart(SubscriptionsQueryPart),
+}
+
+#[derive(Serialize,Deserialize)]
+struct SubscriptionV1 {
+    action: SubscriptionAction,
+    query: QueryPartV1,
+    user_list: HashSet<u64>,
+    list: Vec<Vec<u8>>,
+}
+
+// `Registration` with the plain 64 bit token.
+#[derive(Serialize,Deserialize)]
+struct RegistrationV1 {
+    token: u64,
+    user_hash: u64,
+}
+
+// `ProposalData` before similar proposals and resubmissions were added.
+#[derive(Serialize,Deserialize,Default)]
+struct ProposalDataV1 {
+    proposal_api: String,
+    proposal_link: String,
+    proposal_summary: String,
+    proposal_briefing: String,
+    proposal_blockchain: String,
+    proposal_blockchain_display: String,
+    proposal_status: String,
+    proposal_id: u64,
+    proposal_type: Option<String>,
+    proposal_SubmitTime: Option<i64>,
+    proposal_DepositEndTime: Option<i64>,
+    proposal_VotingStartTime: Option<i64>,
+    proposal_VotingEndTime: Option<i64>,
+    proposal_LatestTime: Option<i64>,
+    proposal_title: String,
+    proposal_description: String,
+    proposal_vetoed: bool,
+    proposal_state: String,
+    proposal_state_details: Option<String>,
+    proposal_in_deposit_period: bool,
+    fraud_risk: String,
+    proposal_tally_result: Option<TallyResultExt>,
+    proposal_tallying_param: Option<ParamsExt>,
+    proposal_voting_param: Option<ParamsExt>,
+    proposal_deposit_param: Option<ParamsExt>,
+    proposal_blockchain_pool: Option<PoolExt>,
+    proposal_status_icon: String,
+    proposal_preview_msg: String,
+    proposal_spam_likelihood: String,
+    proposal_voter_turnout: Option<String>,
+    proposal_blockchain_pool_details: Option<String>,
+    proposal_tally_result_detail: Option<String>,
+    proposal_submitted: String,
+}
+
+impl From<ProposalDataV1> for ProposalData {
+    fn from(p: ProposalDataV1) -> Self {
+        ProposalData {
+            proposal_api: p.proposal_api,
+            proposal_link: p.proposal_link,
+            proposal_summary: p.proposal_summary,
+            proposal_briefing: p.proposal_briefing,
+            proposal_blockchain: p.proposal_blockchain,
+            proposal_blockchain_display: p.proposal_blockchain_display,
+            proposal_status: p.proposal_status,
+            proposal_id: p.proposal_id,
+            proposal_type: p.proposal_type,
+            proposal_SubmitTime: p.proposal_SubmitTime,
+            proposal_DepositEndTime: p.proposal_DepositEndTime,
+            proposal_VotingStartTime: p.proposal_VotingStartTime,
+            proposal_VotingEndTime: p.proposal_VotingEndTime,
+            proposal_LatestTime: p.proposal_LatestTime,
+            proposal_title: p.proposal_title,
+            proposal_description: p.proposal_description,
+            proposal_vetoed: p.proposal_vetoed,
+            proposal_state: p.proposal_state,
+            proposal_state_details: p.proposal_state_details,
+            proposal_in_deposit_period: p.proposal_in_deposit_period,
+            fraud_risk: p.fraud_risk,
+            proposal_tally_result: p.proposal_tally_result,
+            proposal_tallying_param: p.proposal_tallying_param,
+            proposal_voting_param: p.proposal_voting_param,
+            proposal_deposit_param: p.proposal_deposit_param,
+            proposal_blockchain_pool: p.proposal_blockchain_pool,
+            proposal_status_icon: p.proposal_status_icon,
+            proposal_preview_msg: p.proposal_preview_msg,
+            proposal_spam_likelihood: p.proposal_spam_likelihood,
+            proposal_voter_turnout: p.proposal_voter_turnout,
+            proposal_blockchain_pool_details: p.proposal_blockchain_pool_details,
+            proposal_tally_result_detail: p.proposal_tally_result_detail,
+            proposal_submitted: p.proposal_submitted,
+            proposal_similar: Vec::new(),
+            proposal_resubmission_of: None,
+        }
+    }
+}
+
+// the variant order must match `CustomData`.
+#[derive(Serialize,Deserialize)]
+enum CustomDataV1 {
+    MetaData(MetaData),
+    ProposalData(ProposalDataV1),
+    Debug(crate::utils::entry::Debug),
+    Error(crate::utils::entry::Error),
+    Log(Log),
+}
+
+#[derive(Serialize,Deserialize)]
+struct ValueV1 {
+    timestamp: i64,
+    origin: String,
+    custom_data: CustomDataV1,
+    imperative: ValueImperative,
+}
+
+#[derive(Serialize,Deserialize)]
+enum EntryV1 {
+    Value(ValueV1),
+}
+
+impl From<EntryV1> for Entry {
+    fn from(entry: EntryV1) -> Self {
+        match entry {
+            EntryV1::Value(v) => Entry::Value(Value {
+                timestamp: v.timestamp,
+                origin: v.origin,
+                custom_data: match v.custom_data {
+                    CustomDataV1::MetaData(x) => CustomData::MetaData(x),
+                    CustomDataV1::ProposalData(x) => CustomData::ProposalData(x.into()),
+                    CustomDataV1::Debug(x) => CustomData::Debug(x),
+                    CustomDataV1::Error(x) => CustomData::Error(x),
+                    CustomDataV1::Log(x) => CustomData::Log(x),
+                },
+                imperative: v.imperative,
+            }),
+        }
+    }
+}
+
+// the variant order must match `CosmosRustBotValue`.
+#[derive(Serialize,Deserialize)]
+enum CosmosRustBotValueV1 {
+    Index(Index),
+    Entry(EntryV1),
+    Subscription(SubscriptionV1),
+    Registration(RegistrationV1),
+    Authorization(Authorization),
+}
+
+// bincode ignores trailing bytes, a value only has the legacy layout if it re-encodes to the same length
+// (the bytes may differ, `user_list` is a HashSet).
+fn decode_subscription_v1(value: &[u8]) -> Option<Subscription> {
+    let legacy: CosmosRustBotValueV1 = bincode::deserialize(value).ok()?;
+    if bincode::serialized_size(&legacy).ok()? != value.len() as u64 {
+        return None;
+    }
+    match legacy {
+        CosmosRustBotValueV1::Subscription(s) => Some(Subscription {
+            action: s.action,
+            query: match s.query {
+                QueryPartV1::RegisterQueryPart(q) => QueryPart::RegisterQueryPart(q),
+                QueryPartV1::AuthQueryPart(q) => QueryPart::AuthQueryPart(AuthQueryPart { token: q.token.to_string(), user_hash: q.user_hash }),
+                QueryPartV1::SubscriptionsQueryPart(q) => QueryPart::SubscriptionsQueryPart(q),
+                QueryPartV1::EntriesQueryPart(q) => QueryPart::EntriesQueryPart(EntriesQueryPart {
+                    message: q.message,
+                    display: q.display,
+                    indices: q.indices,
+                    filter: q.filter,
+                    order_by: q.order_by,
+                    limit: q.limit,
+                    order_direction: OrderDirection::default(),
+                    order: Vec::new(),
+                    cursor: None,
+                    offset: 0,
+                    search: None,
+                    semantic: None,
+                }),
+            },
+            user_list: s.user_list,
+            list: s.list,
+            schedules: HashMap::new(),
+        }),
+        _ => None,
+    }
+}
+
+fn decode_entry_v1(value: &[u8]) -> Option<Entry> {
+    let legacy: CosmosRustBotValueV1 = bincode::deserialize(value).ok()?;
+    if bincode::serialized_size(&legacy).ok()? != value.len() as u64 {
+        return None;
+    }
+    match legacy {
+        CosmosRustBotValueV1::Entry(entry) => Some(entry.into()),
+        _ => None,
+    }
+}
+
+// `Subscription` before delivery schedules were added.
+#[derive(Serialize,Deserialize)]
+struct SubscriptionV2 {
+    action: SubscriptionAction,
+    query: QueryPart,
+    user_list: HashSet<u64>,
+    list: Vec<Vec<u8>>,
+}
+
+// the variant order must match `CosmosRustBotValue`.
+#[derive(Serialize,Deserialize)]
+enum CosmosRustBotValueV2 {
+    Index(Index),
+    Entry(Entry),
+    Subscription(SubscriptionV2),
+}
+
+fn decode_subscription_v2(value: &[u8]) -> Option<Subscription> {
+    let legacy: CosmosRustBotValueV2 = bincode::deserialize(value).ok()?;
+    if bincode::serialized_size(&legacy).ok()? != value.len() as u64 {
+        return None;
+    }
+    match legacy {
+        CosmosRustBotValueV2::Subscription(s) => Some(Subscription {
+            action: s.action,
+            query: s.query,
+            user_list: s.user_list,
+            list: s.list,
+            schedules: HashMap::new(),
+        }),
+        _ => None,
+    }
+}
+
+/// Rewrites subscriptions stored before `EntriesQueryPart` had ordering and pagination options,
+/// or before subscriptions had delivery schedules.
+/// The subscription key does not change, `migrate_entry_keys` already re-keyed them.
+pub fn migrate_subscription_layout(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+    let subscription_db = &store.subscription_store.0.db;
+    if is_migrated_to(subscription_db, SUBSCRIPTION_LAYOUT_MIGRATION)? {
+        return Ok(());
+    }
+    let items = subscription_db.scan_prefix(&Subscription::get_prefix()[..]).filter_map(|x| x.ok()).collect::<Vec<(sled::IVec,sled::IVec)>>();
+    let mut count = 0;
+    for (old_key, value) in items {
+        if let Some(s) = decode_subscription_v1(&value).or_else(|| decode_subscription_v2(&value)) {
+            let item = CosmosRustBotValue::Subscription(s);
+            let new_key = item.key();
+            let value: Vec<u8> = item.try_into()?;
+            re_key(subscription_db, &old_key, &new_key, value)?;
+            count += 1;
+        }
+    }
+    info!("Migrated {} subscriptions to the current layout.", count);
+    set_migrated_to(subscription_db, SUBSCRIPTION_LAYOUT_MIGRATION)
+}
+
+/// Replaces the plain tokens of registrations with salted hashes.
+/// The legacy tokens stay valid for `auth::LEGACY_TOKEN_GRACE_SECONDS`.
+pub fn migrate_registration_layout(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+    let subscription_db = &store.subscription_store.0.db;
+    if is_migrated_to(subscription_db, REGISTRATION_LAYOUT_MIGRATION)? {
+        return Ok(());
+    }
+    let now = Utc::now().timestamp();
+    let items = subscription_db.scan_prefix(&Registration::get_prefix()[..]).filter_map(|x| x.ok()).collect::<Vec<(sled::IVec,sled::IVec)>>();
+    let mut count = 0;
+    for (key, value) in items {
+        let legacy = match bincode::deserialize::<CosmosRustBotValueV1>(&value) {
+            Ok(legacy) if bincode::serialized_size(&legacy)? == value.len() as u64 => legacy,
+            _ => { continue; }
+        };
+        if let CosmosRustBotValueV1::Registration(r) = legacy {
+            // the legacy login link had the token in decimal.
+            let registration = auth::registration_for(r.user_hash, &r.token.to_string(), now, now + auth::LEGACY_TOKEN_GRACE_SECONDS);
+            let value: Vec<u8> = CosmosRustBotValue::Registration(registration).try_into()?;
+            subscription_db.insert(key, value)?;
+            count += 1;
+        }
+    }
+    info!("Migrated {} registrations to hashed tokens.", count);
+    set_migrated_to(subscription_db, REGISTRATION_LAYOUT_MIGRATION)
+}
+
+/// Removes entries and indices that no longer decode (e.g. after a field was added to `ProposalData`).
+/// They are derived from the task memory store and rebuilt by the next `update_items`.
+pub fn remove_undecodable_items(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+    let entry_index_db = &store.entry_store.0.db;
+    let keys = entry_index_db.iter()
+        .filter_map(|x| x.ok())
+        .filter(|(_k, v)| CosmosRustBotValue::try_from(v.to_vec()).is_err())
+        .map(|(k, _v)| k)
+        .collect::<Vec<sled::IVec>>();
+    for key in &keys {
+        entry_index_db.remove(key)?;
+    }
+    if !keys.is_empty() {
+        info!("Removed {} undecodable entries/indices.", keys.len());
+    }
+    Ok(())
+}
+
+/// Re-keys entries, rewrites subscriptions and registrations stored with a previous layout and removes undecodable entries.
+pub fn migrate_store_layout(store: &CosmosRustBotStore) -> anyhow::Result<()> {
+    migrate_entry_keys(store)?;
+    migrate_subscription_layout(store)?;
+    migrate_registration_layout(store)?;
+    migrate_user_hashes(store)?;
+    remove_undecodable_items(store)
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::{HashMap, HashSet};
+    use super::{migrate_cosmos_rust_bot_store, CosmosRustBotValueV1, RegistrationV1, EntryV1, ValueV1, CustomDataV1, ProposalDataV1, SubscriptionV1, QueryPartV1, EntriesQueryPartV1};
+    use crate::utils::entry::db::{auth, CosmosRustBotStore, SubscriptionStore};
+    use crate::utils::entry::{CosmosRustBotValue, DeliverySchedule, Entry, EntriesQueryPart, QueryPart, Registration, Subscription, SubscriptionAction, UserMetaData, ValueImperative};
+    use crate::utils::hash::legacy_hash;
+    use cosmos_rust_package::chrono::Utc;
+
+    #[test]
+    pub fn legacy_entry_keys_are_mapped_in_subscriptions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let legacy_entry = CosmosRustBotValueV1::Entry(EntryV1::Value(ValueV1 {
+            timestamp: 0,
+            origin: "gov_proposals".to_string(),
+            custom_data: CustomDataV1::ProposalData(ProposalDataV1 { proposal_id: 1, proposal_title: "Legacy".to_string(), ..Default::default() }),
+            imperative: ValueImperative::Notify,
+        }));
+        // keys were `DefaultHasher` hashes in native byte order.
+        let mut legacy_entry_key = Entry::get_prefix();
+        legacy_entry_key.append(&mut legacy_hash("entry").to_ne_bytes().to_vec());
+        db.insert(&legacy_entry_key, bincode::serialize(&legacy_entry).unwrap()).unwrap();
+
+        let legacy_subscription = CosmosRustBotValueV1::Subscription(SubscriptionV1 {
+            action: SubscriptionAction::Created,
+            query: QueryPartV1::EntriesQueryPart(EntriesQueryPartV1 {
+                message: "".to_string(),
+                display: "default".to_string(),
+                indices: vec!["proposal_id".to_string()],
+                filter: Vec::new(),
+                order_by: "default".to_string(),
+                limit: 10,
+            }),
+            user_list: HashSet::from([1]),
+            list: vec![legacy_entry_key.clone()],
+        });
+        let mut legacy_subscription_key = Subscription::get_prefix();
+        legacy_subscription_key.append(&mut legacy_hash("subscription").to_ne_bytes().to_vec());
+        db.insert(&legacy_subscription_key, bincode::serialize(&legacy_subscription).unwrap()).unwrap();
+
+        let mut store = CosmosRustBotStore::new(db.clone(), SubscriptionStore::new(&db)).unwrap();
+
+        assert!(db.get(&legacy_entry_key).unwrap().is_none());
+        let entries = store.entry_store.get_entries().collect::<Vec<CosmosRustBotValue>>();
+        assert_eq!(entries.len(), 1);
+        let entry_key = entries[0].key();
+
+        let subscriptions = store.subscription_store.get_subscriptions().collect::<Vec<Subscription>>();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].list, vec![entry_key.clone()]);
+        assert!(db.get(&legacy_subscription_key).unwrap().is_none());
+        assert!(db.get(subscriptions[0].get_key()).unwrap().is_some());
+
+        // the unchanged entry does not update the subscription.
+        store.update_items(entries);
+        let subscriptions = store.subscription_store.get_subscriptions().collect::<Vec<Subscription>>();
+        assert_eq!(subscriptions[0].action, SubscriptionAction::Created);
+        assert_eq!(subscriptions[0].list, vec![entry_key]);
+    }
+
+    #[test]
+    pub fn legacy_registration_decodes_after_open() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let legacy = CosmosRustBotValueV1::Registration(RegistrationV1 { token: 1234567890, user_hash: 1 });
+        db.insert(Registration::get_key_for_user_hash(1), bincode::serialize(&legacy).unwrap()).unwrap();
+        assert!(CosmosRustBotValue::try_from(db.get(Registration::get_key_for_user_hash(1)).unwrap().unwrap().to_vec()).is_err());
+
+        let store = CosmosRustBotStore::new(db.clone(), SubscriptionStore::new(&db)).unwrap();
+        let value = store.subscription_store.0.db.get(Registration::get_key_for_user_hash(1)).unwrap().unwrap();
+        match CosmosRustBotValue::try_from(value.to_vec()) {
+            Ok(CosmosRustBotValue::Registration(registration)) => {
+                // the legacy token keeps working for the grace period.
+                assert!(auth::verify_token(&registration, "1234567890", Utc::now().timestamp()));
+                assert!(!auth::verify_token(&registration, "1234567890", Utc::now().timestamp() + auth::LEGACY_TOKEN_GRACE_SECONDS + 1));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn records_written_after_the_upgrade_are_kept() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = CosmosRustBotStore::new(db.clone(), SubscriptionStore::new(&db)).unwrap();
+        let (legacy_user_hash, user_hash) = (legacy_hash(&42u64), UserMetaData::user_hash(42));
+        let user_hashes = HashMap::from([(legacy_user_hash, user_hash)]);
+
+        let now = Utc::now().timestamp();
+        // the user registered again before the migration ran.
+        for registration in [auth::registration_for(legacy_user_hash, "legacy", now - 10, now), auth::registration_for(user_hash, "new", now, now + 10)] {
+            let key = registration.get_key();
+            db.insert(key, TryInto::<Vec<u8>>::try_into(CosmosRustBotValue::Registration(registration)).unwrap()).unwrap();
+        }
+        let legacy_schedule = DeliverySchedule { utc_offset_minutes: 60, ..Default::default() };
+        let schedule = DeliverySchedule { utc_offset_minutes: 120, ..Default::default() };
+        let subscription = Subscription {
+            action: SubscriptionAction::Created,
+            query: QueryPart::EntriesQueryPart(serde_json::from_value::<EntriesQueryPart>(serde_json::json!({"message": "gov prpsl all", "display": "default", "indices": [], "filter": [], "order_by": "", "limit": 10})).unwrap()),
+            user_list: HashSet::from([legacy_user_hash, user_hash]),
+            list: Vec::new(),
+            schedules: HashMap::from([(legacy_user_hash, legacy_schedule), (user_hash, schedule.clone())]),
+        };
+        db.insert(subscription.get_key(), TryInto::<Vec<u8>>::try_into(CosmosRustBotValue::Subscription(subscription)).unwrap()).unwrap();
+
+        migrate_cosmos_rust_bot_store(&store, &user_hashes).unwrap();
+
+        assert!(db.get(Registration::get_key_for_user_hash(legacy_user_hash)).unwrap().is_none());
+        match CosmosRustBotValue::try_from(db.get(Registration::get_key_for_user_hash(user_hash)).unwrap().unwrap().to_vec()) {
+            Ok(CosmosRustBotValue::Registration(registration)) => { assert!(auth::verify_token(&registration, "new", now)); },
+            other => panic!("unexpected {:?}", other),
+        }
+        let subscriptions = store.subscription_store.get_subscriptions().collect::<Vec<Subscription>>();
+        assert_eq!(subscriptions[0].user_list, HashSet::from([user_hash]));
+        assert_eq!(subscriptions[0].schedules, HashMap::from([(user_hash, schedule)]));
+    }
+}
@@ -1,6 +1,15 @@
 pub mod notification;
 pub mod query;
 pub mod socket;
+pub mod migration;
+pub mod snapshot;
+pub mod quota;
+pub mod auth;
+pub mod access;
+pub mod audit;
+pub mod stream;
+#[cfg(feature = "http")]
+pub mod http;
 
 use sled::{IVec, Mode};
 use std::path::PathBuf;
@@ -18,28 +27,37 @@ use crate::utils::entry::*;
 use crate::utils::entry::db::notification::socket::{client_send_notification_request};
 use crate::utils::entry::db::query::socket::spawn_socket_query_server;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::utils::response::ResponseResult;
 use cosmos_rust_package::chrono::Utc;
 use serde_json::json;
-use cosmos_rust_package::tokio::time::{sleep, Duration};
+use cosmos_rust_package::tokio::sync::watch;
+use crate::utils::entry::db::notification::outbox::retry_delay;
+use crate::utils::entry::db::notification::webhook;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
 use crate::utils::entry::ValueImperative::Notify;
 
 use serde::{Serialize,Deserialize};
 use crate::utils::entry::db::query::CosmosRustBotStoreInquirer;
+use crate::utils::hash::stable_hash;
+use crate::utils::entry::search::vector::{BruteForceIndex, VectorIndex};
+use crate::utils::config::Config;
 
 
-const NOTIFICATION_SOCKET: &str = "./tmp/cosmos_rust_bot_notification_socket";
-
 const REV_INDEX_PREFIX: &str = "rev_index_";
 
-const CRB_SUBSCRIPTION_STORE_JSON: &str = "./tmp/cosmos_rust_bot_subscriptions.json";
-const CRB_REGISTRATION_STORE_JSON: &str = "./tmp/cosmos_rust_bot_registrations.json";
+const NOTIFIED_STATE_TREE: &str = "notified_state";
 
 pub fn load_sled_db(path: &str) -> sled::Db {
+    load_sled_db_with_config(path, &Config::shared())
+}
+
+pub fn load_sled_db_with_config(path: &str, config: &Config) -> sled::Db {
     let db: sled::Db = sled::Config::default()
         .path(path)
-        .cache_capacity( 1024 * 1024 * 1024) // 1gb
+        .cache_capacity(config.sled_cache_capacity)
         //.use_compression(true)
         //.compression_factor(22)
         .flush_every_ms(Some(1000))
@@ -60,26 +78,36 @@ pub struct TaskMemoryStore(SledStore);
 
 impl Clone for TaskMemoryStore {
     fn clone(&self) -> Self {
-        let sled_db_copy = self.0.db.clone();
-        TaskMemoryStore(SledStore::new(sled_db_copy))
+        TaskMemoryStore(self.0.share())
     }
 
     fn clone_from(&mut self, source: &Self) {
-        let sled_db_copy = source.0.db.clone();
-        *self = TaskMemoryStore(SledStore::new(sled_db_copy));
+        *self = TaskMemoryStore(source.0.share());
     }
 }
 
 impl TaskMemoryStore {
     pub fn new(path: Option<String>) -> anyhow::Result<Self> {
+        TaskMemoryStore::with_config(path, Config::shared())
+    }
+
+    pub fn with_config(path: Option<String>, config: Arc<Config>) -> anyhow::Result<Self> {
         let sled_store = if let Some(p) = path {
-            SledStore::open(&p)?
+            SledStore::open(&p, config)?
         }else {
-            SledStore::temporary()?
+            SledStore::temporary(config)?
         };
         Ok(TaskMemoryStore(sled_store))
     }
 
+    pub fn config(&self) -> &Config {
+        &self.0.config
+    }
+
+    pub fn get_tree(&self) -> &sled::Db {
+        self.0.get_tree()
+    }
+
     // Get: returns the max revision.
     // GetOk: returns the first ok result with max revision
     // the item stored and found with the given key must impl Deserialize for T, else an Error is returned.
@@ -217,6 +245,74 @@ impl TaskMemoryStore {
         Ok(())
     }
 
+    // moves all revisions of `from` to `to`, overwriting whatever is stored at `to`.
+    // returns false if `from` does not exist.
+    pub fn rename_key(&self, from: &str, to: &str) -> anyhow::Result<bool> {
+        trace!("rename_key: from: {}, to: {}", from, to);
+        let current_rev: Option<IVec> = self.0.get(format!("{}{}", REV_INDEX_PREFIX, from).as_bytes().to_vec())?;
+        let index = match current_rev {
+            Some(val) => u64::from_be_bytes(val.to_vec()[..].try_into()?),
+            None => { return Ok(false); }
+        };
+        self.remove_key(to)?;
+        for i in (0..=index).rev() {
+            match self.0.remove(format!("key_{}_rev_{}",from,i).as_bytes().to_vec())? {
+                Some(val) => {
+                    self.0.insert(format!("key_{}_rev_{}",to,i).as_bytes().to_vec(),val)?;
+                },
+                None => {
+                    break;
+                }
+            }
+        }
+        self.0.insert(format!("{}{}",REV_INDEX_PREFIX,to).as_bytes().to_vec(),index.to_be_bytes().to_vec())?;
+        self.0.remove(format!("{}{}",REV_INDEX_PREFIX,from).as_bytes().to_vec())?;
+        Ok(true)
+    }
+
+    // removes the key including its complete history.
+    pub fn remove_key(&self, key: &str) -> anyhow::Result<()> {
+        trace!("remove_key: key: {}", key);
+        let current_rev: Option<IVec> = self.0.remove(format!("{}{}", REV_INDEX_PREFIX, key).as_bytes().to_vec())?;
+        if let Some(val) = current_rev {
+            let index = u64::from_be_bytes(val.to_vec()[..].try_into()?);
+            for i in (0..=index).rev() {
+                if self.0.remove(format!("key_{}_rev_{}",key,i).as_bytes().to_vec())?.is_none(){
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // returns every revision still stored for the key, oldest first.
+    pub fn get_revisions<T>(&self, key: &str) -> anyhow::Result<Vec<(u64,Maybe<T>)>>
+        where
+            T: for<'a> Deserialize<'a> + Serialize
+    {
+        let current_rev: Option<IVec> = self.0.get(format!("{}{}", REV_INDEX_PREFIX, key).as_bytes().to_vec())?;
+        let mut revisions = Vec::new();
+        if let Some(val) = current_rev {
+            let index = u64::from_be_bytes(val.to_vec()[..].try_into()?);
+            for i in 0..=index {
+                if let Some(val) = self.0.get(format!("key_{}_rev_{}",key,i).as_bytes().to_vec())? {
+                    revisions.push((i, val.to_vec().try_into()?));
+                }
+            }
+        }
+        Ok(revisions)
+    }
+
+    // (key, revision, size in bytes) of every stored revision.
+    pub fn revision_iter(&self) -> impl Iterator<Item = (String,u64,usize)> {
+        self.0.db.scan_prefix(b"key_").filter_map(|x| {
+            let (key, value) = x.ok()?;
+            let key = String::from_utf8(key.to_vec()).ok()?;
+            let (key, rev) = key["key_".len()..].rsplit_once("_rev_")?;
+            Some((key.to_string(), rev.parse::<u64>().ok()?, value.len()))
+        })
+    }
+
     pub fn key_iter(&self) -> impl Iterator<Item = String> {
         let mut iter = self.0.db.scan_prefix(REV_INDEX_PREFIX.as_bytes());
         iter.filter_map(|x| {
@@ -255,33 +351,98 @@ pub struct CosmosRustBotStore {
     pub entry_store: EntryStore,
     pub index_store: IndexStore,
     pub subscription_store: SubscriptionStore,
+    // set after the first complete `update_items` pass, shared between clones.
+    ready: (Arc<watch::Sender<bool>>, watch::Receiver<bool>),
+    // built by the first semantic query, dropped by `update_items` once the embeddings change.
+    embedding_index: Arc<RwLock<Option<Arc<BruteForceIndex<Vec<u8>>>>>>,
+    // embeddings of semantic query texts by `stable_hash(text)`.
+    text_embeddings: Arc<Mutex<HashMap<u64, Vec<f32>>>>,
 }
 
 impl Clone for CosmosRustBotStore {
     fn clone(&self) -> Self {
         CosmosRustBotStore {
-            entry_store: EntryStore::new(&self.entry_store.0.db),
-            index_store: IndexStore::new(&self.index_store.0.db),
-            subscription_store: SubscriptionStore::new(&self.subscription_store.0.db),
+            entry_store: EntryStore(self.entry_store.0.share()),
+            index_store: IndexStore(self.index_store.0.share()),
+            subscription_store: SubscriptionStore(self.subscription_store.0.share()),
+            ready: self.ready.clone(),
+            embedding_index: self.embedding_index.clone(),
+            text_embeddings: self.text_embeddings.clone(),
         }
     }
 }
 
 impl CosmosRustBotStore {
 
-    pub fn new(entry_index_db: sled::Db, subscription_store: SubscriptionStore) -> Self {
-        CosmosRustBotStore {
-            entry_store: EntryStore::new(&entry_index_db),
-            index_store: IndexStore::new(&entry_index_db),
+    // the entry and index stores use the configuration of the subscription store.
+    // Entries with legacy keys and values stored with a previous layout are migrated first, see `migration::migrate_store_layout`.
+    pub fn new(entry_index_db: sled::Db, subscription_store: SubscriptionStore) -> anyhow::Result<Self> {
+        let (sender, receiver) = watch::channel(false);
+        let config = subscription_store.0.config.clone();
+        let store = CosmosRustBotStore {
+            entry_store: EntryStore(SledStore::with_config(entry_index_db.clone(), config.clone())),
+            index_store: IndexStore(SledStore::with_config(entry_index_db, config)),
             subscription_store,
+            ready: (Arc::new(sender), receiver),
+            embedding_index: Arc::new(RwLock::new(None)),
+            text_embeddings: Arc::new(Mutex::new(HashMap::new())),
+        };
+        migration::migrate_store_layout(&store)?;
+        Ok(store)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.subscription_store.0.config
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.1.borrow()
+    }
+
+    // resolves once the state is up-to-date, i.e. after the first complete `update_items` pass.
+    pub async fn wait_until_ready(&self) {
+        let mut receiver = self.ready.1.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
         }
     }
 
-    pub fn update_items(&mut self, mut items: Vec<CosmosRustBotValue>) {
+    // the vector index over all entry embeddings, only rebuilt after the embeddings changed.
+    pub fn embedding_index(&self) -> Arc<BruteForceIndex<Vec<u8>>> {
+        if let Some(index) = self.embedding_index.read().unwrap().as_ref() {
+            return index.clone();
+        }
+        let mut cached = self.embedding_index.write().unwrap();
+        let index = cached.get_or_insert_with(|| {
+            let mut index = BruteForceIndex::new();
+            for embedding in self.index_store.get_embeddings() {
+                index.insert(embedding.entry_key, embedding.vector);
+            }
+            Arc::new(index)
+        });
+        index.clone()
+    }
 
-        self.entry_store.remove_entries_not_in_items(&items); // outdated entries/indices
-        self.index_store.remove_indices_not_in_items(&items);
+    // Applies the new view as a diff: only entries/indices whose key or content changed are written,
+    // sorted indices are updated with the added and removed entries,
+    // and only subscriptions touched by an added or removed entry are re-evaluated.
+    pub fn update_items(&mut self, items: Vec<CosmosRustBotValue>) {
 
+        let item_keys = items
+            .iter()
+            .map(|x| x.key())
+            .collect::<HashSet<Vec<u8>>>();
+
+        let removed_entry_values = self.entry_store.remove_entries_not_in_items(&item_keys); // outdated entries/indices
+        let removed_entries = removed_entry_values.keys().cloned().collect::<HashSet<Vec<u8>>>();
+        self.index_store.remove_indices_not_in_items(&item_keys);
+        self.index_store.remove_search_indices_not_in_items(&item_keys);
+        let mut embeddings_changed = !self.index_store.remove_embeddings_not_in_items(&item_keys).is_empty();
+
+        let mut added_entries: HashSet<Vec<u8>> = HashSet::new();
+        let mut index_members: HashMap<String,HashSet<Vec<u8>>> = HashMap::new();
 
         //info!("Updating data..");
         for item in &items {
@@ -292,6 +453,7 @@ impl CosmosRustBotStore {
                     let value: Vec<u8> = item.clone().try_into().unwrap();
                     if let Ok(false) = self.entry_store.0.db.contains_key(&key) {
                         self.entry_store.0.db.insert(&key, value).ok();
+                        added_entries.insert(key);
 
                         if let Entry::Value(Value { timestamp: _, origin: _, custom_data: CustomData::ProposalData(proposal_data), imperative: _ }) = entry.clone() {
 
@@ -300,20 +462,20 @@ impl CosmosRustBotStore {
                             //info!("Updating data, writing to path: {}",&path);
 
                             // filesystem sync of generated files for proposal data
-                            let file_path = format!("./tmp/public/en/{}.json", &path);
+                            let file_path = format!("{}/en/{}.json", self.config().files.public_dir, &path);
                             match std::fs::write(&file_path, serde_json::to_string_pretty(&proposal_data.generate_map()).unwrap()) {
                                 Ok(_) => {},
                                 Err(err) => { error!("Unable to write {}, Error: {}", &file_path,err.to_string()); },
                             };
 
                             // write governance proposal as HTML page
-                            let file_path = format!("./tmp/governance_proposals/{}.html", &path);
+                            let file_path = format!("{}/{}.html", self.config().files.governance_proposals_dir, &path);
                             match std::fs::write(&file_path, proposal_data.generate_html()) {
                                 Ok(_) => {},
                                 Err(err) => { error!("Unable to write {}, Error: {}", &file_path,err.to_string()); },
                             };
                             // write the fraud prediction to a JSON
-                            let file_path = format!("./tmp/fraud_detection/{}.json", &path);
+                            let file_path = format!("{}/{}.json", self.config().files.fraud_detection_dir, &path);
                             let json_string = json!({"title": proposal_data.proposal_title, "description": proposal_data.proposal_description, "fraud_prediction": proposal_data.fraud_risk}).to_string();
                             match std::fs::write(&file_path, json_string) {
                                 Ok(_) => {},
@@ -322,24 +484,49 @@ impl CosmosRustBotStore {
                         }
                     }
                 },
-                CosmosRustBotValue::Index(_) => {
+                CosmosRustBotValue::Index(index) => {
+                    index_members.insert(index.name.to_owned(), index.list.iter().cloned().collect());
                     let key =item.key();
                     let value: Vec<u8> = item.clone().try_into().unwrap();
+                    // the key only depends on the name.
+                    if !matches!(self.index_store.0.db.get(&key), Ok(Some(stored)) if stored.as_ref() == &value[..]) {
+                        self.index_store.0.db.insert(&key, value).ok();
+                    }
+                }
+                CosmosRustBotValue::SearchIndex(_) | CosmosRustBotValue::Embedding(_) => {
+                    let key =item.key();
                     if let Ok(false) = self.index_store.0.db.contains_key(&key) {
+                        let value: Vec<u8> = item.clone().try_into().unwrap();
                         self.index_store.0.db.insert(&key, value).ok();
+                        embeddings_changed |= matches!(item, CosmosRustBotValue::Embedding(_));
                     }
                 }
                 _ => {}
             };
         }
 
-        self.set_action_param_for_outdated_subscriptions();
+        if embeddings_changed {
+            *self.embedding_index.write().unwrap() = None;
+        }
+
+        let sorted_indices = items.iter().filter_map(|x| if let CosmosRustBotValue::SortedIndex(index) = x { Some(index) } else { None }).collect::<Vec<&SortedIndex>>();
+        let added_entry_values = items.iter().filter(|x| added_entries.contains(&x.key())).collect::<Vec<&CosmosRustBotValue>>();
+        index_members.extend(self.index_store.update_sorted_indices(&sorted_indices, &items, &removed_entry_values, &added_entry_values));
+
+        self.set_action_param_for_outdated_subscriptions(&removed_entries, &added_entries, &index_members);
 
+        if !self.is_ready() {
+            info!("Initial update completed, subscriptions are up-to-date.");
+            self.ready.0.send(true).ok();
+        }
     }
 
-    fn set_action_param_for_outdated_subscriptions(&mut self) {
+    fn set_action_param_for_outdated_subscriptions(&mut self, removed_entries: &HashSet<Vec<u8>>, added_entries: &HashSet<Vec<u8>>, index_members: &HashMap<String,HashSet<Vec<u8>>>) {
         // refreshing subscriptions by updating them if their content changed.
         for mut subscription in self.subscription_store.get_subscriptions() {
+            if !Self::is_touched_by_changes(&subscription, removed_entries, added_entries, index_members) {
+                continue;
+            }
             if self.check_and_update_outdated_subscription(&mut subscription){
                 subscription.action = SubscriptionAction::Update;
                 let item = CosmosRustBotValue::Subscription(subscription);
@@ -350,6 +537,28 @@ impl CosmosRustBotStore {
         }
     }
 
+    // a subscription can only change if one of its entries was removed,
+    // or if an added entry is a member of one of the indices it selects from.
+    // Subscriptions with a time-relative filter ("within:") are re-evaluated on every update.
+    fn is_touched_by_changes(subscription: &Subscription, removed_entries: &HashSet<Vec<u8>>, added_entries: &HashSet<Vec<u8>>, index_members: &HashMap<String,HashSet<Vec<u8>>>) -> bool {
+        if subscription.list.iter().any(|x| removed_entries.contains(x)) {
+            return true;
+        }
+        if let QueryPart::EntriesQueryPart(query_part) = &subscription.query {
+            if query_part.filter.iter().flatten().any(|(_, value)| filter::is_time_relative(value)) {
+                return true;
+            }
+            // any added entry may match the search terms or be similar.
+            if (query_part.search.is_some() || query_part.semantic.is_some()) && !added_entries.is_empty() {
+                return true;
+            }
+            return query_part.indices.iter().any(|name| {
+                index_members.get(name).map(|members| added_entries.iter().any(|x| members.contains(x))).unwrap_or(false)
+            });
+        }
+        false
+    }
+
     fn check_and_update_outdated_subscription(&mut self, subscription: &mut Subscription) -> bool {
         // if the query result changed update the subscription
         if let QueryPart::EntriesQueryPart(query_part) = &subscription.query {
@@ -359,10 +568,12 @@ impl CosmosRustBotStore {
             let mut removed_items = false;
 
             let selected_keys = query_result.iter().map(|x| x.key()).collect::<Vec<Vec<u8>>>();
+            let selected_key_set = selected_keys.iter().cloned().collect::<HashSet<Vec<u8>>>();
 
             // remove outdated keys
             let len = subscription.list.len();
-            subscription.list.retain(|x| selected_keys.contains(x));
+            subscription.list.retain(|x| selected_key_set.contains(x));
+            let existing_keys = subscription.list.iter().cloned().collect::<HashSet<Vec<u8>>>();
             if len != subscription.list.len() {
                 removed_items = true;
                 info!("Removed outdated keys from subscription: {}",&subscription.query);
@@ -370,7 +581,7 @@ impl CosmosRustBotStore {
 
             // add new keys
             for e in selected_keys {
-                if !subscription.list.contains(&e) {
+                if !existing_keys.contains(&e) {
                     added_items = true;
                     subscription.list.push(e);
                     info!("Added new key to subscription: {}",&subscription.query);
@@ -388,44 +599,121 @@ impl CosmosRustBotStore {
         return false;
     }
 
+    // Sends the notification for an updated subscription, unless its current state has already been notified.
+    // The notified state is persisted, so a restart does not drop a notification. The notification carries a
+    // delivery key derived from the subscription and its state, the notification service ignores it if it was
+    // already handed off before a restart.
+    // Quotas are only counted once the notification was handed off.
+    // Returns false if the notification service could not be reached.
+    fn notify_subscription(&self, s: Subscription) -> bool {
+        let key = s.get_key();
+        let state = stable_hash(&s.list);
+        if self.subscription_store.get_notified_state(&key) == Some(state) {
+            return true;
+        }
+        let query: UserQuery = UserQuery::new(s.query);
+
+        let mut entries = CosmosRustBotStoreInquirer(self).execute(&query);
+        entries.retain(|x| {
+            if let CosmosRustBotValue::Entry(Entry::Value(v)) = x {
+                v.imperative == ValueImperative::Notify
+            } else {
+                true
+            }
+        });
+        if !entries.is_empty() {
+            // users over their notification quota are skipped, and told so once a day.
+            let mut user_list = HashSet::new();
+            for user_hash in s.user_list {
+                // e.g. an admin subscription of a user that is no longer an admin.
+                let role = access::assigned_role(&self.subscription_store, user_hash);
+                if !entries.iter().all(|x| access::may_view(role, x)) {
+                    continue;
+                }
+                match quota::check_notification(&self.subscription_store, user_hash) {
+                    Ok(()) => { user_list.insert(user_hash); },
+                    Err(exceeded) => {
+                        if quota::report_exceeded_once(&self.subscription_store, user_hash) {
+                            let notification = Notification {
+                                query: query.clone(),
+                                entries: vec![CosmosRustBotValue::QuotaExceeded(exceeded)],
+                                user_list: HashSet::from([user_hash]),
+                                schedules: HashMap::new(),
+                                delivery_key: None,
+                            };
+                            client_send_notification_request(&self.config().sockets.notification, CosmosRustServerValue::Notification(notification)).ok();
+                        }
+                    },
+                }
+            }
+            // nobody left to notify, the delivery key is not spent.
+            if !user_list.is_empty() {
+                let notification = Notification {
+                    query: query.clone(),
+                    entries: entries.clone(),
+                    user_list: user_list.clone(),
+                    schedules: s.schedules,
+                    delivery_key: Some(stable_hash(&(&key, state))),
+                };
+                if let Err(err) = client_send_notification_request(
+                    &self.config().sockets.notification,
+                    CosmosRustServerValue::Notification(notification),
+                ) {
+                    // not marked as notified, retried by `notify_pending_subscriptions`.
+                    error!("Failed to send notification: {}", err.to_string());
+                    return false;
+                }
+                for user_hash in &user_list {
+                    quota::count_notification(&self.subscription_store, *user_hash).ok();
+                }
+                webhook::enqueue(&self.subscription_store, &user_list, &query, &entries);
+            }
+        }
+        self.subscription_store.set_notified_state(&key, state);
+        true
+    }
+
+    // updated subscriptions whose current state has not been notified yet.
+    fn notify_pending_subscriptions(&self) -> bool {
+        let mut all_sent = true;
+        for s in self.subscription_store.get_subscriptions().collect::<Vec<Subscription>>() {
+            if s.action == SubscriptionAction::Update {
+                all_sent &= self.notify_subscription(s);
+            }
+        }
+        all_sent
+    }
+
     pub fn spawn_notify_on_subscription_update_task(&mut self) -> cosmos_rust_package::tokio::task::JoinHandle<()> {
         let mut copy_self = self.clone();
         cosmos_rust_package::tokio::spawn(async move {
 
-            // Delay the task for 5 minutes
-            // Reason: This delay allows the state to get up-to-date first.
-            // TODO: find a better solution
-            sleep(Duration::from_secs(5*60)).await;
-
+            // registered right away, updates made while waiting are delivered afterwards.
             copy_self.subscription_store.register_subscriber().unwrap();
 
-            while let Some(updated) = copy_self.subscription_store.get_next_updated() {
-
-                if let Ok(CosmosRustBotValue::Subscription(s)) = updated {
-                    if s.action == SubscriptionAction::Update  {  // only subscriptions that have been marked for update
-
-                        let query: UserQuery = UserQuery::new(s.query);
-
-                        let mut entries = CosmosRustBotStoreInquirer(&copy_self).query(&query);
-                        entries.retain(|x| {
-                            if let CosmosRustBotValue::Entry(Entry::Value(v)) = x {
-                                v.imperative == ValueImperative::Notify
-                            } else {
-                                true
+            // notifications are only sent once the state is up-to-date.
+            copy_self.wait_until_ready().await;
+
+            // subscriptions updated before a restart that have not been notified yet.
+            copy_self.subscription_store.remove_outdated_notified_states();
+            let mut failed_attempts: u32 = if copy_self.notify_pending_subscriptions() { 0 } else { 1 };
+
+            loop {
+                // while the notification service is unreachable, pending subscriptions are retried with a backoff.
+                let timeout = if failed_attempts > 0 { Duration::from_secs(retry_delay(failed_attempts) as u64) } else { Duration::from_secs(60 * 60) };
+                match copy_self.subscription_store.get_next_updated_timeout(timeout) {
+                    Ok(Some(Ok(CosmosRustBotValue::Subscription(s)))) => {
+                        if s.action == SubscriptionAction::Update  {  // only subscriptions that have been marked for update
+                            if !copy_self.notify_subscription(s) && failed_attempts == 0 {
+                                failed_attempts = 1;
                             }
-                        });
-                        if !entries.is_empty() {
-                            let notification = Notification {
-                                query,
-                                entries,
-                                user_list: s.user_list,
-                            };
-                            client_send_notification_request(
-                                NOTIFICATION_SOCKET,
-                                CosmosRustServerValue::Notification(notification),
-                            ).ok();
                         }
-                    }
+                    },
+                    Ok(Some(_)) => {},
+                    Ok(None) => { break; },
+                    Err(_timeout) => {
+                        failed_attempts = if copy_self.notify_pending_subscriptions() { 0 } else { failed_attempts.saturating_add(1) };
+                    },
                 }
             }
         })
@@ -433,6 +721,24 @@ impl CosmosRustBotStore {
 }
 
 
+// returns the removed keys with their previous value.
+fn remove_keys_not_in_items(db: &sled::Db, prefix: Vec<u8>, item_keys: &HashSet<Vec<u8>>) -> HashMap<Vec<u8>,IVec> {
+    let outdated = db.scan_prefix(prefix)
+        .keys()
+        .filter_map(|x| x.ok())
+        .map(|x| x.to_vec())
+        .filter(|x| !item_keys.contains(x))
+        .collect::<HashSet<Vec<u8>>>();
+    outdated.into_iter()
+        .filter_map(|key| match db.remove(&key) {
+            Ok(Some(value)) => Some((key, value)),
+            _ => None,
+        })
+        .collect()
+}
+
+const SORTED_INDEX_TREE: &str = "sorted_index";
+
 pub struct IndexStore(SledStore);
 
 impl IndexStore {
@@ -442,6 +748,114 @@ impl IndexStore {
         IndexStore(sled_store)
     }
 
+    pub fn get_index(&self, name: &str) -> Option<Index> {
+        match self.0.db.get(Index::get_key_for_name(name)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+            Some(Ok(CosmosRustBotValue::Index(index))) => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn get_sorted_index(&self, name: &str) -> Option<SortedIndex> {
+        match self.0.db.get(SortedIndex::get_key_for_name(name)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+            Some(Ok(CosmosRustBotValue::SortedIndex(index))) => Some(index),
+            _ => None,
+        }
+    }
+
+    fn get_sorted_indices(&self) -> Vec<SortedIndex> {
+        self.0.db.scan_prefix(SortedIndex::get_prefix()).values()
+            .filter_map(|x| x.ok())
+            .filter_map(|v| match CosmosRustBotValue::try_from(v.to_vec()) {
+                Ok(CosmosRustBotValue::SortedIndex(index)) => Some(index),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn sorted_tree(&self) -> sled::Tree {
+        self.0.db.open_tree(SORTED_INDEX_TREE).unwrap()
+    }
+
+    // key in the sorted index tree: name hash, encoded value, entry key.
+    fn sorted_key(name: &str, entry: &CosmosRustBotValue, field: &str) -> Option<Vec<u8>> {
+        let mut k: Vec<u8> = stable_hash(name).to_be_bytes().to_vec();
+        k.append(&mut SortedIndex::encode_value(&entry.get(field))?);
+        k.append(&mut entry.key());
+        Some(k)
+    }
+
+    // Adds new sorted indices (built from all entries), drops the ones no longer defined and
+    // applies the removed/added entries to the others.
+    // Returns the added entries contained in each sorted index.
+    fn update_sorted_indices(&mut self, definitions: &Vec<&SortedIndex>, items: &Vec<CosmosRustBotValue>, removed_entries: &HashMap<Vec<u8>,IVec>, added_entries: &Vec<&CosmosRustBotValue>) -> HashMap<String,HashSet<Vec<u8>>> {
+        let tree = self.sorted_tree();
+        let stored = self.get_sorted_indices();
+
+        for index in stored.iter().filter(|x| !definitions.contains(x)) {
+            for key in tree.scan_prefix(stable_hash(index.name.as_str()).to_be_bytes()).keys().filter_map(|x| x.ok()) {
+                tree.remove(key).ok();
+            }
+            self.0.db.remove(index.get_key()).ok();
+        }
+
+        let removed_entries = removed_entries.values().filter_map(|v| CosmosRustBotValue::try_from(v.to_vec()).ok()).collect::<Vec<CosmosRustBotValue>>();
+        let mut index_members: HashMap<String,HashSet<Vec<u8>>> = HashMap::new();
+
+        for index in definitions {
+            let entries: Vec<&CosmosRustBotValue> = if stored.contains(*index) {
+                for entry in &removed_entries {
+                    if let Some(key) = Self::sorted_key(&index.name, entry, &index.field) {
+                        tree.remove(key).ok();
+                    }
+                }
+                added_entries.clone()
+            } else {
+                let value: Vec<u8> = CosmosRustBotValue::SortedIndex((*index).clone()).try_into().unwrap();
+                self.0.db.insert(index.get_key(), value).ok();
+                items.iter().filter(|x| matches!(x, CosmosRustBotValue::Entry(_))).collect()
+            };
+            let members = index_members.entry(index.name.to_owned()).or_insert_with(HashSet::new);
+            for entry in entries {
+                if let Some(key) = Self::sorted_key(&index.name, entry, &index.field) {
+                    tree.insert(key, entry.key()).ok();
+                    members.insert(entry.key());
+                }
+            }
+        }
+        index_members
+    }
+
+    /// Entry keys of the sorted index in the given direction, None if there is no such index.
+    pub fn get_sorted(&self, name: &str, direction: &OrderDirection) -> Option<Vec<Vec<u8>>> {
+        self.get_sorted_index(name)?;
+        let list = self.sorted_tree().scan_prefix(stable_hash(name).to_be_bytes()).values()
+            .filter_map(|x| x.ok())
+            .map(|x| x.to_vec());
+        Some(match direction {
+            OrderDirection::Asc => list.collect(),
+            OrderDirection::Desc => list.rev().collect(),
+        })
+    }
+
+    /// Entry keys with `from <= value <= to` in ascending order, either bound may be open.
+    pub fn get_sorted_range(&self, name: &str, from: Option<&serde_json::Value>, to: Option<&serde_json::Value>) -> Vec<Vec<u8>> {
+        let prefix = stable_hash(name).to_be_bytes().to_vec();
+        let mut start = prefix.clone();
+        if let Some(value) = from.and_then(|x| SortedIndex::encode_value(x)) {
+            start.extend(value);
+        }
+        let mut end = prefix.clone();
+        match to.and_then(|x| SortedIndex::encode_value(x)) {
+            // entry keys never start with 0xff.
+            Some(value) => { end.extend(value); end.push(0xff); },
+            None => { end.push(0xff); },
+        }
+        self.sorted_tree().range(start..end).values()
+            .filter_map(|x| x.ok())
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
     pub fn get_indices(&self) -> impl Iterator<Item = CosmosRustBotValue> {
         self.0.db.scan_prefix(Index::get_prefix()).filter_map(|item| match item {
             Ok((_k, v)) => {
@@ -459,21 +873,61 @@ impl IndexStore {
         })
     }
 
-    pub fn remove_indices_not_in_items(&mut self, items: &Vec<CosmosRustBotValue>) {
+    // returns the removed keys.
+    pub fn remove_indices_not_in_items(&mut self, item_keys: &HashSet<Vec<u8>>) -> HashSet<Vec<u8>> {
+        remove_keys_not_in_items(&self.0.db, Index::get_prefix(), item_keys).into_keys().collect()
+    }
 
-        let item_keys = items
-            .iter()
-            .map(|x| x.key())
-            .collect::<Vec<Vec<u8>>>();
+    pub fn remove_search_indices_not_in_items(&mut self, item_keys: &HashSet<Vec<u8>>) -> HashSet<Vec<u8>> {
+        remove_keys_not_in_items(&self.0.db, SearchIndex::get_prefix(), item_keys).into_keys().collect()
+    }
 
-        for index in self.get_indices() {
-            let key = index.key();
-            if !item_keys.contains(&key) {
-                self.0.db.remove(key).ok();
-            }
+    fn get_search_index(&self, term: &str) -> Option<SearchIndex> {
+        // there is only one key per term, the hash of the content is part of the key.
+        self.0.db.scan_prefix(SearchIndex::get_prefix_for_term(term)).values()
+            .filter_map(|x| x.ok())
+            .find_map(|v| match CosmosRustBotValue::try_from(v.to_vec()) {
+                Ok(CosmosRustBotValue::SearchIndex(search_index)) => Some(search_index),
+                _ => None,
+            })
+    }
+
+    pub fn get_search_term_postings(&self, term: &str) -> Option<Vec<(Vec<u8>, u32)>> {
+        match self.get_search_index(term) {
+            Some(SearchIndex::Term { postings, .. }) => Some(postings),
+            _ => None,
+        }
+    }
+
+    pub fn get_search_document_lengths(&self) -> Vec<(Vec<u8>, u32)> {
+        match self.get_search_index("") {
+            Some(SearchIndex::Documents { lengths }) => lengths,
+            _ => Vec::new(),
         }
     }
 
+    pub fn remove_embeddings_not_in_items(&mut self, item_keys: &HashSet<Vec<u8>>) -> HashSet<Vec<u8>> {
+        remove_keys_not_in_items(&self.0.db, Embedding::get_prefix(), item_keys).into_keys().collect()
+    }
+
+    pub fn get_embeddings(&self) -> impl Iterator<Item = Embedding> {
+        self.0.db.scan_prefix(Embedding::get_prefix()).values()
+            .filter_map(|x| x.ok())
+            .filter_map(|v| match CosmosRustBotValue::try_from(v.to_vec()) {
+                Ok(CosmosRustBotValue::Embedding(embedding)) => Some(embedding),
+                _ => None,
+            })
+    }
+
+    pub fn get_embedding(&self, entry_key: &[u8]) -> Option<Embedding> {
+        self.0.db.scan_prefix(Embedding::get_prefix_for_entry(entry_key)).values()
+            .filter_map(|x| x.ok())
+            .find_map(|v| match CosmosRustBotValue::try_from(v.to_vec()) {
+                Ok(CosmosRustBotValue::Embedding(embedding)) => Some(embedding),
+                _ => None,
+            })
+    }
+
     pub fn register_subscriber(&mut self) -> anyhow::Result<()> {
         self.0.set_subscriber()?;
         self.0.subscriber = Some(self.0.db.watch_prefix(Index::get_prefix()));
@@ -507,19 +961,9 @@ impl EntryStore {
         })
     }
 
-    pub fn remove_entries_not_in_items(&mut self, items: &Vec<CosmosRustBotValue>) {
-
-        let item_keys = items
-            .iter()
-            .map(|x| x.key())
-            .collect::<Vec<Vec<u8>>>();
-
-        for entry in self.get_entries() {
-            let key = entry.key();
-            if !item_keys.contains(&key) {
-                self.0.db.remove(key).ok();
-            }
-        }
+    // returns the removed entries, their values are needed to update the sorted indices.
+    pub fn remove_entries_not_in_items(&mut self, item_keys: &HashSet<Vec<u8>>) -> HashMap<Vec<u8>,IVec> {
+        remove_keys_not_in_items(&self.0.db, Entry::get_prefix(), item_keys)
     }
 
     pub fn register_subscriber(&mut self) -> anyhow::Result<()> {
@@ -538,6 +982,14 @@ impl SubscriptionStore {
         SubscriptionStore(sled_store)
     }
 
+    pub fn with_config(tree: &sled::Db, config: Arc<Config>) -> Self {
+        SubscriptionStore(SledStore::with_config(tree.clone(), config))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.0.config
+    }
+
     pub fn get_subscriptions(&self) -> impl Iterator<Item = Subscription> {
         self.0.db.scan_prefix(Subscription::get_prefix()).filter_map(|item| match item {
             Ok((_k, v)) => {
@@ -610,19 +1062,57 @@ impl SubscriptionStore {
         }
     }
 
+    fn notified_tree(&self) -> sled::Tree {
+        self.0.db.open_tree(NOTIFIED_STATE_TREE).unwrap()
+    }
+
+    // hash of the entry list of the subscription when it was last notified.
+    pub fn get_notified_state(&self, subscription_key: &[u8]) -> Option<u64> {
+        let value = self.notified_tree().get(subscription_key).ok()??;
+        Some(u64::from_be_bytes(value.as_ref().try_into().ok()?))
+    }
+
+    pub fn set_notified_state(&self, subscription_key: &[u8], state: u64) {
+        self.notified_tree().insert(subscription_key, state.to_be_bytes().to_vec()).ok();
+    }
+
+    // drops the state of subscriptions that no longer exist.
+    pub fn remove_outdated_notified_states(&self) {
+        let tree = self.notified_tree();
+        for key in tree.iter().keys().filter_map(|x| x.ok()) {
+            if let Ok(false) = self.0.db.contains_key(&key) {
+                tree.remove(key).ok();
+            }
+        }
+    }
+
     pub fn get_next_updated(&mut self) -> Option<anyhow::Result<CosmosRustBotValue>> {
-        match self.0.await_next_update() {
+        let event = self.0.await_next_update();
+        self.decode_event(event)
+    }
+
+    // like `get_next_updated`, but returns an error if there was no update within the timeout.
+    pub fn get_next_updated_timeout(&mut self, timeout: Duration) -> Result<Option<anyhow::Result<CosmosRustBotValue>>, RecvTimeoutError> {
+        match self.0.await_next_update_timeout(timeout) {
+            Ok(event) => Ok(self.decode_event(Some(event))),
+            Err(RecvTimeoutError::Disconnected) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn decode_event(&self, event: Option<sled::Event>) -> Option<anyhow::Result<CosmosRustBotValue>> {
+        match event {
             Some(sled::Event::Remove { key }) => {
                 Some(Err(anyhow::anyhow!("Error: Remove Event.")))
             },
             Some(sled::Event::Insert { key, value }) => {
                 match value.to_vec().try_into().unwrap() {
                     CosmosRustBotValue::Subscription(s) => {
-                        self.export_subscriptions(CRB_SUBSCRIPTION_STORE_JSON);
+                        self.export_subscriptions(&self.0.config.files.subscriptions_json);
                         Some(Ok(CosmosRustBotValue::Subscription(s)))
                     },
                     CosmosRustBotValue::Registration(r) => {
-                        self.export_subscriptions(CRB_REGISTRATION_STORE_JSON);
+                        self.export_subscriptions(&self.0.config.files.registrations_json);
                         Some(Ok(CosmosRustBotValue::Registration(r)))
                     },
                     _ => {
@@ -644,27 +1134,38 @@ impl SubscriptionStore {
 pub struct SledStore {
     db: sled::Db,
     subscriber: Option<sled::Subscriber>,
+    config: Arc<Config>,
 }
 
 impl SledStore {
-    pub fn open(path: &str) -> anyhow::Result<Self> {
-        let db: sled::Db = load_sled_db(path);
-        Ok(SledStore::new(db))
+    pub fn open(path: &str, config: Arc<Config>) -> anyhow::Result<Self> {
+        let db: sled::Db = load_sled_db_with_config(path, &config);
+        Ok(SledStore::with_config(db, config))
     }
     pub fn new(sled_db: sled::Db) -> Self {
+        SledStore::with_config(sled_db, Config::shared())
+    }
+    pub fn with_config(sled_db: sled::Db, config: Arc<Config>) -> Self {
         SledStore {
             db: sled_db,
             subscriber: None,
+            config,
         }
     }
 
-    pub fn temporary() -> anyhow::Result<Self> {
+    pub fn temporary(config: Arc<Config>) -> anyhow::Result<Self> {
         Ok(Self {
             db: sled::Config::new().temporary(true).cache_capacity(1024 * 1024 * 1024 * 2 /*2GB*/).open()?,
             subscriber: None,
+            config,
         })
     }
 
+    // the same db and configuration, without the subscriber.
+    fn share(&self) -> Self {
+        SledStore::with_config(self.db.clone(), self.config.clone())
+    }
+
 
     pub fn get_tree(&self) -> &sled::Db {
         &self.db
@@ -728,4 +1229,11 @@ impl SledStore {
     fn await_next_update(&mut self) -> Option<sled::Event> {
         self.subscriber.as_mut().map(|s| s.next()).flatten()
     }
+
+    fn await_next_update_timeout(&mut self, timeout: Duration) -> Result<sled::Event, RecvTimeoutError> {
+        match self.subscriber.as_mut() {
+            Some(s) => s.next_timeout(timeout),
+            None => Err(RecvTimeoutError::Disconnected),
+        }
+    }
 }
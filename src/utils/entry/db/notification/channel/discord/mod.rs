@@ -0,0 +1,109 @@
+use super::ChannelMessage;
+
+use serde::{Serialize,Deserialize};
+
+// Discord delivery through a channel webhook, one embed per message part.
+
+// limits of the Discord API.
+const MAX_EMBEDS: usize = 10;
+const MAX_FIELDS: usize = 25;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_FIELD_VALUE_LENGTH: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DiscordConfig {
+    // "https://discord.com/api/webhooks/{id}/{token}"
+    pub webhook_url: String,
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max_chars - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+/// Webhook payloads, Discord accepts at most `MAX_EMBEDS` embeds per message.
+pub fn render(message: &ChannelMessage) -> Vec<serde_json::Value> {
+    let embeds = message.parts.iter().map(|part| {
+        let fields = part.links.iter()
+            .map(|link| serde_json::json!({
+                "name": truncate(&link.label, 256),
+                "value": truncate(&format!("[Open]({})", link.url), MAX_FIELD_VALUE_LENGTH),
+                "inline": true,
+            }))
+            .chain(part.actions.iter().map(|action| serde_json::json!({
+                "name": truncate(&action.label, 256),
+                "value": truncate(&format!("`{}`", action.command), MAX_FIELD_VALUE_LENGTH),
+                "inline": true,
+            })))
+            .take(MAX_FIELDS)
+            .collect::<Vec<serde_json::Value>>();
+        serde_json::json!({
+            "description": truncate(&part.text, MAX_DESCRIPTION_LENGTH),
+            "fields": fields,
+        })
+    }).collect::<Vec<serde_json::Value>>();
+    embeds.chunks(MAX_EMBEDS).map(|chunk| serde_json::json!({ "embeds": chunk })).collect()
+}
+
+pub async fn send(client: &reqwest::Client, config: &DiscordConfig, message: &ChannelMessage) -> anyhow::Result<()> {
+    for payload in render(message) {
+        let response = client.post(&config.webhook_url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Error: Discord responded with status {}", response.status()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{render, send, DiscordConfig};
+    use crate::utils::entry::db::notification::channel::{Action, ChannelMessage, Link, MessagePart};
+    use crate::utils::entry::db::notification::stub::{body, request_line, spawn_stub};
+
+    fn message(parts: usize) -> ChannelMessage {
+        ChannelMessage {
+            parts: (0..parts).map(|i| MessagePart {
+                text: format!("Proposal {}", i),
+                links: vec![Link { label: "Open in Browser".to_string(), url: "https://example.com".to_string() }],
+                actions: vec![Action { label: "Unsubscribe".to_string(), command: "gov prpsl all unsubscribe".to_string() }],
+            }).collect(),
+        }
+    }
+
+    #[test]
+    pub fn render_embeds() {
+        let payloads = render(&message(12));
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0]["embeds"].as_array().unwrap().len(), 10);
+        assert_eq!(payloads[1]["embeds"][0]["description"], "Proposal 10");
+        assert_eq!(payloads[0]["embeds"][0]["fields"][0]["value"], "[Open](https://example.com)");
+        assert_eq!(payloads[0]["embeds"][0]["fields"][1]["value"], "`gov prpsl all unsubscribe`");
+    }
+
+    #[test]
+    pub fn send_to_mock_server() {
+        let runtime = cosmos_rust_package::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (url, stub) = spawn_stub("204 No Content", "");
+        let config = DiscordConfig { webhook_url: format!("{}/api/webhooks/1/token", url) };
+        assert!(runtime.block_on(send(&reqwest::Client::new(), &config, &message(1))).is_ok());
+
+        let request = stub.join().unwrap();
+        assert_eq!(request_line(&request), "POST /api/webhooks/1/token HTTP/1.1");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body(&request)).unwrap(), render(&message(1))[0]);
+
+        let (url, stub) = spawn_stub("429 Too Many Requests", "");
+        let config = DiscordConfig { webhook_url: format!("{}/api/webhooks/1/token", url) };
+        assert!(runtime.block_on(send(&reqwest::Client::new(), &config, &message(1))).is_err());
+        stub.join().unwrap();
+    }
+}
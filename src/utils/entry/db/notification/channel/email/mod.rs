@@ -0,0 +1,575 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::SubscriptionStore;
+use crate::utils::escape_html;
+use crate::utils::hash::{stable_hash, to_hex};
+use super::ChannelMessage;
+use cosmos_rust_package::chrono::Utc;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use log::{info, error};
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::extension::ClientId;
+use lettre::{Address, SmtpTransport, Transport};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::sync::Arc;
+
+use serde::{Serialize,Deserialize};
+
+use crate::utils::entry::db::notification::digest::next_delivery;
+
+// Email delivery via SMTP.
+//
+// Subscription updates of users with an email channel are not sent one by one, the proposals are
+// collected and sent as a single summary (weekly by default, see `EmailConfig::schedule`).
+// Every subscription in the summary has an unsubscribe link, `unsubscribe_query` turns a signed link
+// back into the `UserQuery` that unsubscribes the user (`GET /api/v1/unsubscribe` of the HTTP gateway).
+// Addresses are a single addr-spec ("user@example.com"), see `is_valid_address`.
+//
+// Messages are sent with STARTTLS (default) or implicit TLS (usually port 465), see `SmtpSecurity`.
+// Without TLS credentials are only sent to a loopback host (e.g. a local postfix relay) unless `allow_insecure_auth` is set.
+
+const EMAIL_DIGEST_TREE: &str = "email_digest";
+const EMAIL_CHECK_INTERVAL_SECONDS: u64 = 60;
+const SMTP_TIMEOUT_SECONDS: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum SmtpSecurity {
+    StartTls,
+    Tls,
+    None,
+}
+
+impl Default for SmtpSecurity {
+    fn default() -> Self {
+        SmtpSecurity::StartTls
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // e.g. "CosmosRustBot <bot@example.com>"
+    pub from: String,
+    // e.g. "https://example.com/api/v1/unsubscribe", or a page that forwards `user`, `subscription` and `token` to it.
+    pub unsubscribe_url: String,
+    pub unsubscribe_secret: String,
+    #[serde(default)]
+    pub allow_insecure_auth: bool,
+}
+
+impl SmtpConfig {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EmailConfig {
+    pub address: String,
+    pub schedule: DeliverySchedule,
+}
+
+impl EmailConfig {
+    /// Weekly summary, Monday 08:00 UTC.
+    pub fn new(address: &str) -> Self {
+        EmailConfig {
+            address: address.to_string(),
+            schedule: DeliverySchedule { mode: DeliveryMode::WeeklyDigest { weekday: 0, hour: 8, minute: 0 }, ..DeliverySchedule::default() },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EmailDigestItem {
+    pub subscription: EntriesQueryPart,
+    pub proposal: ProposalData,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EmailDigest {
+    pub user_hash: u64,
+    pub deliver_at: i64,
+    pub items: Vec<EmailDigestItem>,
+}
+
+impl TryFrom<Vec<u8>> for EmailDigest {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<EmailDigest> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: EmailDigest) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EmailMessage {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+fn sign(secret: &str, user_hash: u64, subscription: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(format!("{}.{}", user_hash, subscription).as_bytes());
+    to_hex(hmac.result().code())
+}
+
+pub fn unsubscribe_link(smtp: &SmtpConfig, user_hash: u64, subscription: &EntriesQueryPart) -> String {
+    let subscription = to_hex(&Subscription::get_key_for_entries_query(subscription));
+    format!("{}?user={}&subscription={}&token={}", smtp.unsubscribe_url, user_hash, subscription, sign(&smtp.unsubscribe_secret, user_hash, &subscription))
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// The query that unsubscribes the user, None if the token does not match or the user is not subscribed.
+pub fn unsubscribe_query(store: &SubscriptionStore, smtp: &SmtpConfig, user_hash: u64, subscription: &str, token: &str) -> Option<UserQuery> {
+    if !fixed_time_eq(sign(&smtp.unsubscribe_secret, user_hash, subscription).as_bytes(), token.as_bytes()) {
+        return None;
+    }
+    let key = from_hex(subscription).filter(|key| key.starts_with(&Subscription::get_prefix()))?;
+    let query_part = match store.0.db.get(key).ok()?.map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::Subscription(subscription))) if subscription.contains_user_hash(user_hash) => subscription.query,
+        _ => { return None; }
+    };
+    Some(UserQuery {
+        query_part,
+        settings_part: SettingsPart { unsubscribe: Some(true), user_hash: Some(user_hash), ..SettingsPart::default() },
+    })
+}
+
+/// Summary of the proposals, grouped by subscription.
+pub fn render_digest(smtp: &SmtpConfig, user_hash: u64, items: &[EmailDigestItem]) -> EmailMessage {
+    let mut subscriptions: Vec<&EntriesQueryPart> = Vec::new();
+    for item in items {
+        if !subscriptions.contains(&&item.subscription) {
+            subscriptions.push(&item.subscription);
+        }
+    }
+    let subject = format!("CosmosRustBot: {} proposal update{}", items.len(), if items.len() == 1 { "" } else { "s" });
+    let mut text = format!("{}\n", subject);
+    let mut html = format!("<html><body><h2>{}</h2>", escape_html(&subject));
+    for subscription in subscriptions {
+        let unsubscribe = unsubscribe_link(smtp, user_hash, subscription);
+        text.push_str(&format!("\n/{}\n", subscription.message.replace(' ', "_")));
+        html.push_str(&format!("<h3>/{}</h3>", escape_html(&subscription.message.replace(' ', "_"))));
+        for item in items.iter().filter(|x| &x.subscription == subscription) {
+            let map = item.proposal.generate_map();
+            let field = |key: &str| map.get(key).cloned().unwrap_or_default();
+            text.push_str(&format!("\n#{} {}: {}\n{}\n{}\n{}\n{}\n",
+                                   field("proposal_id"), field("proposal_blockchain"), field("proposal_title"),
+                                   field("proposal_state"), field("proposal_submitted"), field("proposal_summary"),
+                                   item.proposal.proposal_api));
+            html.push_str(&format!("<p><a href=\"{}\"><b>#{} {}: {}</b></a><br>{}<br>{}<br>{}</p>",
+                                   escape_html(&item.proposal.proposal_api),
+                                   escape_html(&field("proposal_id")), escape_html(&field("proposal_blockchain")), escape_html(&field("proposal_title")),
+                                   escape_html(&field("proposal_state")).replace('\n', "<br>"),
+                                   escape_html(&field("proposal_submitted")),
+                                   escape_html(&field("proposal_summary")).replace('\n', "<br>")));
+        }
+        text.push_str(&format!("\nUnsubscribe: {}\n", unsubscribe));
+        html.push_str(&format!("<p><small><a href=\"{}\">Unsubscribe</a></small></p>", escape_html(&unsubscribe)));
+    }
+    html.push_str("</body></html>");
+    EmailMessage { subject, text, html }
+}
+
+/// Any other notification, actions are shown as bot commands.
+pub fn render_message(message: &ChannelMessage) -> EmailMessage {
+    let subject = message.parts.first()
+        .and_then(|x| x.text.lines().next())
+        .map(|x| format!("CosmosRustBot: {}", x))
+        .unwrap_or("CosmosRustBot notification".to_string());
+    let mut text = Vec::new();
+    let mut html = Vec::new();
+    for part in &message.parts {
+        let mut t = part.text.to_owned();
+        let mut h = escape_html(&part.text).replace('\n', "<br>");
+        for link in &part.links {
+            t.push_str(&format!("\n{}: {}", link.label, link.url));
+            h.push_str(&format!("<br><a href=\"{}\">{}</a>", escape_html(&link.url), escape_html(&link.label)));
+        }
+        for action in &part.actions {
+            t.push_str(&format!("\n{}: {}", action.label, action.command));
+            h.push_str(&format!("<br>{}: <code>{}</code>", escape_html(&action.label), escape_html(&action.command)));
+        }
+        text.push(t);
+        html.push(format!("<p>{}</p>", h));
+    }
+    EmailMessage { subject, text: text.join("\n\n"), html: format!("<html><body>{}</body></html>", html.join("")) }
+}
+
+fn base64_lines(text: &str) -> String {
+    base64::encode(text.as_bytes()).as_bytes().chunks(76)
+        .map(|x| String::from_utf8_lossy(x).to_string())
+        .collect::<Vec<String>>().join("\r\n")
+}
+
+// characters outside of quoted strings that an addr-spec may not contain.
+const SPECIALS: &str = "()<>[]:;@\\,\"";
+
+/// A single addr-spec, e.g. "user@example.com": no display name, comments, quoting, whitespace or control characters.
+pub fn is_valid_address(address: &str) -> bool {
+    let (local, domain) = match address.split_once('@') {
+        Some(parts) => parts,
+        None => { return false; }
+    };
+    let is_atom = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_graphic() && !SPECIALS.contains(c));
+    address.len() <= 254
+        && local.len() <= 64
+        && local.split('.').all(is_atom)
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty() && label.len() <= 63
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// multipart/alternative message with a text and a HTML part.
+pub fn to_mime(message: &EmailMessage, from: &str, to: &str) -> String {
+    let boundary = format!("crb-{:016x}", stable_hash(&(&message.subject, &message.text, to)));
+    [
+        format!("From: {}", from),
+        format!("To: {}", to),
+        format!("Subject: =?UTF-8?B?{}?=", base64::encode(message.subject.as_bytes())),
+        format!("Date: {}", Utc::now().to_rfc2822()),
+        format!("Message-ID: <{}.{}@{}>", Utc::now().timestamp(), boundary, address(from).split('@').last().unwrap_or("localhost")),
+        "MIME-Version: 1.0".to_string(),
+        format!("Content-Type: multipart/alternative; boundary=\"{}\"", boundary),
+        String::new(),
+        format!("--{}", boundary),
+        "Content-Type: text/plain; charset=UTF-8".to_string(),
+        "Content-Transfer-Encoding: base64".to_string(),
+        String::new(),
+        base64_lines(&message.text),
+        format!("--{}", boundary),
+        "Content-Type: text/html; charset=UTF-8".to_string(),
+        "Content-Transfer-Encoding: base64".to_string(),
+        String::new(),
+        base64_lines(&message.html),
+        format!("--{}--", boundary),
+    ].join("\r\n")
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" || host.parse::<std::net::IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+/// Sends the message, blocking.
+pub fn send(smtp: &SmtpConfig, to: &str, message: &EmailMessage) -> anyhow::Result<()> {
+    // the mailbox ends up in the RCPT command and the To header.
+    if to.chars().any(|c| c.is_control()) || !is_valid_address(address(to)) {
+        return Err(anyhow::anyhow!("Error: invalid email address"));
+    }
+    if smtp.username.is_some() && smtp.security == SmtpSecurity::None && !is_loopback(&smtp.host) && !smtp.allow_insecure_auth {
+        return Err(anyhow::anyhow!("Error: refusing to send SMTP credentials without TLS to {}", smtp.host));
+    }
+    let builder = match smtp.security {
+        SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&smtp.host)?,
+        SmtpSecurity::Tls => SmtpTransport::relay(&smtp.host)?,
+        SmtpSecurity::None => SmtpTransport::builder_dangerous(&smtp.host),
+    };
+    let mut builder = builder
+        .port(smtp.port)
+        .hello_name(ClientId::Domain("cosmos-rust-bot".to_string()))
+        .timeout(Some(Duration::from_secs(SMTP_TIMEOUT_SECONDS)));
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        builder = builder.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+    }
+    let envelope = Envelope::new(Some(address(&smtp.from).parse::<Address>()?), vec![address(to).parse::<Address>()?])?;
+    builder.build().send_raw(&envelope, to_mime(message, &smtp.from, to).as_bytes())?;
+    Ok(())
+}
+
+fn digest_tree(db: &sled::Db) -> sled::Tree {
+    db.open_tree(EMAIL_DIGEST_TREE).unwrap()
+}
+
+/// Adds the proposals of a subscription update to the next summary of the user.
+pub fn queue(db: &sled::Db, user_hash: u64, config: &EmailConfig, subscription: &EntriesQueryPart, entries: &Vec<CosmosRustBotValue>) {
+    let proposals = entries.iter().filter_map(|entry| match entry {
+        CosmosRustBotValue::Entry(Entry::Value(Value { custom_data: CustomData::ProposalData(proposal), .. })) => Some(proposal.clone()),
+        _ => None,
+    }).collect::<Vec<ProposalData>>();
+    if proposals.is_empty() {
+        return;
+    }
+    let deliver_at = next_delivery(&config.schedule, Utc::now().timestamp());
+    // atomic, the summary may be sent at the same time.
+    digest_tree(db).update_and_fetch(user_hash.to_be_bytes(), |old| {
+        let mut digest = old.and_then(|v| EmailDigest::try_from(v.to_vec()).ok())
+            .unwrap_or(EmailDigest { user_hash, deliver_at, items: Vec::new() });
+        for proposal in &proposals {
+            // only the latest state of a proposal.
+            digest.items.retain(|x| !(x.proposal.proposal_blockchain == proposal.proposal_blockchain && x.proposal.proposal_id == proposal.proposal_id));
+            digest.items.push(EmailDigestItem { subscription: subscription.clone(), proposal: proposal.clone() });
+        }
+        TryInto::<Vec<u8>>::try_into(digest).ok().or(old.map(|v| v.to_vec()))
+    }).ok();
+}
+
+// removes the sent items, items queued while sending stay for the next summary at `deliver_at`.
+fn remove_sent(tree: &sled::Tree, user_hash: u64, sent: &[EmailDigestItem], deliver_at: i64) {
+    tree.update_and_fetch(user_hash.to_be_bytes(), |old| {
+        let mut digest = EmailDigest::try_from(old?.to_vec()).ok()?;
+        digest.items.retain(|x| !sent.contains(x));
+        if digest.items.is_empty() {
+            return None;
+        }
+        digest.deliver_at = deliver_at;
+        TryInto::<Vec<u8>>::try_into(digest).ok()
+    }).ok();
+}
+
+/// Sends every summary that is due, a failed summary is kept and retried.
+pub fn flush_due_digests(db: &sled::Db, smtp: &SmtpConfig) {
+    let tree = digest_tree(db);
+    let now = Utc::now().timestamp();
+    for digest in tree.iter().values().filter_map(|x| x.ok()).filter_map(|v| EmailDigest::try_from(v.to_vec()).ok()) {
+        if digest.deliver_at > now {
+            continue;
+        }
+        let config = match super::get_channel(db, digest.user_hash) {
+            Some(super::ChannelConfig::Email(config)) => config,
+            _ => {
+                // the user switched to another channel.
+                remove_sent(&tree, digest.user_hash, &digest.items, now);
+                continue;
+            }
+        };
+        match send(smtp, &config.address, &render_digest(smtp, digest.user_hash, &digest.items)) {
+            Ok(()) => {
+                remove_sent(&tree, digest.user_hash, &digest.items, next_delivery(&config.schedule, now));
+            },
+            Err(err) => {
+                info!("Email summary for user {} failed: {}", digest.user_hash, err.to_string());
+            }
+        }
+    }
+}
+
+pub fn spawn_email_digest_task(db: &sled::Db, config: Arc<Config>) -> JoinHandle<()> {
+    info!("Spawning email digest task");
+    let db = db.clone();
+    std::thread::spawn(move || {
+        loop {
+            match SmtpConfig::load(&config.files.smtp_config_json) {
+                Ok(smtp) => flush_due_digests(&db, &smtp),
+                Err(err) => error!("Unable to load the SMTP config: {}", err.to_string()),
+            }
+            std::thread::sleep(Duration::from_secs(EMAIL_CHECK_INTERVAL_SECONDS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{digest_tree, is_valid_address, remove_sent, render_digest, render_message, send, to_mime, unsubscribe_link, unsubscribe_query, sign, EmailDigest, EmailDigestItem, EmailMessage, SmtpConfig, SmtpSecurity};
+    use crate::utils::entry::db::notification::channel::{Action, ChannelMessage, Link, MessagePart};
+    use crate::utils::entry::db::notification::stub::spawn_smtp_sink;
+    use crate::utils::entry::db::SubscriptionStore;
+    use crate::utils::entry::{CosmosRustBotValue, EntriesQueryPart, ProposalData, QueryPart, Subscription, SubscriptionAction};
+    use std::collections::{HashMap, HashSet};
+
+    fn smtp(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: Some("bot".to_string()),
+            password: Some("secret".to_string()),
+            from: "CosmosRustBot <bot@example.com>".to_string(),
+            unsubscribe_url: "https://example.com/unsubscribe".to_string(),
+            unsubscribe_secret: "key".to_string(),
+            allow_insecure_auth: false,
+        }
+    }
+
+    #[test]
+    pub fn multipart_message() {
+        let message = render_message(&ChannelMessage {
+            parts: vec![MessagePart {
+                text: "Subscribed\n/gov_prpsl_all".to_string(),
+                links: vec![Link { label: "Open in Browser".to_string(), url: "https://example.com/?a=1&b=2".to_string() }],
+                actions: vec![Action { label: "Unsubscribe".to_string(), command: "gov prpsl all unsubscribe".to_string() }],
+            }],
+        });
+        assert_eq!(message.subject, "CosmosRustBot: Subscribed");
+        assert_eq!(message.html, "<html><body><p>Subscribed<br>/gov_prpsl_all<br><a href=\"https://example.com/?a=1&amp;b=2\">Open in Browser</a><br>Unsubscribe: <code>gov prpsl all unsubscribe</code></p></body></html>");
+
+        let mime = to_mime(&message, "bot@example.com", "user@example.com");
+        assert!(mime.contains("Content-Type: multipart/alternative; boundary=\"crb-"));
+        assert!(mime.contains("Content-Type: text/plain; charset=UTF-8"));
+        assert!(mime.contains("Content-Type: text/html; charset=UTF-8"));
+        assert!(mime.contains(&base64::encode(message.text.as_bytes())));
+        assert!(mime.lines().all(|line| line.len() <= 998));
+    }
+
+    fn entries_query(message: &str) -> EntriesQueryPart {
+        serde_json::from_value(serde_json::json!({"message": message, "display": "default", "indices": [], "filter": [], "order_by": "", "limit": 10})).unwrap()
+    }
+
+    fn proposal(id: u64, title: &str) -> ProposalData {
+        serde_json::from_value(serde_json::json!({
+            "proposal_api": format!("https://example.com/proposal?id={}&chain=cosmos", id), "proposal_link": "", "proposal_summary": "summary",
+            "proposal_briefing": "", "proposal_blockchain": "cosmos", "proposal_blockchain_display": "Cosmos Hub",
+            "proposal_status": "", "proposal_id": id, "proposal_title": title, "proposal_description": "",
+            "proposal_vetoed": false, "proposal_state": "Voting", "proposal_in_deposit_period": false, "fraud_risk": "0",
+            "proposal_status_icon": "", "proposal_preview_msg": "", "proposal_spam_likelihood": "0", "proposal_submitted": "2023-01-02",
+        })).unwrap()
+    }
+
+    fn params(link: &str) -> HashMap<String, String> {
+        link.split_once('?').unwrap().1.split('&').filter_map(|x| x.split_once('=')).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    pub fn valid_addresses() {
+        assert!(is_valid_address("user@example.com"));
+        assert!(is_valid_address("first.last+tag@mail.example.org"));
+        assert!(!is_valid_address("User <user@example.com>"));
+        assert!(!is_valid_address("user@example.com>\r\nRCPT TO:<other@example.com"));
+        assert!(!is_valid_address("user@example.com\r\nBcc: other@example.com"));
+        assert!(!is_valid_address("us er@example.com"));
+        assert!(!is_valid_address("user@@example.com"));
+        assert!(!is_valid_address("@example.com"));
+        assert!(!is_valid_address("user@localhost"));
+        assert!(!is_valid_address("user@-example.com"));
+        assert!(!is_valid_address("user..name@example.com"));
+    }
+
+    #[test]
+    pub fn digest_grouped_by_subscription() {
+        let (governance, cosmos) = (entries_query("gov prpsl all"), entries_query("gov prpsl cosmos"));
+        let items = vec![
+            EmailDigestItem { subscription: governance.clone(), proposal: proposal(1, "<b>Upgrade</b> & more") },
+            EmailDigestItem { subscription: cosmos.clone(), proposal: proposal(2, "Community pool spend") },
+            EmailDigestItem { subscription: governance.clone(), proposal: proposal(3, "Parameter change") },
+        ];
+        let message = render_digest(&smtp(25), 42, &items);
+        assert_eq!(message.subject, "CosmosRustBot: 3 proposal updates");
+        // one section and one unsubscribe link per subscription, in the order of the first item.
+        let all = message.text.find("/gov_prpsl_all").unwrap();
+        let hub = message.text.find("/gov_prpsl_cosmos").unwrap();
+        assert!(all < message.text.find("#3 Cosmos Hub: Parameter change").unwrap());
+        assert!(message.text.find("#3 Cosmos Hub: Parameter change").unwrap() < hub);
+        assert_eq!(message.text.matches("\nUnsubscribe: https://example.com/unsubscribe?").count(), 2);
+        assert!(message.text.contains(&unsubscribe_link(&smtp(25), 42, &cosmos)));
+        // user data is escaped in the HTML part.
+        assert!(message.html.contains("#1 Cosmos Hub: &lt;b&gt;Upgrade&lt;/b&gt; &amp; more"));
+        assert!(message.html.contains("<a href=\"https://example.com/proposal?id=2&amp;chain=cosmos\">"));
+        assert_eq!(message.html.matches(">Unsubscribe</a>").count(), 2);
+
+        let single = render_digest(&smtp(25), 42, &items[..1]);
+        assert_eq!(single.subject, "CosmosRustBot: 1 proposal update");
+    }
+
+    #[test]
+    pub fn items_queued_while_sending_wait_for_the_next_summary() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = digest_tree(&db);
+        let query_part = entries_query("gov prpsl all");
+        let sent = vec![EmailDigestItem { subscription: query_part.clone(), proposal: proposal(1, "Sent") }];
+        let queued = EmailDigestItem { subscription: query_part, proposal: proposal(2, "Queued while sending") };
+        let digest = EmailDigest { user_hash: 42, deliver_at: 100, items: vec![sent[0].clone(), queued.clone()] };
+        let value: Vec<u8> = digest.try_into().unwrap();
+        tree.insert(42u64.to_be_bytes(), value).unwrap();
+
+        remove_sent(&tree, 42, &sent, 200);
+        let digest = EmailDigest::try_from(tree.get(42u64.to_be_bytes()).unwrap().unwrap().to_vec()).unwrap();
+        assert_eq!(digest.items, vec![queued]);
+        assert_eq!(digest.deliver_at, 200);
+
+        remove_sent(&tree, 42, &digest.items, 300);
+        assert!(tree.get(42u64.to_be_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    pub fn unsubscribe_link_to_query() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SubscriptionStore::new(&db);
+        let query_part = entries_query("gov prpsl all");
+        let item = CosmosRustBotValue::Subscription(Subscription {
+            action: SubscriptionAction::Created,
+            query: QueryPart::EntriesQueryPart(query_part.clone()),
+            user_list: HashSet::from([42]),
+            list: Vec::new(),
+            schedules: HashMap::new(),
+        });
+        let key = item.key();
+        let value: Vec<u8> = item.try_into().unwrap();
+        db.insert(key, value).unwrap();
+
+        let link = params(&unsubscribe_link(&smtp(25), 42, &query_part));
+        let query = unsubscribe_query(&store, &smtp(25), 42, &link["subscription"], &link["token"]).unwrap();
+        assert_eq!(query.query_part, QueryPart::EntriesQueryPart(query_part.clone()));
+        assert_eq!(query.settings_part.unsubscribe, Some(true));
+        assert_eq!(query.settings_part.user_hash, Some(42));
+
+        assert!(unsubscribe_query(&store, &smtp(25), 42, &link["subscription"], "0000").is_none());
+        assert!(unsubscribe_query(&store, &smtp(25), 43, &link["subscription"], &link["token"]).is_none());
+        // a valid link of a user that is not subscribed.
+        let other = params(&unsubscribe_link(&smtp(25), 43, &query_part));
+        assert!(unsubscribe_query(&store, &smtp(25), 43, &other["subscription"], &other["token"]).is_none());
+    }
+
+    #[test]
+    pub fn unsubscribe_token() {
+        let link = unsubscribe_link(&smtp(25), 42, &serde_json::from_str(r#"{"message":"gov prpsl all","display":"default","indices":[],"filter":[],"order_by":"","limit":10}"#).unwrap());
+        let (_, params) = link.split_once('?').unwrap();
+        let params = params.split('&').filter_map(|x| x.split_once('=')).collect::<std::collections::HashMap<&str, &str>>();
+        assert_eq!(params["user"], "42");
+        assert_eq!(params["token"], sign("key", 42, params["subscription"]));
+        assert_ne!(params["token"], sign("key", 43, params["subscription"]));
+    }
+
+    #[test]
+    pub fn send_to_smtp_sink() {
+        let (port, sink) = spawn_smtp_sink();
+        let message = EmailMessage { subject: "Weekly summary".to_string(), text: "text".to_string(), html: "<p>html</p>".to_string() };
+        assert!(send(&smtp(port), "User <user@example.com>", &message).is_ok());
+
+        let (commands, data) = sink.join().unwrap();
+        assert_eq!(commands[0], "EHLO cosmos-rust-bot");
+        assert_eq!(commands[1], format!("AUTH PLAIN {}", base64::encode("\0bot\0secret")));
+        assert_eq!(commands[2], "MAIL FROM:<bot@example.com>");
+        assert_eq!(commands[3], "RCPT TO:<user@example.com>");
+        assert_eq!(commands[4], "DATA");
+        assert!(data.contains("To: User <user@example.com>\r\n"));
+        assert!(data.contains(&base64::encode("<p>html</p>")));
+
+        // neither the RCPT command nor the To header can be extended.
+        assert!(send(&smtp(port), "user@example.com>\r\nDATA", &message).is_err());
+        assert!(send(&smtp(port), "User\r\nBcc: other@example.com <user@example.com>", &message).is_err());
+
+        // no credentials in plain text to other hosts.
+        let remote = SmtpConfig { host: "smtp.example.com".to_string(), ..smtp(25) };
+        assert_eq!(remote.security, SmtpSecurity::None);
+        assert!(send(&remote, "user@example.com", &message).is_err());
+    }
+}
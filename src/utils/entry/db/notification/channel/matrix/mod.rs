@@ -0,0 +1,101 @@
+use super::ChannelMessage;
+use crate::utils::escape_html;
+
+use serde::{Serialize,Deserialize};
+
+// Matrix delivery through the client-server API, as `m.room.message` with an HTML formatted body.
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MatrixConfig {
+    // e.g. "https://matrix.org"
+    pub homeserver: String,
+    pub access_token: String,
+    pub room_id: String,
+}
+
+/// Event content with a plain text `body` and the HTML `formatted_body`.
+pub fn render(message: &ChannelMessage) -> serde_json::Value {
+    let mut body: Vec<String> = Vec::new();
+    let mut html: Vec<String> = Vec::new();
+    for part in &message.parts {
+        let mut text = part.text.to_owned();
+        let mut formatted = escape_html(&part.text).replace('\n', "<br>");
+        for link in &part.links {
+            text.push_str(&format!("\n{}: {}", link.label, link.url));
+            formatted.push_str(&format!("<br><a href=\"{}\">{}</a>", escape_html(&link.url), escape_html(&link.label)));
+        }
+        for action in &part.actions {
+            text.push_str(&format!("\n{}: {}", action.label, action.command));
+            formatted.push_str(&format!("<br>{}: <code>{}</code>", escape_html(&action.label), escape_html(&action.command)));
+        }
+        body.push(text);
+        html.push(format!("<p>{}</p>", formatted));
+    }
+    serde_json::json!({
+        "msgtype": "m.text",
+        "body": body.join("\n\n"),
+        "format": "org.matrix.custom.html",
+        "formatted_body": html.join(""),
+    })
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    segment.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// Sends the message to the room, the transaction id makes retries idempotent.
+pub async fn send(client: &reqwest::Client, config: &MatrixConfig, message: &ChannelMessage, transaction_id: &str) -> anyhow::Result<()> {
+    let url = format!("{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                      config.homeserver.trim_end_matches('/'), encode_path_segment(&config.room_id), encode_path_segment(transaction_id));
+    let response = client.put(url)
+        .header("Authorization", format!("Bearer {}", config.access_token))
+        .header("Content-Type", "application/json")
+        .body(render(message).to_string())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!("Error: Matrix responded with status {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{render, send, MatrixConfig};
+    use crate::utils::entry::db::notification::channel::{Action, ChannelMessage, Link, MessagePart};
+    use crate::utils::entry::db::notification::stub::{body, header, request_line, spawn_stub};
+
+    fn message() -> ChannelMessage {
+        ChannelMessage {
+            parts: vec![MessagePart {
+                text: "Proposal <1>".to_string(),
+                links: vec![Link { label: "Open in Browser".to_string(), url: "https://example.com/?a=1&b=2".to_string() }],
+                actions: vec![Action { label: "Unsubscribe".to_string(), command: "gov prpsl all unsubscribe".to_string() }],
+            }],
+        }
+    }
+
+    #[test]
+    pub fn render_html() {
+        let content = render(&message());
+        assert_eq!(content["body"], "Proposal <1>\nOpen in Browser: https://example.com/?a=1&b=2\nUnsubscribe: gov prpsl all unsubscribe");
+        assert_eq!(content["formatted_body"], "<p>Proposal &lt;1&gt;<br><a href=\"https://example.com/?a=1&amp;b=2\">Open in Browser</a><br>Unsubscribe: <code>gov prpsl all unsubscribe</code></p>");
+    }
+
+    #[test]
+    pub fn send_to_mock_server() {
+        let runtime = cosmos_rust_package::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (url, stub) = spawn_stub("200 OK", r#"{"event_id":"$1"}"#);
+        let config = MatrixConfig { homeserver: url, access_token: "token".to_string(), room_id: "!room:example.com".to_string() };
+        assert!(runtime.block_on(send(&reqwest::Client::new(), &config, &message(), "abc")).is_ok());
+
+        let request = stub.join().unwrap();
+        assert_eq!(request_line(&request), "PUT /_matrix/client/v3/rooms/%21room%3Aexample.com/send/m.room.message/abc HTTP/1.1");
+        assert_eq!(header(&request, "Authorization").unwrap(), "Bearer token");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body(&request)).unwrap(), render(&message()));
+    }
+}
@@ -0,0 +1,279 @@
+pub mod matrix;
+pub mod discord;
+pub mod email;
+
+use crate::utils::entry::*;
+use crate::utils::entry::db::notification::outbox;
+use crate::utils::entry::db::notification::webhook;
+use crate::utils::entry::db::audit::{self, AuditAction};
+use crate::utils::hash::to_hex;
+use cosmos_rust_package::tokio;
+use std::sync::Arc;
+use log::info;
+
+use serde::{Serialize,Deserialize};
+
+// Channel-neutral message model and delivery to channels other than Telegram.
+//
+// A Notify carries Telegram-shaped buttons, `ChannelMessage` turns them into links (urls) and
+// actions (bot commands). Users routed to Matrix, Discord or email with a `ChannelQueryPart` (see `set_channel`) are delivered by
+// `spawn_channel_delivery_task`, the Telegram frontend only claims the Notify records of the others.
+// Subscription updates for email users are collected into a summary instead, see `email`.
+
+const CHANNEL_TREE: &str = "channel";
+const CHANNEL_CHECK_INTERVAL_SECONDS: u64 = 10;
+const CHANNEL_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Link {
+    pub label: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Action {
+    pub label: String,
+    // bot command, e.g. "gov prpsl all subscribe".
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MessagePart {
+    pub text: String,
+    pub links: Vec<Link>,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelMessage {
+    pub parts: Vec<MessagePart>,
+}
+
+fn is_url(value: &str) -> bool {
+    value.starts_with("https://") || value.starts_with("http://")
+}
+
+impl From<&Notify> for ChannelMessage {
+    fn from(notify: &Notify) -> Self {
+        let parts = notify.msg.iter().enumerate().map(|(i, text)| {
+            let mut part = MessagePart { text: text.to_owned(), links: Vec::new(), actions: Vec::new() };
+            for (label, value) in notify.buttons.get(i).into_iter().flatten().flatten() {
+                if is_url(value) {
+                    part.links.push(Link { label: label.to_owned(), url: value.to_owned() });
+                } else {
+                    part.actions.push(Action { label: label.to_owned(), command: value.to_owned() });
+                }
+            }
+            part
+        }).collect();
+        ChannelMessage { parts }
+    }
+}
+
+impl ChannelMessage {
+    /// The shape of `Notify`: one text per part with a single row of buttons.
+    pub fn to_telegram(&self) -> (Vec<String>, Vec<Vec<Vec<(String,String)>>>) {
+        let msg = self.parts.iter().map(|x| x.text.to_owned()).collect();
+        let buttons = self.parts.iter().map(|part| {
+            let row = part.links.iter().map(|x| (x.label.to_owned(), x.url.to_owned()))
+                .chain(part.actions.iter().map(|x| (x.label.to_owned(), x.command.to_owned())))
+                .collect::<Vec<(String,String)>>();
+            if row.is_empty() { Vec::new() } else { vec![row] }
+        }).collect();
+        (msg, buttons)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ChannelConfig {
+    Matrix(matrix::MatrixConfig),
+    Discord(discord::DiscordConfig),
+    Email(email::EmailConfig),
+}
+
+impl TryFrom<Vec<u8>> for ChannelConfig {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<ChannelConfig> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: ChannelConfig) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+fn is_discord_webhook(url: &str) -> bool {
+    url.starts_with("https://discord.com/api/webhooks/") || url.starts_with("https://discordapp.com/api/webhooks/")
+}
+
+fn has_control_chars(value: &str) -> bool {
+    value.chars().any(|c| c.is_control())
+}
+
+/// The channel requested by the query, None for Telegram. An error if the settings are invalid.
+pub fn channel_config(query_part: &ChannelQueryPart) -> anyhow::Result<Option<ChannelConfig>> {
+    match query_part {
+        ChannelQueryPart::Telegram => Ok(None),
+        ChannelQueryPart::Matrix { homeserver, access_token, room_id } => {
+            webhook::resolve_url(homeserver)?;
+            if access_token.is_empty() || has_control_chars(access_token) {
+                return Err(anyhow::anyhow!("Error: invalid Matrix access token"));
+            }
+            // "!opaque_id:server"
+            if !room_id.starts_with('!') || !room_id.contains(':') || room_id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(anyhow::anyhow!("Error: invalid Matrix room id"));
+            }
+            Ok(Some(ChannelConfig::Matrix(matrix::MatrixConfig {
+                homeserver: homeserver.to_owned(),
+                access_token: access_token.to_owned(),
+                room_id: room_id.to_owned(),
+            })))
+        },
+        ChannelQueryPart::Discord { webhook_url } => {
+            if !is_discord_webhook(webhook_url) {
+                return Err(anyhow::anyhow!("Error: not a Discord webhook url"));
+            }
+            webhook::resolve_url(webhook_url)?;
+            Ok(Some(ChannelConfig::Discord(discord::DiscordConfig { webhook_url: webhook_url.to_owned() })))
+        },
+        ChannelQueryPart::Email { address } => {
+            if !email::is_valid_address(address) {
+                return Err(anyhow::anyhow!("Error: invalid email address"));
+            }
+            Ok(Some(ChannelConfig::Email(email::EmailConfig::new(address))))
+        },
+    }
+}
+
+fn channel_tree(db: &sled::Db) -> sled::Tree {
+    db.open_tree(CHANNEL_TREE).unwrap()
+}
+
+/// The channel the user's notifications are delivered to, None for Telegram.
+pub fn get_channel(db: &sled::Db, user_hash: u64) -> Option<ChannelConfig> {
+    channel_tree(db).get(user_hash.to_be_bytes()).ok()?.and_then(|v| v.to_vec().try_into().ok())
+}
+
+/// Routes the user's notifications to the channel, None switches back to Telegram.
+pub fn set_channel(db: &sled::Db, user_hash: u64, channel: Option<ChannelConfig>) -> anyhow::Result<()> {
+    if let Some(ChannelConfig::Email(config)) = &channel {
+        if !email::is_valid_address(&config.address) {
+            return Err(anyhow::anyhow!("Error: invalid email address"));
+        }
+    }
+    let tree = channel_tree(db);
+    let summary = match &channel {
+        Some(ChannelConfig::Matrix(config)) => format!("matrix {}", config.room_id),
+        Some(ChannelConfig::Discord(_)) => "discord".to_string(),
+        Some(ChannelConfig::Email(_)) => "email".to_string(),
+        None => "telegram".to_string(),
+    };
+    audit::record(db, Some(user_hash), AuditAction::ChannelChanged, summary);
+    match channel {
+        Some(channel) => {
+            let value: Vec<u8> = channel.try_into()?;
+            tree.insert(user_hash.to_be_bytes(), value)?;
+        },
+        None => {
+            tree.remove(user_hash.to_be_bytes())?;
+        },
+    }
+    Ok(())
+}
+
+// The urls are user supplied, like webhooks they are resolved and checked again before every delivery.
+pub async fn send(config: &Config, channel: &ChannelConfig, message: &ChannelMessage, transaction_id: &str) -> anyhow::Result<()> {
+    match channel {
+        ChannelConfig::Matrix(config) => {
+            let client = webhook::pinned_client(&config.homeserver, CHANNEL_TIMEOUT_SECONDS)?;
+            matrix::send(&client, config, message, transaction_id).await
+        },
+        ChannelConfig::Discord(config) => {
+            let client = webhook::pinned_client(&config.webhook_url, CHANNEL_TIMEOUT_SECONDS)?;
+            discord::send(&client, config, message).await
+        },
+        ChannelConfig::Email(email_config) => {
+            let smtp = email::SmtpConfig::load(&config.files.smtp_config_json)?;
+            let to = email_config.address.to_owned();
+            let message = email::render_message(message);
+            tokio::task::spawn_blocking(move || email::send(&smtp, &to, &message)).await?
+        },
+    }
+}
+
+/// Delivers the due Notify records of users with a channel and acknowledges the result.
+pub async fn deliver_due(db: &sled::Db, config: &Config) {
+    for notify in outbox::claim_due_where(db, |notify| get_channel(db, notify.user_hash).is_some()) {
+        let channel = match get_channel(db, notify.user_hash) {
+            Some(channel) => channel,
+            None => { continue; }
+        };
+        let notify_key = notify.get_key();
+        // retries reuse the transaction id, so Matrix does not post the message twice.
+        let transaction_id = to_hex(&notify_key);
+        let result = send(config, &channel, &ChannelMessage::from(&notify), &transaction_id).await;
+        if let Err(err) = &result {
+            info!("Channel delivery failed for user {}: {}", notify.user_hash, err.to_string());
+        }
+        let ack = NotifyAck {
+            notify_key,
+            delivered: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        };
+        outbox::acknowledge(db, &ack).ok();
+    }
+}
+
+pub fn spawn_channel_delivery_task(db: &sled::Db, config: Arc<Config>) -> tokio::task::JoinHandle<()> {
+    let db = db.clone();
+    tokio::spawn(async move {
+        loop {
+            deliver_due(&db, &config).await;
+            tokio::time::sleep(std::time::Duration::from_secs(CHANNEL_CHECK_INTERVAL_SECONDS)).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{channel_config, email, Action, ChannelConfig, ChannelMessage, Link};
+    use crate::utils::entry::{ChannelQueryPart, Notify};
+
+    #[test]
+    pub fn notify_to_channel_message() {
+        let notify = Notify {
+            timestamp: 0,
+            msg: vec!["Proposal 1".to_string(), "Subscribed".to_string()],
+            buttons: vec![vec![vec![("Open in Browser".to_string(), "https://example.com/1".to_string()), ("Unsubscribe".to_string(), "gov prpsl all unsubscribe".to_string())]]],
+            user_hash: 1,
+        };
+        let message = ChannelMessage::from(&notify);
+        assert_eq!(message.parts.len(), 2);
+        assert_eq!(message.parts[0].links, vec![Link { label: "Open in Browser".to_string(), url: "https://example.com/1".to_string() }]);
+        assert_eq!(message.parts[0].actions, vec![Action { label: "Unsubscribe".to_string(), command: "gov prpsl all unsubscribe".to_string() }]);
+        assert!(message.parts[1].links.is_empty() && message.parts[1].actions.is_empty());
+
+        let (msg, buttons) = message.to_telegram();
+        assert_eq!(msg, notify.msg);
+        assert_eq!(buttons[0], notify.buttons[0]);
+        assert!(buttons[1].is_empty());
+    }
+
+    #[test]
+    pub fn validated_channel_settings() {
+        assert_eq!(channel_config(&ChannelQueryPart::Telegram).unwrap(), None);
+        assert_eq!(channel_config(&ChannelQueryPart::Email { address: "user@example.com".to_string() }).unwrap(),
+                   Some(ChannelConfig::Email(email::EmailConfig::new("user@example.com"))));
+        assert!(channel_config(&ChannelQueryPart::Email { address: "user@example.com>\r\nRCPT TO:<other@example.com".to_string() }).is_err());
+        assert!(channel_config(&ChannelQueryPart::Discord { webhook_url: "https://example.com/api/webhooks/1/token".to_string() }).is_err());
+        assert!(channel_config(&ChannelQueryPart::Matrix {
+            homeserver: "https://127.0.0.1:8008".to_string(),
+            access_token: "token".to_string(),
+            room_id: "!room:example.com".to_string(),
+        }).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::utils::entry::*;
+use cosmos_rust_package::chrono::Utc;
+use std::thread::JoinHandle;
+use log::info;
+
+use serde::{Serialize,Deserialize};
+
+use super::insert_notify;
+
+// Subscription updates for users with a digest schedule or quiet hours are collected per user
+// and delivered as one Notify when the digest is due.
+// Updates arrive while digests are flushed: appending is atomic (`update_and_fetch`) and a digest is
+// only removed if it is still the value that was read (`compare_and_swap`).
+
+const DIGEST_TREE: &str = "digest";
+const DIGEST_CHECK_INTERVAL_SECONDS: u64 = 60;
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PendingDigest {
+    pub user_hash: u64,
+    pub deliver_at: i64,
+    pub count: usize,
+    pub msg: Vec<String>,
+    pub buttons: Vec<Vec<Vec<(String,String)>>>,
+}
+
+impl TryFrom<Vec<u8>> for PendingDigest {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<PendingDigest> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: PendingDigest) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+fn local_time(schedule: &DeliverySchedule, t: i64) -> i64 {
+    t + schedule.utc_offset_minutes as i64 * MINUTE
+}
+
+pub fn is_quiet(schedule: &DeliverySchedule, t: i64) -> bool {
+    match &schedule.quiet_hours {
+        Some(QuietHours { from_minute, to_minute }) => {
+            let minute = local_time(schedule, t).rem_euclid(DAY) / MINUTE;
+            let (from, to) = (*from_minute as i64, *to_minute as i64);
+            if from <= to {
+                minute >= from && minute < to
+            } else {
+                minute >= from || minute < to
+            }
+        },
+        None => false,
+    }
+}
+
+/// The first time at or after `t` that is not within the quiet hours.
+pub fn end_of_quiet_hours(schedule: &DeliverySchedule, t: i64) -> i64 {
+    match &schedule.quiet_hours {
+        Some(QuietHours { to_minute, .. }) if is_quiet(schedule, t) => {
+            let local = local_time(schedule, t);
+            let minute = local.rem_euclid(DAY) / MINUTE;
+            let minutes_left = (*to_minute as i64 - minute).rem_euclid(DAY / MINUTE);
+            t - local.rem_euclid(MINUTE) + minutes_left * MINUTE
+        },
+        _ => t,
+    }
+}
+
+/// When an update that arrives at `now` is delivered.
+pub fn next_delivery(schedule: &DeliverySchedule, now: i64) -> i64 {
+    let local = local_time(schedule, now);
+    let local_delivery = match schedule.mode {
+        DeliveryMode::Immediate => local,
+        DeliveryMode::HourlyDigest => local - local.rem_euclid(HOUR) + HOUR,
+        DeliveryMode::DailyDigest { hour, minute } => {
+            let at = local - local.rem_euclid(DAY) + hour as i64 * HOUR + minute as i64 * MINUTE;
+            if at <= local { at + DAY } else { at }
+        },
+        DeliveryMode::WeeklyDigest { weekday, hour, minute } => {
+            // 1970-01-01 was a Thursday.
+            let today = (local.div_euclid(DAY) + 3).rem_euclid(7);
+            let days = (weekday as i64 - today).rem_euclid(7);
+            let at = local - local.rem_euclid(DAY) + days * DAY + hour as i64 * HOUR + minute as i64 * MINUTE;
+            if at <= local { at + 7 * DAY } else { at }
+        },
+    };
+    end_of_quiet_hours(schedule, local_delivery - (local - now))
+}
+
+fn digest_tree(db: &sled::Db) -> sled::Tree {
+    db.open_tree(DIGEST_TREE).unwrap()
+}
+
+/// Delivers the message according to the schedule, either right away or with the next digest of the user.
+pub fn deliver(db: &sled::Db, msg: Vec<String>, mut buttons: Vec<Vec<Vec<(String,String)>>>, user_hash: u64, schedule: Option<&DeliverySchedule>) {
+    let now = Utc::now().timestamp();
+    let deliver_at = match schedule {
+        Some(schedule) => next_delivery(schedule, now),
+        None => now,
+    };
+    if deliver_at <= now {
+        insert_notify(db, msg, buttons, user_hash);
+        return;
+    }
+    // one button row per message, so the rows still line up after merging.
+    buttons.resize(msg.len(), Vec::new());
+    append(&digest_tree(db), user_hash, deliver_at, msg, buttons);
+}
+
+fn append(tree: &sled::Tree, user_hash: u64, deliver_at: i64, msg: Vec<String>, buttons: Vec<Vec<Vec<(String,String)>>>) {
+    tree.update_and_fetch(user_hash.to_be_bytes(), |old| {
+        let digest = match old.and_then(|v| PendingDigest::try_from(v.to_vec()).ok()) {
+            Some(mut digest) => {
+                digest.deliver_at = digest.deliver_at.min(deliver_at);
+                digest.count += 1;
+                digest.msg.extend(msg.iter().cloned());
+                digest.buttons.extend(buttons.iter().cloned());
+                digest
+            },
+            None => PendingDigest { user_hash, deliver_at, count: 1, msg: msg.clone(), buttons: buttons.clone() },
+        };
+        // keeps the previous value if it can not be encoded.
+        TryInto::<Vec<u8>>::try_into(digest).ok().or(old.map(|v| v.to_vec()))
+    }).ok();
+}
+
+/// Turns every digest that is due into a single Notify.
+pub fn flush_due_digests(db: &sled::Db) {
+    let tree = digest_tree(db);
+    let now = Utc::now().timestamp();
+    for key in tree.iter().keys().filter_map(|x| x.ok()) {
+        // read again if a message was appended in between.
+        while let Ok(Some(value)) = tree.get(&key) {
+            let digest = match PendingDigest::try_from(value.to_vec()) {
+                Ok(digest) if digest.deliver_at <= now => digest,
+                _ => { break; }
+            };
+            if let Ok(Ok(())) = tree.compare_and_swap(&key, Some(value), None as Option<&[u8]>) {
+                let mut msg = vec![format!("Digest: {} update{}", digest.count, if digest.count == 1 { "" } else { "s" })];
+                let mut buttons = vec![Vec::new()];
+                msg.extend(digest.msg);
+                buttons.extend(digest.buttons);
+                insert_notify(db, msg, buttons, digest.user_hash);
+                break;
+            }
+        }
+    }
+}
+
+pub fn spawn_digest_task(db: &sled::Db) -> JoinHandle<()> {
+    info!("Spawning digest task");
+    let db = db.clone();
+    std::thread::spawn(move || {
+        loop {
+            flush_due_digests(&db);
+            std::thread::sleep(std::time::Duration::from_secs(DIGEST_CHECK_INTERVAL_SECONDS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{append, digest_tree, flush_due_digests, is_quiet, next_delivery};
+    use crate::utils::entry::{CosmosRustServerValue, DeliveryMode, DeliverySchedule, QuietHours};
+
+    // 2023-01-02 10:30:00 UTC
+    const NOW: i64 = 1672655400;
+
+    #[test]
+    pub fn digest_delivery_time() {
+        let hourly = DeliverySchedule { mode: DeliveryMode::HourlyDigest, ..DeliverySchedule::default() };
+        assert_eq!(next_delivery(&hourly, NOW), NOW + 30 * 60);
+
+        // 08:00 in UTC+2 is 06:00 UTC, already passed today.
+        let daily = DeliverySchedule { mode: DeliveryMode::DailyDigest { hour: 8, minute: 0 }, utc_offset_minutes: 120, quiet_hours: None };
+        assert_eq!(next_delivery(&daily, NOW), NOW - 30 * 60 - 4 * 60 * 60 + 24 * 60 * 60);
+
+        // Monday 10:30 UTC is now, so it is the next Monday.
+        let weekly = DeliverySchedule { mode: DeliveryMode::WeeklyDigest { weekday: 0, hour: 10, minute: 30 }, ..DeliverySchedule::default() };
+        assert_eq!(next_delivery(&weekly, NOW), NOW + 7 * 24 * 60 * 60);
+        let weekly = DeliverySchedule { mode: DeliveryMode::WeeklyDigest { weekday: 2, hour: 8, minute: 0 }, ..DeliverySchedule::default() };
+        assert_eq!(next_delivery(&weekly, NOW), NOW - 150 * 60 + 2 * 24 * 60 * 60);
+
+        let immediate = DeliverySchedule::default();
+        assert_eq!(next_delivery(&immediate, NOW), NOW);
+    }
+
+    #[test]
+    pub fn quiet_hours() {
+        // 22:00 - 07:00 in UTC-12, it is 22:30 local time.
+        let schedule = DeliverySchedule { mode: DeliveryMode::Immediate, utc_offset_minutes: -12 * 60, quiet_hours: Some(QuietHours { from_minute: 22 * 60, to_minute: 7 * 60 }) };
+        assert!(is_quiet(&schedule, NOW));
+        assert_eq!(next_delivery(&schedule, NOW), NOW + 8 * 60 * 60 + 30 * 60);
+        assert!(!is_quiet(&schedule, NOW + 9 * 60 * 60));
+    }
+
+    #[test]
+    pub fn no_message_lost_while_flushing() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let writers = (0..4).map(|writer| {
+            let tree = digest_tree(&db);
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    // already due.
+                    append(&tree, 1, 0, vec![format!("{}-{}", writer, i)], vec![Vec::new()]);
+                }
+            })
+        }).collect::<Vec<_>>();
+        let flusher = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    flush_due_digests(&db);
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        flusher.join().unwrap();
+        flush_due_digests(&db);
+
+        let mut delivered = db.iter().values().filter_map(|x| x.ok())
+            .filter_map(|v| match CosmosRustServerValue::try_from(v.to_vec()) {
+                Ok(CosmosRustServerValue::Notify(notify)) => Some(notify.msg.into_iter().skip(1).collect::<Vec<String>>()),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<String>>();
+        delivered.sort_unstable();
+        delivered.dedup();
+        assert_eq!(delivered.len(), 4 * 50);
+        assert!(digest_tree(&db).is_empty());
+    }
+}
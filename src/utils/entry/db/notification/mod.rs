@@ -1,15 +1,21 @@
 use crate::utils::entry::*;
-use cosmos_rust_package::chrono::Utc;
+use cosmos_rust_package::chrono::{TimeZone, Utc};
 use std::collections::HashMap;
 use std::iter::FilterMap;
 use log::info;
+use channel::{email, ChannelConfig};
+use crate::utils::entry::db::audit::{self, AuditAction};
 
 pub mod socket;
+pub mod outbox;
+pub mod digest;
+pub mod webhook;
+pub mod channel;
+#[cfg(test)]
+pub mod stub;
 
 // TODO: the whole thing needs to be refactored into a NotificationStore struct.
 
-pub const CRB_USER_META_DATA_STORE_JSON: &str = "./tmp/cosmos_rust_telegram_bot_user_meta_data.json";
-
 pub fn get_user_meta_data(db: &sled::Db) -> impl Iterator<Item = UserMetaData> {
 
     db.iter().values().filter_map(|x| {
@@ -44,27 +50,66 @@ pub fn import_user_meta_data(db: &sled::Db, path: &str){
     }
 }
 
-pub fn notify_sled_db(db: &sled::Db, notification: CosmosRustServerValue) {
+// email users get a summary of the proposals instead of each update, see `channel::email`.
+fn is_email_user(db: &sled::Db, user_hash: u64) -> bool {
+    matches!(channel::get_channel(db, user_hash), Some(ChannelConfig::Email(_)))
+}
+
+pub fn insert_notify(db: &sled::Db, msg: Vec<String>, buttons: Vec<Vec<Vec<(String,String)>>>, user_hash: u64) {
+    let notify = CosmosRustServerValue::Notify(Notify {
+        timestamp: Utc::now().timestamp(),
+        msg,
+        buttons,
+        user_hash,
+    });
+    let key = notify.key();
+    db.insert(&key, TryInto::<Vec<u8>>::try_into(notify).unwrap()).ok();
+    outbox::enqueue(db, &key);
+}
+
+pub fn notify_sled_db(db: &sled::Db, config: &Config, notification: CosmosRustServerValue) {
     match notification {
         CosmosRustServerValue::UserMetaData(_) => {
             db.insert(notification.key(), TryInto::<Vec<u8>>::try_into(notification).unwrap()).ok();
             // every time a user writes to the bot. TODO: improve this.
-            export_user_meta_data(db,CRB_USER_META_DATA_STORE_JSON);
+            export_user_meta_data(db, &config.files.user_meta_data_json);
         }
         CosmosRustServerValue::Notify(_) => {
-            db.insert(notification.key(), TryInto::<Vec<u8>>::try_into(notification).unwrap()).ok();
+            let key = notification.key();
+            db.insert(&key, TryInto::<Vec<u8>>::try_into(notification).unwrap()).ok();
+            outbox::enqueue(db, &key);
+        }
+        CosmosRustServerValue::NotifyAck(ack) => {
+            if let Err(err) = outbox::acknowledge(db, &ack) {
+                info!("Ignoring notification acknowledgement: {}", err.to_string());
+            }
         }
         CosmosRustServerValue::Notification(n) => {
-            let insert_notify = |db: &sled::Db, msg: Vec<String>, buttons: Vec<Vec<Vec<(String,String)>>>, user_hash: u64| {
-                let notify = CosmosRustServerValue::Notify(Notify {
-                    timestamp: Utc::now().timestamp(),
-                    msg,
-                    buttons,
-                    user_hash,
-                });
-                db.insert(notify.key(), TryInto::<Vec<u8>>::try_into(notify).unwrap()).ok();
-            };
-
+            if let Some(delivery_key) = n.delivery_key {
+                if !outbox::accept_delivery(db, delivery_key) {
+                    info!("Ignoring subscription update that was already delivered.");
+                    return;
+                }
+            }
+            // the query was rejected or denied, or notifications were suppressed by a quota.
+            if let Some(CosmosRustBotValue::QuotaExceeded(exceeded)) = n.entries.iter().find(|x| matches!(x, CosmosRustBotValue::QuotaExceeded(_))) {
+                if let Some(user_hash) = exceeded.user_hash {
+                    insert_notify(db, vec![exceeded.message()], vec![], user_hash);
+                }
+                return;
+            }
+            if let Some(CosmosRustBotValue::AccessDenied(denied)) = n.entries.iter().find(|x| matches!(x, CosmosRustBotValue::AccessDenied(_))) {
+                if let Some(user_hash) = denied.user_hash {
+                    insert_notify(db, vec![denied.message()], vec![], user_hash);
+                }
+                return;
+            }
+            if let Some(CosmosRustBotValue::CursorExpired(expired)) = n.entries.first() {
+                if let Some(user_hash) = n.query.settings_part.user_hash {
+                    insert_notify(db, vec![expired.message()], vec![], user_hash);
+                }
+                return;
+            }
             match n.query.query_part {
                 QueryPart::SubscriptionsQueryPart(subscription_query_part) => {
                     if let Some(user_hash) = n.query.settings_part.user_hash {
@@ -113,9 +158,11 @@ pub fn notify_sled_db(db: &sled::Db, notification: CosmosRustServerValue) {
                             return;
                         }
                     }
+                    // subscription updates, delivered according to the schedule of each user.
                     if n.entries.is_empty() {
-                        for user_hash in n.user_list.into_iter() {
-                            insert_notify(db, vec![format!("Empty result set\n{}", command)], vec![], user_hash);
+                        for user_hash in n.user_list.into_iter().filter(|user_hash| !is_email_user(db, *user_hash)) {
+                            audit::record(db, Some(user_hash), AuditAction::Notified, format!("{}: empty result set", command));
+                            digest::deliver(db, vec![format!("Empty result set\n{}", command)], vec![], user_hash, n.schedules.get(&user_hash));
                         }
                     } else {
 
@@ -157,7 +204,13 @@ pub fn notify_sled_db(db: &sled::Db, notification: CosmosRustServerValue) {
                             insert_notify(db, msg, buttons, user_hash);
                         } else {
                             for user_hash in n.user_list {
-                                insert_notify(db, msg.to_owned(), buttons.clone(), user_hash);
+                                // answers "why did I get this", the subscription and what it matched.
+                                audit::record(db, Some(user_hash), AuditAction::Notified, format!("{}: {} entries", command, msg.len()));
+                                if let Some(ChannelConfig::Email(config)) = channel::get_channel(db, user_hash) {
+                                    email::queue(db, user_hash, &config, &query_part, &n.entries);
+                                } else {
+                                    digest::deliver(db, msg.to_owned(), buttons.clone(), user_hash, n.schedules.get(&user_hash));
+                                }
                             }
                         }
                     }
@@ -166,18 +219,51 @@ pub fn notify_sled_db(db: &sled::Db, notification: CosmosRustServerValue) {
 
                     for i in 0..n.entries.len() {
                         match &n.entries[i] {
+                            CosmosRustBotValue::IssuedToken(issued) => {
+                                let expires = Utc.timestamp_opt(issued.expires, 0).single().map(|t| t.format("%Y-%m-%d").to_string()).unwrap_or_default();
+                                let msg = format!("Registration successful! \nYour new authentication token is valid until {}, any previous token no longer works.", expires);
+                                insert_notify(db, vec![msg],vec![vec![vec![("Login".to_string(), config.login_url(issued.user_hash, &issued.login_code))]]], issued.user_hash);
+                            }
                             CosmosRustBotValue::Registration(registration) => {
-                                let msg = if let Some(true) = n.query.settings_part.register{
-                                    format!("Registration successful! \nYour new authentication token is now available.")
-                                }else{
-                                    format!("Registration existing. \nYour authentication token is available.")
+                                let msg = if registration.revoked {
+                                    format!("Your authentication token is revoked. \nRegister again to get a new token.")
+                                } else if registration.expires <= Utc::now().timestamp() {
+                                    format!("Your authentication token expired. \nRegister again to get a new token.")
+                                } else {
+                                    format!("Registration existing. \nYour authentication token can not be shown again, register again to get a new token.")
                                 };
-                                insert_notify(db, vec![msg],vec![vec![vec![("Login".to_string(), format!("https://libreai.de/public/login.html?user_id={}&password={}",registration.user_hash,registration.token))]]], registration.user_hash);
+                                insert_notify(db, vec![msg], Vec::new(), registration.user_hash);
                             }
                             _ => {}
                         }
                     }
                 }
+                QueryPart::WebhookQueryPart(_) => {
+                    if let Some(user_hash) = n.query.settings_part.user_hash {
+                        let msg = match n.entries.first() {
+                            Some(CosmosRustBotValue::Webhook(webhook)) if webhook.url.is_empty() => "Webhook removed.".to_string(),
+                            Some(CosmosRustBotValue::Webhook(webhook)) => format!("Webhook registered.\nNotifications for your subscriptions are also sent to {}", webhook.url),
+                            _ => "Invalid webhook URL.".to_string(),
+                        };
+                        insert_notify(db, vec![msg], Vec::new(), user_hash);
+                    }
+                }
+                // answered to the login page, nothing to notify.
+                QueryPart::LoginQueryPart(_) => {}
+                QueryPart::ChannelQueryPart(query_part) => {
+                    if let Some(user_hash) = n.query.settings_part.user_hash {
+                        let accepted = n.entries.iter().any(|x| matches!(x, CosmosRustBotValue::ChannelChanged(_)));
+                        let result = if accepted { channel::channel_config(&query_part) } else { Err(anyhow::anyhow!("Error: rejected by the query service")) };
+                        let msg = match result.and_then(|config| channel::set_channel(db, user_hash, config)) {
+                            Ok(()) => format!("Notifications are now delivered via {}.", query_part.name()),
+                            Err(err) => {
+                                info!("Channel change of user {} failed: {}", user_hash, err.to_string());
+                                "Invalid channel settings.".to_string()
+                            },
+                        };
+                        insert_notify(db, vec![msg], Vec::new(), user_hash);
+                    }
+                }
                 QueryPart::AuthQueryPart(_) => {
                     for i in 0..n.entries.len() {
                         match &n.entries[i] {
@@ -185,7 +271,7 @@ pub fn notify_sled_db(db: &sled::Db, notification: CosmosRustServerValue) {
                                 let msg = if auth.is_authorized {
                                     format!("Your auth token is valid.")
                                 }else{
-                                    format!("Your auth token is invalid.")
+                                    format!("Your auth token is invalid, expired or revoked.")
                                 };
                                 insert_notify(db, vec![msg],Vec::new(), auth.user_hash);
                             }
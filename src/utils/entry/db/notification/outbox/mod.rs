@@ -0,0 +1,232 @@
+use crate::utils::entry::*;
+use cosmos_rust_package::chrono::Utc;
+use std::thread::JoinHandle;
+use log::{info, warn};
+use super::channel::get_channel;
+
+use serde::{Serialize,Deserialize};
+
+// Delivery state of the Notify records in the notification db.
+//
+// Every Notify gets an outbox item when it is inserted. The frontend (or the delivery task of the
+// user's channel, see `channel`) claims the items that are due,
+// sends them and acknowledges the result with a `NotifyAck`. A claimed item is due again after its
+// retry delay, so a message that was neither acknowledged nor rejected is retried as well.
+
+const OUTBOX_TREE: &str = "notify_outbox";
+// delivery keys of the subscription updates that were handed off, see `Notification::delivery_key`,
+// pruned by `spawn_delivery_key_retention_task`.
+const DELIVERY_KEY_TREE: &str = "notify_delivery_keys";
+
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+const BASE_RETRY_DELAY_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_SECONDS: i64 = 6 * 60 * 60;
+// sent and failed items are kept this long, so that a late acknowledgement is still recognized.
+const OUTBOX_RETENTION_SECONDS: i64 = 7 * 24 * 60 * 60;
+const DELIVERY_KEY_RETENTION_INTERVAL_SECONDS: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum DeliveryStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct OutboxItem {
+    pub notify_key: Vec<u8>,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+    pub updated: i64,
+}
+
+impl TryFrom<Vec<u8>> for OutboxItem {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<OutboxItem> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: OutboxItem) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+/// Exponential backoff: 30s, 1m, 2m, .. capped at 6h.
+pub fn retry_delay(attempts: u32) -> i64 {
+    let exponent = attempts.saturating_sub(1).min(20);
+    (BASE_RETRY_DELAY_SECONDS << exponent).min(MAX_RETRY_DELAY_SECONDS)
+}
+
+fn outbox_tree(db: &sled::Db) -> sled::Tree {
+    db.open_tree(OUTBOX_TREE).unwrap()
+}
+
+fn insert_item(tree: &sled::Tree, item: OutboxItem) -> anyhow::Result<()> {
+    let key = item.notify_key.clone();
+    let value: Vec<u8> = item.try_into()?;
+    tree.insert(key, value)?;
+    Ok(())
+}
+
+pub fn get_outbox_item(db: &sled::Db, notify_key: &[u8]) -> Option<OutboxItem> {
+    outbox_tree(db).get(notify_key).ok()?.and_then(|v| v.to_vec().try_into().ok())
+}
+
+/// Adds a pending outbox item for the Notify, unless it already has one.
+pub fn enqueue(db: &sled::Db, notify_key: &[u8]) {
+    let tree = outbox_tree(db);
+    if let Ok(false) = tree.contains_key(notify_key) {
+        let now = Utc::now().timestamp();
+        insert_item(&tree, OutboxItem {
+            notify_key: notify_key.to_vec(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+            updated: now,
+        }).ok();
+    }
+}
+
+/// True the first time the delivery key is seen, a subscription update that is sent again
+/// (e.g. because the query service restarted before recording it as notified) is not delivered twice.
+pub fn accept_delivery(db: &sled::Db, delivery_key: u64) -> bool {
+    let tree = match db.open_tree(DELIVERY_KEY_TREE) {
+        Ok(tree) => tree,
+        Err(_) => { return true; }
+    };
+    let now = Utc::now().timestamp();
+    matches!(tree.compare_and_swap(delivery_key.to_be_bytes(), None as Option<&[u8]>, Some(&now.to_be_bytes()[..])), Ok(Ok(())))
+}
+
+/// Removes the delivery keys older than `OUTBOX_RETENTION_SECONDS`.
+pub fn prune_delivery_keys(db: &sled::Db, now: i64) -> anyhow::Result<usize> {
+    let tree = db.open_tree(DELIVERY_KEY_TREE)?;
+    let mut count = 0;
+    for (key, value) in tree.iter().filter_map(|x| x.ok()) {
+        let accepted = value.as_ref().try_into().map(i64::from_be_bytes).unwrap_or(0);
+        if now - accepted > OUTBOX_RETENTION_SECONDS {
+            tree.remove(key)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+pub fn spawn_delivery_key_retention_task(db: &sled::Db) -> JoinHandle<()> {
+    info!("Spawning delivery key retention task");
+    let db = db.clone();
+    std::thread::spawn(move || {
+        loop {
+            match prune_delivery_keys(&db, Utc::now().timestamp()) {
+                Ok(count) if count > 0 => info!("Removed {} delivery keys.", count),
+                Err(err) => info!("Unable to prune the delivery keys: {}", err.to_string()),
+                _ => {},
+            }
+            std::thread::sleep(std::time::Duration::from_secs(DELIVERY_KEY_RETENTION_INTERVAL_SECONDS));
+        }
+    })
+}
+
+/// Returns the Notify records due for delivery through Telegram, i.e. of users without another channel.
+pub fn claim_due(db: &sled::Db) -> Vec<Notify> {
+    claim_due_where(db, |notify| get_channel(db, notify.user_hash).is_none())
+}
+
+/// Returns the Notify records due for delivery that match the filter and counts the attempt.
+/// Items that reached `MAX_DELIVERY_ATTEMPTS` are marked as failed instead.
+pub fn claim_due_where<F>(db: &sled::Db, filter: F) -> Vec<Notify>
+    where F: Fn(&Notify) -> bool
+{
+    let tree = outbox_tree(db);
+    let now = Utc::now().timestamp();
+    let mut due = Vec::new();
+    for mut item in tree.iter().values().filter_map(|x| x.ok()).filter_map(|v| OutboxItem::try_from(v.to_vec()).ok()) {
+        if item.status != DeliveryStatus::Pending {
+            if now - item.updated > OUTBOX_RETENTION_SECONDS {
+                tree.remove(&item.notify_key).ok();
+            }
+            continue;
+        }
+        if item.next_attempt > now {
+            continue;
+        }
+        let notify = match db.get(&item.notify_key).ok().flatten().map(|v| CosmosRustServerValue::try_from(v.to_vec())) {
+            Some(Ok(CosmosRustServerValue::Notify(notify))) => notify,
+            _ => {
+                // the Notify itself is gone, nothing left to deliver.
+                tree.remove(&item.notify_key).ok();
+                continue;
+            }
+        };
+        if !filter(&notify) {
+            continue;
+        }
+        item.updated = now;
+        if item.attempts >= MAX_DELIVERY_ATTEMPTS {
+            warn!("Giving up on notification for user {} after {} attempts.", notify.user_hash, item.attempts);
+            item.status = DeliveryStatus::Failed;
+        } else {
+            item.attempts += 1;
+            item.next_attempt = now + retry_delay(item.attempts);
+            due.push(notify);
+        }
+        insert_item(&tree, item).ok();
+    }
+    due
+}
+
+/// Records the result reported by the frontend. A rejected message is retried after its retry delay.
+pub fn acknowledge(db: &sled::Db, ack: &NotifyAck) -> anyhow::Result<()> {
+    let tree = outbox_tree(db);
+    let mut item = match tree.get(&ack.notify_key)?.map(|v| OutboxItem::try_from(v.to_vec())) {
+        Some(item) => item?,
+        None => { return Err(anyhow::anyhow!("Error: no outbox item for the acknowledged notification.")); }
+    };
+    if item.status != DeliveryStatus::Pending {
+        // acknowledged twice, or after it was given up on.
+        return Ok(());
+    }
+    item.updated = Utc::now().timestamp();
+    if ack.delivered {
+        item.status = DeliveryStatus::Sent;
+        item.last_error = None;
+    } else {
+        info!("Notification delivery failed (attempt {}): {:?}", item.attempts, ack.error);
+        item.last_error = ack.error.to_owned();
+    }
+    insert_item(&tree, item)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{accept_delivery, prune_delivery_keys, retry_delay, OUTBOX_RETENTION_SECONDS};
+    use cosmos_rust_package::chrono::Utc;
+
+    #[test]
+    pub fn exponential_backoff() {
+        assert_eq!(retry_delay(1), 30);
+        assert_eq!(retry_delay(2), 60);
+        assert_eq!(retry_delay(5), 480);
+        assert_eq!(retry_delay(12), 6 * 60 * 60);
+        assert_eq!(retry_delay(u32::MAX), 6 * 60 * 60);
+    }
+
+    #[test]
+    pub fn delivery_keys_are_accepted_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert!(accept_delivery(&db, 1));
+        assert!(!accept_delivery(&db, 1));
+        assert!(accept_delivery(&db, 2));
+
+        assert_eq!(prune_delivery_keys(&db, Utc::now().timestamp()).unwrap(), 0);
+        assert_eq!(prune_delivery_keys(&db, Utc::now().timestamp() + OUTBOX_RETENTION_SECONDS + 1).unwrap(), 2);
+        assert!(accept_delivery(&db, 1));
+    }
+}
@@ -1,30 +1,35 @@
 use crate::utils::entry::db::notification::notify_sled_db;
+use crate::utils::entry::db::migration;
 use crate::utils::entry::CosmosRustServerValue;
+use crate::utils::config::Config;
 
 use std::collections::HashSet;
-use super::super::socket::{client_send_request, Handler, spawn_socket_service};
-use std::thread::JoinHandle;
+use super::super::socket::{client_send_request, Handler, SocketServiceConfig, SocketServiceHandle, spawn_socket_service};
+use std::sync::Arc;
 use log::info;
 
 use serde::{Serialize,Deserialize};
 
-pub fn spawn_socket_notification_server(socket_path: &str, tree: &sled::Db) -> JoinHandle<()> {
+// the notification db is migrated to stable user hashes before the first notification is accepted.
+pub fn spawn_socket_notification_server(socket_path: &str, tree: &sled::Db, config: Arc<Config>) -> anyhow::Result<SocketServiceHandle> {
+    migration::migrate_notification_db(tree, &migration::legacy_user_hashes(tree))?;
     info!("Spawning Unix domain socket Notification server at '{}'", socket_path);
-    let task = spawn_socket_service(socket_path, Box::new(NotificationHandler{tree:tree.clone()}) as Box<dyn Handler + Send>);
+    let handle = spawn_socket_service(socket_path, Arc::new(NotificationHandler{tree:tree.clone(), config}), SocketServiceConfig::default())?;
     info!("Spawned Unix domain socket Notification server ready");
-    task
+    Ok(handle)
 }
 pub struct NotificationHandler
 {
     pub tree: sled::Db,
+    pub config: Arc<Config>,
 }
 impl Handler for NotificationHandler
 {
-    fn process(&mut self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    fn process(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
 
         let request: CosmosRustServerValue = bytes.try_into()?;
 
-        notify_sled_db(&self.tree, request);
+        notify_sled_db(&self.tree, &self.config, request);
 
         let result: Vec<u8> = NotifyResult{}.try_into()?;
         Ok(result)
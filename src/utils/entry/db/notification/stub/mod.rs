@@ -0,0 +1,95 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread::JoinHandle;
+
+// Local HTTP stub and SMTP sink for the delivery client tests.
+
+/// Answers a single request with the given status line and body, returns the base url
+/// and a handle that yields the raw request.
+pub fn spawn_stub(status: &'static str, response_body: &'static str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head.lines()
+                    .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|x| x.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+            if n == 0 {
+                break;
+            }
+        }
+        stream.write_all(format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, response_body.len(), response_body).as_bytes()).unwrap();
+        String::from_utf8_lossy(&request).to_string()
+    });
+    (url, handle)
+}
+
+pub fn header(request: &str, name: &str) -> Option<String> {
+    request.lines()
+        .find_map(|l| l.split_once(':').filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.trim().to_string()))
+}
+
+pub fn request_line(request: &str) -> String {
+    request.lines().next().unwrap_or("").to_string()
+}
+
+pub fn body(request: &str) -> String {
+    request.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default()
+}
+
+/// Accepts a single SMTP session, returns the port and a handle that yields the commands
+/// and the message data.
+pub fn spawn_smtp_sink() -> (u16, JoinHandle<(Vec<String>, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut commands = Vec::new();
+        let mut data = String::new();
+        stream.write_all(b"220 localhost ESMTP sink\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let command = line.trim_end().to_string();
+            commands.push(command.clone());
+            let verb = command.split(' ').next().unwrap_or("").to_uppercase();
+            let reply = match verb.as_str() {
+                "EHLO" => "250-localhost\r\n250 AUTH PLAIN\r\n",
+                "AUTH" => "235 2.7.0 Authentication successful\r\n",
+                "DATA" => {
+                    stream.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").unwrap();
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap() == 0 || line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    "250 2.0.0 Ok: queued\r\n"
+                },
+                "QUIT" => {
+                    stream.write_all(b"221 2.0.0 Bye\r\n").unwrap();
+                    break;
+                },
+                _ => "250 2.0.0 Ok\r\n",
+            };
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+        (commands, data)
+    });
+    (port, handle)
+}
@@ -0,0 +1,293 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::SubscriptionStore;
+use crate::utils::entry::db::notification::outbox::{retry_delay, MAX_DELIVERY_ATTEMPTS};
+use crate::utils::hash::{stable_hash, to_hex};
+use cosmos_rust_package::chrono::Utc;
+use cosmos_rust_package::tokio;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use serde::{Serialize,Deserialize};
+
+// Webhook delivery channel.
+//
+// Subscription notifications of users with a registered `Webhook` are POSTed as JSON.
+// The receiver verifies `X-CRB-Signature`, the hex encoded HMAC-SHA256 of "{timestamp}.{body}"
+// with the shared secret, and rejects requests with an old `X-CRB-Timestamp`.
+// Deliveries are queued in the subscription store and retried with a backoff.
+// Webhooks may only point to public addresses: the host is resolved when the webhook is registered
+// and again before every delivery, which then connects to the checked address and does not follow redirects.
+
+const WEBHOOK_OUTBOX_TREE: &str = "webhook_outbox";
+const WEBHOOK_CHECK_INTERVAL_SECONDS: u64 = 10;
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+pub const SIGNATURE_HEADER: &str = "X-CRB-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-CRB-Timestamp";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WebhookDelivery {
+    pub user_hash: u64,
+    pub body: String,
+    pub attempts: u32,
+    pub next_attempt: i64,
+}
+
+impl TryFrom<Vec<u8>> for WebhookDelivery {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<WebhookDelivery> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: WebhookDelivery) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of "{timestamp}.{body}".
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(format!("{}.{}", timestamp, body).as_bytes());
+    to_hex(hmac.result().code())
+}
+
+// loopback, link-local, private (incl. unique local and CGNAT), unspecified, broadcast and multicast addresses.
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_link_local() || ip.is_private() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+                || a == 0 || (a == 100 && (64..128).contains(&b)))
+        },
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(&IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (first & 0xffc0) == 0xfe80) // link-local fe80::/10
+        },
+    }
+}
+
+/// Resolves the host of the webhook url, an error if it is not a http(s) url or any address is not public.
+pub fn resolve_url(url: &str) -> anyhow::Result<(String, Vec<SocketAddr>)> {
+    if url.len() > 2048 {
+        return Err(anyhow::anyhow!("Error: webhook url too long"));
+    }
+    let parsed = reqwest::Url::parse(url)?;
+    if parsed.scheme() != "https" && parsed.scheme() != "http" {
+        return Err(anyhow::anyhow!("Error: webhook url must be http(s)"));
+    }
+    let host = parsed.host_str().ok_or(anyhow::anyhow!("Error: webhook url without host"))?.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let addrs = (host.as_str(), port).to_socket_addrs()?.collect::<Vec<SocketAddr>>();
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("Error: webhook host {} did not resolve", host));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(&addr.ip())) {
+        return Err(anyhow::anyhow!("Error: webhook host {} resolves to the non-public address {}", host, addr.ip()));
+    }
+    Ok((host, addrs))
+}
+
+pub fn is_valid_url(url: &str) -> bool {
+    resolve_url(url).is_ok()
+}
+
+// a client that connects to the checked addresses of the url only and does not follow redirects.
+// Also used for the user supplied urls of other channels, see `channel::send`.
+pub fn pinned_client(url: &str, timeout_seconds: u64) -> anyhow::Result<reqwest::Client> {
+    let (host, addrs) = resolve_url(url)?;
+    Ok(reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(timeout_seconds))
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, addrs[0])
+        .build()?)
+}
+
+pub fn get_webhook(store: &SubscriptionStore, user_hash: u64) -> Option<Webhook> {
+    match store.0.db.get(Webhook::get_key_for_user_hash(user_hash)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::Webhook(webhook))) => Some(webhook),
+        _ => None,
+    }
+}
+
+/// JSON body of a notification, one object per entry.
+pub fn payload(query: &UserQuery, entries: &Vec<CosmosRustBotValue>, timestamp: i64) -> String {
+    let (message, display) = match &query.query_part {
+        QueryPart::EntriesQueryPart(query_part) => (query_part.message.to_owned(), query_part.display.to_owned()),
+        _ => (String::new(), "default".to_string()),
+    };
+    let entries = entries.iter().filter_map(|entry| match entry {
+        CosmosRustBotValue::Entry(Entry::Value(Value { timestamp, custom_data, .. })) => Some(serde_json::json!({
+            "timestamp": timestamp,
+            "text": custom_data.display(&display),
+            "link": custom_data.view_in_browser(),
+            "command": custom_data.command(&display),
+        })),
+        _ => None,
+    }).collect::<Vec<serde_json::Value>>();
+    serde_json::json!({
+        "subscription": message,
+        "timestamp": timestamp,
+        "entries": entries,
+    }).to_string()
+}
+
+fn outbox_tree(store: &SubscriptionStore) -> sled::Tree {
+    store.0.db.open_tree(WEBHOOK_OUTBOX_TREE).unwrap()
+}
+
+fn insert_delivery(tree: &sled::Tree, key: &[u8], delivery: WebhookDelivery) {
+    if let Ok(value) = TryInto::<Vec<u8>>::try_into(delivery) {
+        tree.insert(key, value).ok();
+    }
+}
+
+/// Queues the notification for every user in the list that has a webhook.
+pub fn enqueue(store: &SubscriptionStore, user_list: &HashSet<u64>, query: &UserQuery, entries: &Vec<CosmosRustBotValue>) {
+    let tree = outbox_tree(store);
+    let now = Utc::now().timestamp();
+    let body = payload(query, entries, now);
+    for user_hash in user_list {
+        if get_webhook(store, *user_hash).is_some() {
+            // ordered by time of creation.
+            let mut key = now.to_be_bytes().to_vec();
+            key.append(&mut stable_hash(&(user_hash, &body)).to_be_bytes().to_vec());
+            insert_delivery(&tree, &key, WebhookDelivery { user_hash: *user_hash, body: body.to_owned(), attempts: 0, next_attempt: now });
+        }
+    }
+}
+
+/// POSTs the signed body, any non 2xx status is an error.
+pub async fn post(client: &reqwest::Client, webhook: &Webhook, body: &str, timestamp: i64) -> anyhow::Result<()> {
+    let response = client.post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(&webhook.secret, timestamp, body))
+        .body(body.to_string())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!("Error: webhook responded with status {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Delivers the queued notifications that are due. A delivery is dropped once the webhook is removed
+/// or after `MAX_DELIVERY_ATTEMPTS`. The url is checked again, the address of the host may have changed.
+pub async fn deliver_due(store: &SubscriptionStore) {
+    let tree = outbox_tree(store);
+    let now = Utc::now().timestamp();
+    let due = tree.iter().filter_map(|x| x.ok())
+        .filter_map(|(k, v)| WebhookDelivery::try_from(v.to_vec()).ok().map(|d| (k, d)))
+        .filter(|(_, d)| d.next_attempt <= now)
+        .collect::<Vec<(sled::IVec, WebhookDelivery)>>();
+    for (key, mut delivery) in due {
+        let webhook = match get_webhook(store, delivery.user_hash) {
+            Some(webhook) => webhook,
+            None => { tree.remove(&key).ok(); continue; }
+        };
+        let result = match pinned_client(&webhook.url, WEBHOOK_TIMEOUT_SECONDS) {
+            Ok(client) => post(&client, &webhook, &delivery.body, Utc::now().timestamp()).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                tree.remove(&key).ok();
+            },
+            Err(err) => {
+                delivery.attempts += 1;
+                if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+                    warn!("Giving up on webhook delivery to {} after {} attempts: {}", webhook.url, delivery.attempts, err.to_string());
+                    tree.remove(&key).ok();
+                } else {
+                    info!("Webhook delivery to {} failed (attempt {}): {}", webhook.url, delivery.attempts, err.to_string());
+                    delivery.next_attempt = now + retry_delay(delivery.attempts);
+                    insert_delivery(&tree, &key, delivery);
+                }
+            },
+        }
+    }
+}
+
+pub fn spawn_webhook_delivery_task(store: &SubscriptionStore) -> tokio::task::JoinHandle<()> {
+    let store = SubscriptionStore::new(&store.0.db);
+    tokio::spawn(async move {
+        loop {
+            deliver_due(&store).await;
+            tokio::time::sleep(std::time::Duration::from_secs(WEBHOOK_CHECK_INTERVAL_SECONDS)).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{is_valid_url, post, sign, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+    use crate::utils::entry::Webhook;
+    use crate::utils::entry::db::notification::stub::{header, spawn_stub};
+
+    #[test]
+    pub fn hmac_signature() {
+        // HMAC-SHA256("key", "1672655400.{\"entries\":[]}")
+        assert_eq!(sign("key", 1672655400, r#"{"entries":[]}"#), "efedaeaea09a4c44e919636da0179b7bf503e0b1092c4cd5816c82a5d730af79");
+        assert_ne!(sign("key", 1, "body"), sign("key", 2, "body"));
+        assert_ne!(sign("key", 1, "body"), sign("other", 1, "body"));
+        assert_eq!(sign("key", 1, "body").len(), 64);
+    }
+
+    #[test]
+    pub fn reject_non_public_urls() {
+        assert!(is_valid_url("https://93.184.216.34/hook"));
+        assert!(is_valid_url("http://[2606:2800:220:1:248:1893:25c8:1946]:8080/hook"));
+        assert!(!is_valid_url("ftp://93.184.216.34/hook"));
+        assert!(!is_valid_url(&format!("https://93.184.216.34/{}", "a".repeat(2048))));
+        // loopback
+        assert!(!is_valid_url("http://127.0.0.1:8080/hook"));
+        assert!(!is_valid_url("http://localhost/hook"));
+        assert!(!is_valid_url("http://[::1]/hook"));
+        // link-local, e.g. cloud metadata
+        assert!(!is_valid_url("http://169.254.169.254/latest/meta-data"));
+        assert!(!is_valid_url("http://[fe80::1]/hook"));
+        // private
+        assert!(!is_valid_url("http://10.0.0.1/hook"));
+        assert!(!is_valid_url("http://172.16.0.1/hook"));
+        assert!(!is_valid_url("http://192.168.1.1/hook"));
+        assert!(!is_valid_url("http://[fd00::1]/hook"));
+        assert!(!is_valid_url("http://[::ffff:192.168.1.1]/hook"));
+        // unspecified
+        assert!(!is_valid_url("http://0.0.0.0/hook"));
+        assert!(!is_valid_url("http://[::]/hook"));
+    }
+
+    #[test]
+    pub fn post_signed_body_to_stub() {
+        let runtime = cosmos_rust_package::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let client = reqwest::Client::new();
+
+        let (url, stub) = spawn_stub("200 OK", "");
+        let webhook = Webhook { user_hash: 1, url: format!("{}/hook", url), secret: "secret".to_string() };
+        let body = r#"{"subscription":"gov prpsl all","entries":[]}"#;
+        assert!(runtime.block_on(post(&client, &webhook, body, 1672655400)).is_ok());
+
+        let request = stub.join().unwrap();
+        assert!(request.ends_with(body));
+        assert_eq!(header(&request, TIMESTAMP_HEADER).unwrap(), "1672655400");
+        assert_eq!(header(&request, SIGNATURE_HEADER).unwrap(), sign("secret", 1672655400, body));
+
+        let (url, stub) = spawn_stub("500 Internal Server Error", "");
+        let webhook = Webhook { url: format!("{}/hook", url), ..webhook };
+        assert!(runtime.block_on(post(&client, &webhook, body, 1672655400)).is_err());
+        stub.join().unwrap();
+    }
+}
@@ -1,21 +1,83 @@
 pub mod socket;
 
 use crate::utils::entry::db::CosmosRustBotStore;
+use crate::utils::entry::db::quota;
+use crate::utils::entry::db::auth;
+use crate::utils::entry::db::access;
+use crate::utils::entry::db::audit;
+use crate::utils::entry::db::notification::webhook;
+use crate::utils::entry::db::notification::channel;
 use crate::utils::entry::*;
-use rand::{Rng, thread_rng};
+use crate::utils::entry::filter::FilterPredicate;
+use crate::utils::entry::search::rank;
+use crate::utils::entry::search::vector::VectorIndex;
+use crate::utils::hash::stable_hash;
+#[cfg(feature = "interface")]
+use rust_openai_gpt_tools_socket_ipc::ipc::{client_send_openai_gpt_embedding_request, OpenAIGPTResult};
+use cosmos_rust_package::chrono::Utc;
+use log::error;
 
 
+const MAX_CACHED_TEXT_EMBEDDINGS: usize = 1024;
+
 pub struct CosmosRustBotStoreInquirer<'a>(pub &'a CosmosRustBotStore);
 
 
 impl <'a>CosmosRustBotStoreInquirer<'a> {
 
+    /// Query of a user, subject to quotas and the role of the user. Every query is audited.
     pub fn query(&mut self, query: &UserQuery) -> Vec<CosmosRustBotValue> {
+        let result = self.query_with_access_control(query);
+        let (action, summary) = audit::describe_query(query, &result);
+        audit::record(&self.0.subscription_store.0.db, query.settings_part.user_hash, action, summary);
+        result
+    }
+
+    fn query_with_access_control(&mut self, query: &UserQuery) -> Vec<CosmosRustBotValue> {
+
+        // anonymous queries count towards the global quota.
+        if let Err(exceeded) = quota::count_query(&self.0.subscription_store, query.settings_part.user_hash) {
+            return vec![CosmosRustBotValue::QuotaExceeded(exceeded)];
+        }
+
+        let role = access::get_role(&self.0.subscription_store, &query.settings_part);
+        if let Err(denied) = access::check_query(role, query) {
+            return vec![CosmosRustBotValue::AccessDenied(denied)];
+        }
+
+        let mut result = self.execute(query);
+        access::retain_visible(role, &mut result);
+        result
+    }
+
+    /// The result of the user query as the Notification returned by the query services.
+    pub fn answer(&mut self, mut query: UserQuery) -> Notification {
+        let entries = self.query(&query);
+        // the token is not passed on to the notification.
+        query.settings_part.token = None;
+        let mut notification = Notification {
+            query,
+            entries,
+            user_list: HashSet::new(),
+            schedules: HashMap::new(),
+            delivery_key: None,
+        };
+        // anonymous queries have no user to notify.
+        if let Some(user_hash) = notification.query.settings_part.user_hash {
+            notification.add_user_hash(user_hash);
+        }
+        notification
+    }
+
+    /// Runs the query without quota and access checks, e.g. to update subscriptions.
+    pub fn execute(&mut self, query: &UserQuery) -> Vec<CosmosRustBotValue> {
 
         match &query.query_part {
             QueryPart::EntriesQueryPart(query_part) => {
                 let result = self.entries_query(query_part);
-                self.subscribe_unsubscribe_for_user(&result,query_part,&query.settings_part);
+                if let Err(exceeded) = self.subscribe_unsubscribe_for_user(&result,query_part,&query.settings_part) {
+                    return vec![CosmosRustBotValue::QuotaExceeded(exceeded)];
+                }
                 result
             },
             QueryPart::SubscriptionsQueryPart(query_part) => {
@@ -27,11 +89,19 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
             QueryPart::AuthQueryPart(query_part) => {
                 self.verify_auth_token(query_part)
             }
+            QueryPart::WebhookQueryPart(query_part) => {
+                self.register_webhook_for_user(query_part, &query.settings_part)
+            }
+            QueryPart::LoginQueryPart(query_part) => {
+                self.login_with_code(query_part)
+            }
+            QueryPart::ChannelQueryPart(query_part) => {
+                self.check_channel_for_user(query_part, &query.settings_part)
+            }
             /*
             QueryPart::RequestTranslationQueryPart(query_part) => {
 
             }
-            requests if the user is allowed / limits quota (user management)
             */
         }
     }
@@ -47,38 +117,137 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
         // Initialize the order_by_index to None
         let mut order_by_index: Option<Vec<Vec<u8>>> = None;
 
-        // Get all the indices from the index store and check if the index applies to the query.
-        // If it does, add the index's list to the indices_list and remove the unnecessary filters for the index.
-        for index in self.0.index_store.get_indices().filter_map(|x| if let CosmosRustBotValue::Index(index) = x { Some(index)}else{None}) {
-
-            if query_part.indices.contains(&index.name) {
-                indices_list.push(index.list.clone());
+        // Look up the indices of the query by name, a sorted index selects all entries having its field.
+        // Remove the unnecessary filters for each index found.
+        for name in &query_part.indices {
+            let list = match self.0.index_store.get_index(name) {
+                Some(index) => Some(index.list),
+                None => self.0.index_store.get_sorted(name, &OrderDirection::Desc),
+            };
+            if let Some(list) = list {
+                indices_list.push(list);
 
                 for i in 0..filter.len() {
                     // filter_unnecessary
-                    filter[i].retain(|(k, v)| format!("{}_{}", k, v) != index.name);
+                    filter[i].retain(|(k, v)| &format!("{}_{}", k, v) != name);
                 }
             }
+        }
 
-            // set order_by_index to the list of the index with the same name as order_by
-            if &index.name == &query_part.order_by {
-                order_by_index = Some(index.list.clone());
-            }
+        // indices are stored in descending order, see below.
+        if !query_part.order_by.is_empty() {
+            order_by_index = match self.0.index_store.get_sorted(&query_part.order_by, &OrderDirection::Desc) {
+                Some(list) => Some(list),
+                None => self.0.index_store.get_index(&query_part.order_by).map(|index| index.list),
+            };
         }
 
         let mut selection: Vec<Vec<u8>> = retain_common_elements_in_list(indices_list);
 
-        // If order_by_index is present, sort the selection by order_by_index
-        if let Some(ord) = order_by_index {
-            selection = sort_by_index(selection,ord);
+        // search and semantic queries rank the selection themselves, otherwise it is sorted by order_by_index.
+        if query_part.search.is_none() && query_part.semantic.is_none() {
+            if let Some(ord) = order_by_index {
+                selection = sort_by_index(selection, ord, &query_part.order_direction);
+            }
+        }
+
+        if let Some(search) = &query_part.search {
+            // ranked by relevance, restricted to the selected indices if any are given.
+            let selected: HashSet<Vec<u8>> = selection.into_iter().collect();
+            let index_store = &self.0.index_store;
+            selection = rank(search, &index_store.get_search_document_lengths(), |term| index_store.get_search_term_postings(term))
+                .into_iter()
+                .map(|(key, _score)| key)
+                .filter(|key| query_part.indices.is_empty() || selected.contains(key))
+                .collect();
         }
 
-        selection.into_iter().filter_map(|key| self.0.entry_store.0.db.get(&key).ok().and_then(|item| item.map(|x| x.to_vec().try_into().ok()).flatten())).filter(|item|{
+        if let Some(semantic) = &query_part.semantic {
+            // ranked by similarity, restricted to the previous selection if indices or a search are given.
+            let restrict = !query_part.indices.is_empty() || query_part.search.is_some();
+            let selected: HashSet<Vec<u8>> = selection.into_iter().collect();
+            selection = self.semantic_ranking(semantic).into_iter()
+                .filter(|key| !restrict || selected.contains(key))
+                .collect();
+        }
+
+        // invalid predicates (e.g. a malformed regex) never match.
+        let filter: Vec<Vec<(String,Option<FilterPredicate>)>> = filter.into_iter().map(|f| f.into_iter().map(|(k,v)| (k, FilterPredicate::parse(&v).ok())).collect()).collect();
+
+        let mut result: Vec<CosmosRustBotValue> = selection.into_iter().filter_map(|key| self.0.entry_store.0.db.get(&key).ok().and_then(|item| item.map(|x| x.to_vec().try_into().ok()).flatten())).filter(|item|{
             filter.is_empty() || matches_filter(&item, &filter)
-        }).take(query_part.limit).collect()
+        }).collect();
+
+        if !query_part.order.is_empty() {
+            // stable sort, entries with equal keys keep the order of the index.
+            result.sort_by(|a, b| compare_by_order(a, b, &query_part.order));
+        }
+
+        match paginate(result, query_part) {
+            Ok(page) => page,
+            Err(expired) => vec![CosmosRustBotValue::CursorExpired(expired)],
+        }
     }
 
-    fn subscribe_unsubscribe_for_user(&mut self, query_result: &Vec<CosmosRustBotValue>, query_part: &EntriesQueryPart, settings_part: &SettingsPart) {
+    // entry keys ordered by the similarity of their embedding to the query, the reference entry of `SimilarTo` is excluded.
+    fn semantic_ranking(&self, semantic: &SemanticQuery) -> Vec<Vec<u8>> {
+        let (query, exclude) = match semantic {
+            SemanticQuery::SimilarTo(reference) => {
+                let reference: Vec<Vec<(String,Option<FilterPredicate>)>> = vec![reference.iter().map(|(k,v)| (k.to_owned(), FilterPredicate::parse(v).ok())).collect()];
+                let entry_key = self.0.entry_store.0.db.scan_prefix(Entry::get_prefix()).values()
+                    .filter_map(|x| x.ok())
+                    .filter_map(|v| CosmosRustBotValue::try_from(v.to_vec()).ok())
+                    .find(|item| matches_filter(item, &reference))
+                    .map(|item| item.key());
+                match entry_key.and_then(|key| self.0.index_store.get_embedding(&key)) {
+                    Some(embedding) => (embedding.vector, Some(embedding.entry_key)),
+                    None => { return Vec::new(); }
+                }
+            },
+            SemanticQuery::Text(text) => {
+                match self.text_embedding(text) {
+                    Some(vector) => (vector, None),
+                    None => { return Vec::new(); }
+                }
+            },
+        };
+        let index = self.0.embedding_index();
+        index.nearest(&query, index.len()).into_iter()
+            .map(|(key, _similarity)| key)
+            .filter(|key| Some(key) != exclude.as_ref())
+            .collect()
+    }
+
+    // query texts are embedded once, subscriptions repeat the same query on every update.
+    fn text_embedding(&self, text: &str) -> Option<Vec<f32>> {
+        let hash = stable_hash(text);
+        if let Some(vector) = self.0.text_embeddings.lock().unwrap().get(&hash) {
+            return Some(vector.clone());
+        }
+        let vector = self.request_text_embedding(text)?;
+        let mut text_embeddings = self.0.text_embeddings.lock().unwrap();
+        if text_embeddings.len() >= MAX_CACHED_TEXT_EMBEDDINGS {
+            text_embeddings.clear();
+        }
+        text_embeddings.insert(hash, vector.clone());
+        Some(vector)
+    }
+
+    #[cfg(feature = "interface")]
+    fn request_text_embedding(&self, text: &str) -> Option<Vec<f32>> {
+        match client_send_openai_gpt_embedding_request(&self.0.config().sockets.openai_gpt_tools, vec![text.to_owned()]) {
+            Ok(OpenAIGPTResult::EmbeddingResult(mut item)) if !item.result.is_empty() => Some(item.result.remove(0)),
+            _ => None,
+        }
+    }
+
+    // text queries need the embedding service of the `interface` feature.
+    #[cfg(not(feature = "interface"))]
+    fn request_text_embedding(&self, _text: &str) -> Option<Vec<f32>> {
+        None
+    }
+
+    fn subscribe_unsubscribe_for_user(&mut self, query_result: &Vec<CosmosRustBotValue>, query_part: &EntriesQueryPart, settings_part: &SettingsPart) -> Result<(), QuotaExceeded> {
 
         if let Some(user_hash) = settings_part.user_hash {
             let subscribe = settings_part.subscribe.unwrap_or(false);
@@ -89,7 +258,11 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
                     Ok(Some(s)) => {
                         if let CosmosRustBotValue::Subscription(mut s) = s.to_vec().try_into().unwrap() {
                             if subscribe {
+                                if !s.contains_user_hash(user_hash) {
+                                    quota::check_subscription(&self.0.subscription_store, user_hash, false)?;
+                                }
                                 s.add_user_hash(user_hash);
+                                s.set_schedule(user_hash, settings_part.delivery.clone());
                                 s.action = SubscriptionAction::AddUser;
 
                                 let value: Vec<u8> = CosmosRustBotValue::Subscription(s).try_into().unwrap();
@@ -100,6 +273,7 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
                                     self.0.subscription_store.0.remove(&s_key).ok();
                                 } else {
                                     s.remove_user_hash(user_hash);
+                                    s.set_schedule(user_hash, None);
                                     s.action = SubscriptionAction::RemoveUser;
 
                                     let value: Vec<u8> = CosmosRustBotValue::Subscription(s).try_into().unwrap();
@@ -111,13 +285,16 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
                     }
                     Ok(None) => {
                         if !unsubscribe && subscribe {
+                            quota::check_subscription(&self.0.subscription_store, user_hash, true)?;
                             let mut s = Subscription {
                                 action: SubscriptionAction::Created,
                                 query: QueryPart::EntriesQueryPart(query_part.clone()),
                                 user_list: HashSet::new(),
                                 list: Vec::new(),
+                                schedules: HashMap::new(),
                             };
                             s.add_user_hash(user_hash);
+                            s.set_schedule(user_hash, settings_part.delivery.clone());
                             for e in query_result {
                                 s.list.push(e.key());
                             }
@@ -131,73 +308,106 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
                 }
             }
         }
+        Ok(())
+    }
+
+    fn get_registration(&self, user_hash: u64) -> Option<Registration> {
+        match self.0.subscription_store.0.get(Registration::get_key_for_user_hash(user_hash)) {
+            Ok(Some(v)) => match v.to_vec().try_into() {
+                Ok(CosmosRustBotValue::Registration(reg)) => Some(reg),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
+    // registering again rotates the token, the plain token is only part of this result.
     fn register_and_get_token_for_user(&mut self, settings_part: &SettingsPart) -> Vec<CosmosRustBotValue> {
 
         if let Some(user_hash) = settings_part.user_hash {
+            if let Some(true) = settings_part.revoke {
+                if let Some(mut reg) = self.get_registration(user_hash) {
+                    reg.revoked = true;
+                    let item = CosmosRustBotValue::Registration(reg);
+                    let value: Vec<u8> = item.clone().try_into().unwrap();
+                    self.0.subscription_store.0.insert(item.key(), value).ok();
+                    return vec![item];
+                }
+                return vec![];
+            }
             if let Some(true) = settings_part.register {
-                let generate_token = || {
-                    let mut rng = thread_rng();
-                    rng.gen::<u64>()
-                };
+                let now = Utc::now().timestamp();
+                let mut issued = self.store_new_token(user_hash, now);
+                match auth::store_login_code(&self.0.subscription_store.0.db, user_hash, now) {
+                    Ok(login_code) => { issued.login_code = login_code; },
+                    Err(err) => { error!("Unable to store the login code: {}", err.to_string()); },
+                }
+                return vec![CosmosRustBotValue::IssuedToken(issued)];
+            }
+
+            return self.get_registration(user_hash).map(|reg| vec![CosmosRustBotValue::Registration(reg)]).unwrap_or_default();
+        }
+        vec![]
+    }
 
-                let item = CosmosRustBotValue::Registration(Registration {
-                    token: generate_token(),
+    // the result never contains the secret.
+    fn register_webhook_for_user(&mut self, query_part: &WebhookQueryPart, settings_part: &SettingsPart) -> Vec<CosmosRustBotValue> {
+
+        if let Some(user_hash) = settings_part.user_hash {
+            let key = Webhook::get_key_for_user_hash(user_hash);
+            if query_part.url.is_empty() {
+                self.0.subscription_store.0.remove(&key).ok();
+            } else if webhook::is_valid_url(&query_part.url) && !query_part.secret.is_empty() {
+                let item = CosmosRustBotValue::Webhook(Webhook {
                     user_hash,
+                    url: query_part.url.to_owned(),
+                    secret: query_part.secret.to_owned(),
                 });
-                let key = item.key();
                 let value: Vec<u8> = item.try_into().unwrap();
-
-                self.0.subscription_store.0.insert(key, value)
-                    .ok();
-            }
-
-            let key = Registration::get_key_for_user_hash(user_hash);
-
-            return match self.0.subscription_store.0.get(key) {
-                Err(_e) => {
-                    vec![]
-                }
-                Ok(None) => {
-                    vec![]
-                }
-                Ok(Some(v)) => {
-                    let result: CosmosRustBotValue = v.to_vec().try_into().unwrap();
-                    vec![result]
-                }
+                self.0.subscription_store.0.insert(key, value).ok();
+            } else {
+                return vec![];
             }
-
+            return vec![CosmosRustBotValue::Webhook(Webhook { user_hash, url: query_part.url.to_owned(), secret: String::new() })];
         }
         vec![]
     }
 
-    fn verify_auth_token(&mut self, query_part: &AuthQueryPart) -> Vec<CosmosRustBotValue> {
+    // the channel is stored by the notification service, which receives the notification of the query.
+    fn check_channel_for_user(&mut self, query_part: &ChannelQueryPart, settings_part: &SettingsPart) -> Vec<CosmosRustBotValue> {
 
-        let key = Registration::get_key_for_user_hash(query_part.user_hash);
+        match (settings_part.user_hash, channel::channel_config(query_part)) {
+            (Some(user_hash), Ok(_)) => vec![CosmosRustBotValue::ChannelChanged(ChannelChanged { user_hash, channel: query_part.name().to_string() })],
+            _ => vec![],
+        }
+    }
 
-        return match self.0.subscription_store.0.get(key) {
-            Err(_e) => {
-                vec![CosmosRustBotValue::Authorization(Authorization{ is_authorized: false, user_hash: query_part.user_hash })]
-            }
-            Ok(None) => {
-                vec![CosmosRustBotValue::Authorization(Authorization{ is_authorized: false, user_hash: query_part.user_hash })]
-            }
-            Ok(Some(v)) => {
-                let result: CosmosRustBotValue = v.to_vec().try_into().unwrap();
+    fn store_new_token(&mut self, user_hash: u64, now: i64) -> IssuedToken {
+        let (registration, issued) = auth::issue_token(user_hash, now);
+        let item = CosmosRustBotValue::Registration(registration);
+        let key = item.key();
+        let value: Vec<u8> = item.try_into().unwrap();
+        self.0.subscription_store.0.insert(key, value).ok();
+        issued
+    }
 
-                match result {
-                    CosmosRustBotValue::Registration(reg) => {
-                        vec![CosmosRustBotValue::Authorization(Authorization{ is_authorized: reg.token == query_part.token, user_hash: query_part.user_hash })]
-                    }
-                    _ => {
-                        vec![CosmosRustBotValue::Authorization(Authorization{ is_authorized: false, user_hash: query_part.user_hash })]
-                    }
-                }
-            }
+    // the one-time code of the login link is exchanged for a new token, the previous token stops working.
+    fn login_with_code(&mut self, query_part: &LoginQueryPart) -> Vec<CosmosRustBotValue> {
+        let now = Utc::now().timestamp();
+        match auth::redeem_login_code(&self.0.subscription_store.0.db, query_part.user_hash, &query_part.code, now) {
+            Ok(true) => vec![CosmosRustBotValue::IssuedToken(self.store_new_token(query_part.user_hash, now))],
+            _ => vec![CosmosRustBotValue::Authorization(Authorization { is_authorized: false, user_hash: query_part.user_hash })],
         }
     }
 
+    fn verify_auth_token(&mut self, query_part: &AuthQueryPart) -> Vec<CosmosRustBotValue> {
+
+        let is_authorized = self.get_registration(query_part.user_hash)
+            .map(|reg| auth::verify_token(&reg, &query_part.token, Utc::now().timestamp()))
+            .unwrap_or(false);
+        vec![CosmosRustBotValue::Authorization(Authorization{ is_authorized, user_hash: query_part.user_hash })]
+    }
+
     fn opt_unsubscribe_and_get_subscriptions_for_user(&mut self, _query_part: &SubscriptionsQueryPart, settings_part: &SettingsPart) -> Vec<CosmosRustBotValue> {
         let mut res: Vec<CosmosRustBotValue> = Vec::new();
 
@@ -211,6 +421,7 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
                             if settings_part.unsubscribe.unwrap_or(false) {
                                 let mut new_subscription = subscription.clone();
                                 new_subscription.remove_user_hash(user_hash);
+                                new_subscription.set_schedule(user_hash, None);
                                 new_subscription.action = SubscriptionAction::RemoveUser;
                                 let new_val = CosmosRustBotValue::Subscription(new_subscription);
                                 let key = new_val.key();
@@ -233,41 +444,120 @@ impl <'a>CosmosRustBotStoreInquirer<'a> {
 fn retain_common_elements_in_list(mut list: Vec<Vec<Vec<u8>>>) -> Vec<Vec<u8>> {
     // filters the first vector by removing all elements that are not present in all the other vectors in the original vector.
     if list.len() > 1 {
-        let to_check = list.drain(1..).collect::<Vec<Vec<Vec<u8>>>>();
-        list[0].retain(|x| to_check.iter().fold(true, |sum, list_to_check| { list_to_check.contains(&x) && sum}));
+        let to_check = list.drain(1..).map(|x| x.into_iter().collect::<HashSet<Vec<u8>>>()).collect::<Vec<HashSet<Vec<u8>>>>();
+        list[0].retain(|x| to_check.iter().all(|list_to_check| list_to_check.contains(x)));
     }
     list.into_iter().flatten().collect()
 }
 
-fn matches_filter(entry: &CosmosRustBotValue, filter: &Vec<Vec<(String,String)>>) -> bool {
+fn matches_filter(entry: &CosmosRustBotValue, filter: &Vec<Vec<(String,Option<FilterPredicate>)>>) -> bool {
     filter
         .iter()
         .any(|f| {
             f.iter()
                 .all(|(k, v)| {
-                    if v == "any" {
-                        true
-                    }else {
-                        match entry.get(k) {
-                            serde_json::Value::String(s) => s == v.as_str(),
-                            serde_json::Value::Number(n) => &n.to_string() == v,
-                            _ => false,
-                        }
+                    match v {
+                        Some(predicate) => predicate.matches(&entry.get(k)),
+                        None => false,
                     }
                 })
         })
 }
 
-fn sort_by_index(list: Vec<Vec<u8>>, order_by: Vec<Vec<u8>>) -> Vec<Vec<u8>>  {
+// `order_by` is in descending order.
+fn sort_by_index(list: Vec<Vec<u8>>, mut order_by: Vec<Vec<u8>>, direction: &OrderDirection) -> Vec<Vec<u8>>  {
+    if direction == &OrderDirection::Asc {
+        order_by.reverse();
+    }
+    let mut list: HashSet<Vec<u8>> = list.into_iter().collect();
     let mut ordered: Vec<Vec<u8>> = Vec::new();
-    let mut unknown: Vec<Vec<u8>> = Vec::new();
     for key in order_by {
-        if list.contains(&key) {
+        if list.remove(&key) {
             ordered.push(key);
-        }else{
-            unknown.push(key);
         }
     }
+    // keys not contained in the index are appended at the end, regardless of the direction.
+    let mut unknown: Vec<Vec<u8>> = list.into_iter().collect();
+    unknown.sort_unstable();
     ordered.append(&mut unknown);
     ordered
+}
+
+fn compare_by_order(a: &CosmosRustBotValue, b: &CosmosRustBotValue, order: &Vec<OrderBy>) -> std::cmp::Ordering {
+    for order_by in order {
+        let ordering = match (a.get(&order_by.field), b.get(&order_by.field)) {
+            // entries without the field are listed last, regardless of the direction.
+            (serde_json::Value::Null, serde_json::Value::Null) => std::cmp::Ordering::Equal,
+            (serde_json::Value::Null, _) => std::cmp::Ordering::Greater,
+            (_, serde_json::Value::Null) => std::cmp::Ordering::Less,
+            (first, second) => match order_by.direction {
+                OrderDirection::Asc => compare_field_values(&first, &second),
+                OrderDirection::Desc => compare_field_values(&second, &first),
+            },
+        };
+        if ordering != std::cmp::Ordering::Equal {
+            return ordering;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+// applies cursor, offset and limit to the ordered result.
+// the cursor expires once its entry left the result (e.g. the entry changed), the query has to start over.
+fn paginate(result: Vec<CosmosRustBotValue>, query_part: &EntriesQueryPart) -> Result<Vec<CosmosRustBotValue>, CursorExpired> {
+    let start = match &query_part.cursor {
+        Some(cursor) => match result.iter().position(|x| &x.key() == cursor) {
+            Some(position) => position + 1,
+            None => { return Err(CursorExpired { cursor: cursor.to_owned() }); },
+        },
+        None => 0,
+    };
+    Ok(result.into_iter().skip(start).skip(query_part.offset).take(query_part.limit).collect())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{paginate, sort_by_index};
+    use crate::utils::entry::{CosmosRustBotValue, CursorExpired, CustomData, Debug, EntriesQueryPart, Entry, OrderDirection, Value, ValueImperative};
+
+    fn entry(key: &str) -> CosmosRustBotValue {
+        CosmosRustBotValue::Entry(Entry::Value(Value {
+            timestamp: 0,
+            origin: "test".to_string(),
+            custom_data: CustomData::Debug(Debug { key: key.to_string(), value: String::new() }),
+            imperative: ValueImperative::Notify,
+        }))
+    }
+
+    fn page(limit: usize, cursor: Option<Vec<u8>>, offset: usize) -> EntriesQueryPart {
+        let query_part: EntriesQueryPart = serde_json::from_value(serde_json::json!({"message": "", "display": "default", "indices": [], "filter": [], "order_by": "", "limit": limit})).unwrap();
+        EntriesQueryPart { cursor, offset, ..query_part }
+    }
+
+    #[test]
+    pub fn ordered_by_index_with_unindexed_keys_last() {
+        let key = |x: u8| vec![x];
+        // the index is stored in descending order.
+        let index = vec![key(3), key(2), key(1)];
+        let selection = vec![key(1), key(9), key(3), key(2), key(8)];
+        assert_eq!(sort_by_index(selection.clone(), index.clone(), &OrderDirection::Desc), vec![key(3), key(2), key(1), key(8), key(9)]);
+        assert_eq!(sort_by_index(selection, index, &OrderDirection::Asc), vec![key(1), key(2), key(3), key(8), key(9)]);
+    }
+
+    #[test]
+    pub fn pages_follow_the_cursor() {
+        let result = (1..=5).map(|i| entry(&i.to_string())).collect::<Vec<CosmosRustBotValue>>();
+        let first = paginate(result.clone(), &page(2, None, 0)).unwrap();
+        assert_eq!(first, result[..2].to_vec());
+        let second = paginate(result.clone(), &page(2, Some(first[1].key()), 0)).unwrap();
+        assert_eq!(second, result[2..4].to_vec());
+        let skipped = paginate(result.clone(), &page(2, Some(first[1].key()), 1)).unwrap();
+        assert_eq!(skipped, result[3..5].to_vec());
+        assert!(paginate(result.clone(), &page(2, Some(result[4].key()), 0)).unwrap().is_empty());
+
+        // the entry of the cursor left the result.
+        let gone = entry("6").key();
+        assert_eq!(paginate(result, &page(2, Some(gone.clone()), 0)), Err(CursorExpired { cursor: gone }));
+    }
 }
\ No newline at end of file
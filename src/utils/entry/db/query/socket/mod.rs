@@ -1,15 +1,15 @@
-use crate::utils::entry::{CosmosRustServerValue, Notification, UserQuery};
-use std::collections::HashSet;
-use super::super::socket::{client_send_request, Handler, spawn_socket_service};
-use std::thread::JoinHandle;
+use crate::utils::entry::{CosmosRustServerValue, UserQuery};
+use super::super::socket::{client_send_request, Handler, SocketServiceConfig, SocketServiceHandle, spawn_socket_service};
+use std::sync::Arc;
 use crate::utils::entry::db::CosmosRustBotStore;
 use crate::utils::entry::db::query::CosmosRustBotStoreInquirer;
+use log::debug;
 
-pub fn spawn_socket_query_server(socket_path: &str, cosmos_rust_bot_store: &CosmosRustBotStore) -> JoinHandle<()> {
+pub fn spawn_socket_query_server(socket_path: &str, cosmos_rust_bot_store: &CosmosRustBotStore) -> anyhow::Result<SocketServiceHandle> {
     println!("Starting socket query server at path: {}", socket_path);
-    let task = spawn_socket_service(socket_path,Box::new(QueryHandler::new(cosmos_rust_bot_store)) as Box<dyn Handler + Send>);
+    let handle = spawn_socket_service(socket_path, Arc::new(QueryHandler::new(cosmos_rust_bot_store)), SocketServiceConfig::default())?;
     println!("Socket query server is ready and listening for incoming connections");
-    task
+    Ok(handle)
 }
 pub struct QueryHandler
 {
@@ -25,18 +25,13 @@ impl QueryHandler {
 }
 impl Handler for QueryHandler
 {
-    fn process(&mut self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    fn process(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
         println!("Processing user query");
         let user_query: UserQuery = UserQuery::try_from(bytes)?;
-        println!("Received user query: {:?}", user_query);
-        let entries = CosmosRustBotStoreInquirer(&self.cosmos_rust_bot_store).query(&user_query);
-        let mut notification = Notification {
-            query: user_query,
-            entries,
-            user_list: HashSet::new(),
-        };
-        notification.add_user_hash(notification.query.settings_part.user_hash.unwrap());
-        println!("Notification created with query: {:?}", notification.query);
+        // Display, the Debug output contains tokens and secrets.
+        debug!("Received user query: {}", user_query.query_part);
+        let notification = CosmosRustBotStoreInquirer(&self.cosmos_rust_bot_store).answer(user_query);
+        debug!("Notification created with query: {}", notification.query.query_part);
         let result: Vec<u8> = CosmosRustServerValue::Notification(notification).try_into()?;
         println!("Processed user query successfully");
         Ok(result)
@@ -0,0 +1,254 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::SubscriptionStore;
+use cosmos_rust_package::chrono::Utc;
+use log::{error, warn};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+
+// Per-user and global quotas on queries, active subscriptions and notifications.
+//
+// Usage is counted in fixed windows (minute/day) and stored as `Quota` in the subscription store.
+// Per-user limits can be raised or lowered by setting `Quota::limits`.
+// Anonymous queries only count towards the global quota, the HTTP gateway limits them per client.
+// Queries are answered concurrently, the quotas are checked and counted in a transaction.
+
+pub const DEFAULT_LIMITS: QuotaLimits = QuotaLimits {
+    queries_per_minute: 20,
+    active_subscriptions: 50,
+    notifications_per_day: 100,
+};
+
+pub const GLOBAL_LIMITS: QuotaLimits = QuotaLimits {
+    queries_per_minute: 600,
+    active_subscriptions: 10000,
+    notifications_per_day: 20000,
+};
+
+const MINUTE: i64 = 60;
+const DAY: i64 = 24 * 60 * MINUTE;
+
+impl Quota {
+    fn new(user_hash: Option<u64>) -> Self {
+        Quota {
+            user_hash,
+            limits: None,
+            minute: 0,
+            queries: 0,
+            day: 0,
+            notifications: 0,
+            exceeded_reported_day: 0,
+        }
+    }
+
+    pub fn effective_limits(&self) -> QuotaLimits {
+        match (&self.limits, self.user_hash) {
+            (Some(limits), _) => limits.clone(),
+            (None, Some(_)) => DEFAULT_LIMITS,
+            (None, None) => GLOBAL_LIMITS,
+        }
+    }
+
+    // starts a new window once the current one is over.
+    fn roll(&mut self, now: i64) {
+        let minute = now - now.rem_euclid(MINUTE);
+        if self.minute != minute {
+            self.minute = minute;
+            self.queries = 0;
+        }
+        let day = now - now.rem_euclid(DAY);
+        if self.day != day {
+            self.day = day;
+            self.notifications = 0;
+        }
+    }
+
+    fn exceeded(&self, kind: QuotaKind, limit: u32, user_hash: Option<u64>) -> QuotaExceeded {
+        QuotaExceeded { user_hash, kind, limit, global: self.user_hash.is_none() }
+    }
+
+    /// Checks the query and notification windows, without counting.
+    pub fn check(&mut self, kind: &QuotaKind, now: i64, user_hash: Option<u64>) -> Result<(), QuotaExceeded> {
+        self.roll(now);
+        let limits = self.effective_limits();
+        match kind {
+            QuotaKind::QueriesPerMinute if self.queries >= limits.queries_per_minute => Err(self.exceeded(QuotaKind::QueriesPerMinute, limits.queries_per_minute, user_hash)),
+            QuotaKind::NotificationsPerDay if self.notifications >= limits.notifications_per_day => Err(self.exceeded(QuotaKind::NotificationsPerDay, limits.notifications_per_day, user_hash)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn count(&mut self, kind: &QuotaKind) {
+        match kind {
+            QuotaKind::QueriesPerMinute => { self.queries += 1; },
+            QuotaKind::NotificationsPerDay => { self.notifications += 1; },
+            QuotaKind::ActiveSubscriptions | QuotaKind::ConcurrentStreams => {},
+        }
+    }
+}
+
+pub fn get_quota(store: &SubscriptionStore, user_hash: Option<u64>) -> Quota {
+    match store.0.db.get(Quota::get_key_for_user_hash(user_hash)).ok().flatten().map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::Quota(quota))) => quota,
+        _ => Quota::new(user_hash),
+    }
+}
+
+fn get_quota_in(tx: &TransactionalTree, user_hash: Option<u64>) -> sled::transaction::ConflictableTransactionResult<Quota, QuotaExceeded> {
+    match tx.get(Quota::get_key_for_user_hash(user_hash))?.map(|v| CosmosRustBotValue::try_from(v.to_vec())) {
+        Some(Ok(CosmosRustBotValue::Quota(quota))) => Ok(quota),
+        _ => Ok(Quota::new(user_hash)),
+    }
+}
+
+fn set_quota_in(tx: &TransactionalTree, quota: Quota) -> sled::transaction::ConflictableTransactionResult<(), QuotaExceeded> {
+    let item = CosmosRustBotValue::Quota(quota);
+    let key = item.key();
+    if let Ok(value) = TryInto::<Vec<u8>>::try_into(item) {
+        tx.insert(key, value)?;
+    }
+    Ok(())
+}
+
+// counts towards the user (if any) and the global quota, but only if neither is exhausted.
+fn check_and_count(store: &SubscriptionStore, user_hash: Option<u64>, kind: QuotaKind) -> Result<(), QuotaExceeded> {
+    let now = Utc::now().timestamp();
+    let owners = match user_hash {
+        Some(_) => vec![user_hash, None],
+        None => vec![None],
+    };
+    let result = store.0.db.transaction(|tx| {
+        let mut quotas = Vec::new();
+        for owner in &owners {
+            let mut quota = get_quota_in(tx, *owner)?;
+            quota.check(&kind, now, user_hash).map_err(ConflictableTransactionError::Abort)?;
+            quota.count(&kind);
+            quotas.push(quota);
+        }
+        for quota in quotas {
+            set_quota_in(tx, quota)?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => Ok(()),
+        Err(TransactionError::Abort(exceeded)) => Err(exceeded),
+        Err(TransactionError::Storage(err)) => {
+            error!("Unable to count the quota: {}", err.to_string());
+            Ok(())
+        },
+    }
+}
+
+pub fn count_query(store: &SubscriptionStore, user_hash: Option<u64>) -> Result<(), QuotaExceeded> {
+    check_and_count(store, user_hash, QuotaKind::QueriesPerMinute).map_err(|err| {
+        warn!("Query quota exceeded for user {:?} (global: {})", user_hash, err.global);
+        err
+    })
+}
+
+/// Checks the notification quota of the user and the global quota, without counting.
+pub fn check_notification(store: &SubscriptionStore, user_hash: u64) -> Result<(), QuotaExceeded> {
+    let now = Utc::now().timestamp();
+    get_quota(store, Some(user_hash)).check(&QuotaKind::NotificationsPerDay, now, Some(user_hash))?;
+    get_quota(store, None).check(&QuotaKind::NotificationsPerDay, now, Some(user_hash))
+}
+
+// counted once the notification was handed off, see `check_notification`.
+pub fn count_notification(store: &SubscriptionStore, user_hash: u64) -> Result<(), QuotaExceeded> {
+    check_and_count(store, Some(user_hash), QuotaKind::NotificationsPerDay)
+}
+
+/// Checks if the user may subscribe to one more query, `new_subscription` if it does not exist yet.
+pub fn check_subscription(store: &SubscriptionStore, user_hash: u64, new_subscription: bool) -> Result<(), QuotaExceeded> {
+    let user_limit = get_quota(store, Some(user_hash)).effective_limits().active_subscriptions;
+    let global_limit = get_quota(store, None).effective_limits().active_subscriptions;
+    let mut active = 0u32;
+    let mut total = 0u32;
+    for subscription in store.get_subscriptions() {
+        total += 1;
+        if subscription.contains_user_hash(user_hash) {
+            active += 1;
+        }
+    }
+    if active >= user_limit {
+        return Err(QuotaExceeded { user_hash: Some(user_hash), kind: QuotaKind::ActiveSubscriptions, limit: user_limit, global: false });
+    }
+    if new_subscription && total >= global_limit {
+        return Err(QuotaExceeded { user_hash: Some(user_hash), kind: QuotaKind::ActiveSubscriptions, limit: global_limit, global: true });
+    }
+    Ok(())
+}
+
+/// True once per day, so an exhausted notification quota is reported a single time.
+pub fn report_exceeded_once(store: &SubscriptionStore, user_hash: u64) -> bool {
+    let now = Utc::now().timestamp();
+    let day = now - now.rem_euclid(DAY);
+    let result = store.0.db.transaction(|tx| {
+        let mut quota = get_quota_in(tx, Some(user_hash))?;
+        if quota.exceeded_reported_day == day {
+            return Ok(false);
+        }
+        quota.exceeded_reported_day = day;
+        set_quota_in(tx, quota)?;
+        Ok(true)
+    });
+    result.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::utils::entry::{Quota, QuotaKind};
+    use crate::utils::entry::db::SubscriptionStore;
+    use super::{count_query, get_quota, GLOBAL_LIMITS};
+
+    #[test]
+    pub fn quota_windows() {
+        let now = 1672655400;
+        let mut quota = Quota::new(Some(1));
+        for _ in 0..super::DEFAULT_LIMITS.queries_per_minute {
+            assert!(quota.check(&QuotaKind::QueriesPerMinute, now, Some(1)).is_ok());
+            quota.count(&QuotaKind::QueriesPerMinute);
+        }
+        let exceeded = quota.check(&QuotaKind::QueriesPerMinute, now + 59, Some(1)).unwrap_err();
+        assert_eq!(exceeded.limit, super::DEFAULT_LIMITS.queries_per_minute);
+        assert!(!exceeded.global);
+        assert!(quota.check(&QuotaKind::NotificationsPerDay, now, Some(1)).is_ok());
+        // the next minute starts a new window.
+        assert!(quota.check(&QuotaKind::QueriesPerMinute, now + 60, Some(1)).is_ok());
+    }
+
+    #[test]
+    pub fn concurrent_and_anonymous_queries_count_globally() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SubscriptionStore::new(&db);
+        let threads = (0..8u64).map(|user_hash| {
+            let store = SubscriptionStore::new(&db);
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    count_query(&store, Some(user_hash)).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // no increment is lost (unless a new minute started in between).
+        let global = get_quota(&store, None);
+        let users = (0..8u64).map(|user_hash| get_quota(&store, Some(user_hash))).collect::<Vec<_>>();
+        if users.iter().all(|quota| quota.minute == global.minute) {
+            assert_eq!(global.queries, users.iter().map(|quota| quota.queries).sum::<u32>());
+            assert_eq!(global.queries, 80);
+        }
+
+        let mut exceeded = None;
+        for _ in 0..=GLOBAL_LIMITS.queries_per_minute {
+            if let Err(err) = count_query(&store, None) {
+                exceeded = Some(err);
+                break;
+            }
+        }
+        let exceeded = exceeded.expect("anonymous queries are limited by the global quota");
+        assert!(exceeded.global);
+        assert_eq!(exceeded.user_hash, None);
+    }
+}
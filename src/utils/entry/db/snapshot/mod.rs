@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use log::{error, info};
+use serde::{Serialize,Deserialize};
+use cosmos_rust_package::chrono::Utc;
+use cosmos_rust_package::tokio::time::{sleep, Duration};
+
+use crate::utils::entry::db::{CosmosRustBotStore, TaskMemoryStore};
+
+// Point-in-time backup of all stores in one versioned archive.
+//
+// Each sled tree is dumped after a flush, sled offers no snapshot isolation across trees,
+// therefore the snapshot should be taken while no task writes to the stores (e.g. right after a refresh).
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const SNAPSHOT_FILE_PREFIX: &str = "snapshot_";
+const SNAPSHOT_FILE_SUFFIX: &str = ".bin";
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct SnapshotTree {
+    pub name: Vec<u8>,
+    pub items: Vec<(Vec<u8>,Vec<u8>)>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub timestamp: i64,
+    pub task_memory_store: Option<Vec<SnapshotTree>>,
+    pub entry_index_db: Option<Vec<SnapshotTree>>,
+    pub subscription_db: Option<Vec<SnapshotTree>>,
+    pub notification_db: Option<Vec<SnapshotTree>>,
+}
+
+impl TryFrom<Vec<u8>> for Snapshot {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        // the version is the first field, check it before decoding the rest.
+        let version: u32 = bincode::deserialize(&item[..])?;
+        if version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!("Error: unsupported snapshot version {}, expected {}", version, SNAPSHOT_VERSION));
+        }
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<Snapshot> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: Snapshot) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+fn dump_db(db: &sled::Db) -> anyhow::Result<Vec<SnapshotTree>> {
+    db.flush()?;
+    let mut trees = Vec::new();
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        let items = tree.iter()
+            .map(|x| x.map(|(k,v)| (k.to_vec(),v.to_vec())))
+            .collect::<sled::Result<Vec<(Vec<u8>,Vec<u8>)>>>()?;
+        trees.push(SnapshotTree { name: name.to_vec(), items });
+    }
+    Ok(trees)
+}
+
+// trees that are not in the snapshot (e.g. migration markers or outboxes created later) are dropped,
+// they would describe a state that no longer matches.
+fn restore_db(db: &sled::Db, trees: &Vec<SnapshotTree>) -> anyhow::Result<()> {
+    let names = trees.iter().map(|x| x.name.to_vec()).collect::<HashSet<Vec<u8>>>();
+    for name in db.tree_names() {
+        if names.contains(&name.to_vec()) {
+            continue;
+        }
+        // the default tree can not be dropped.
+        if name == db.name() {
+            db.clear()?;
+        } else {
+            db.drop_tree(&name)?;
+        }
+    }
+    for snapshot_tree in trees {
+        let tree = db.open_tree(&snapshot_tree.name)?;
+        tree.clear()?;
+        for (k,v) in &snapshot_tree.items {
+            tree.insert(k, v.to_vec())?;
+        }
+    }
+    db.flush()?;
+    Ok(())
+}
+
+impl Snapshot {
+
+    pub fn new(task_store: Option<&TaskMemoryStore>, cosmos_rust_bot_store: Option<&CosmosRustBotStore>, notification_db: Option<&sled::Db>) -> anyhow::Result<Self> {
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            timestamp: Utc::now().timestamp(),
+            task_memory_store: task_store.map(|x| dump_db(&x.0.db)).transpose()?,
+            entry_index_db: cosmos_rust_bot_store.map(|x| dump_db(&x.entry_store.0.db)).transpose()?,
+            subscription_db: cosmos_rust_bot_store.map(|x| dump_db(&x.subscription_store.0.db)).transpose()?,
+            notification_db: notification_db.map(|x| dump_db(x)).transpose()?,
+        })
+    }
+
+    // only the stores that are part of the snapshot and given as argument are overwritten.
+    pub fn restore(&self, task_store: Option<&TaskMemoryStore>, cosmos_rust_bot_store: Option<&CosmosRustBotStore>, notification_db: Option<&sled::Db>) -> anyhow::Result<()> {
+        if let (Some(trees), Some(store)) = (&self.task_memory_store, task_store) {
+            restore_db(&store.0.db, trees)?;
+        }
+        if let Some(store) = cosmos_rust_bot_store {
+            if let Some(trees) = &self.entry_index_db {
+                restore_db(&store.entry_store.0.db, trees)?;
+            }
+            if let Some(trees) = &self.subscription_db {
+                restore_db(&store.subscription_store.0.db, trees)?;
+            }
+        }
+        if let (Some(trees), Some(db)) = (&self.notification_db, notification_db) {
+            restore_db(db, trees)?;
+        }
+        Ok(())
+    }
+
+    pub fn export(&self, path: &str) -> anyhow::Result<()> {
+        let bytes: Vec<u8> = self.clone().try_into()?;
+        // write to a temporary file first, an interrupted export never replaces a valid archive.
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn import(path: &str) -> anyhow::Result<Self> {
+        std::fs::read(path)?.try_into()
+    }
+}
+
+/// Writes a snapshot to `dir` every `interval` and keeps the latest `keep` archives.
+pub fn spawn_snapshot_task(dir: &str, interval: Duration, keep: usize, task_store: Option<TaskMemoryStore>, cosmos_rust_bot_store: Option<CosmosRustBotStore>, notification_db: Option<sled::Db>) -> cosmos_rust_package::tokio::task::JoinHandle<()> {
+    let dir = dir.to_owned();
+    cosmos_rust_package::tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+            let path = format!("{}/{}{}{}", dir, SNAPSHOT_FILE_PREFIX, Utc::now().timestamp(), SNAPSHOT_FILE_SUFFIX);
+            match Snapshot::new(task_store.as_ref(), cosmos_rust_bot_store.as_ref(), notification_db.as_ref()).and_then(|x| x.export(&path)) {
+                Ok(_) => { info!("Snapshot written to {}", &path); },
+                Err(err) => { error!("Unable to write snapshot {}, Error: {}", &path, err.to_string()); },
+            };
+            if let Err(err) = remove_old_snapshots(&dir, keep) {
+                error!("Unable to remove old snapshots in {}, Error: {}", &dir, err.to_string());
+            }
+        }
+    })
+}
+
+fn remove_old_snapshots(dir: &str, keep: usize) -> anyhow::Result<()> {
+    let mut snapshots = std::fs::read_dir(dir)?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.file_name().and_then(|x| x.to_str()).map(|x| x.starts_with(SNAPSHOT_FILE_PREFIX) && x.ends_with(SNAPSHOT_FILE_SUFFIX)).unwrap_or(false))
+        .collect::<Vec<_>>();
+    // the timestamp in the file name has a fixed width for the foreseeable future.
+    snapshots.sort();
+    while snapshots.len() > keep {
+        let path = snapshots.remove(0);
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Snapshot, SNAPSHOT_VERSION};
+
+    #[test]
+    pub fn export_import_restore() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(b"key", b"before".to_vec()).unwrap();
+        db.open_tree("outbox").unwrap().insert(b"item", b"before".to_vec()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("crb_snapshot_test_{}.bin", std::process::id())).to_str().unwrap().to_string();
+        Snapshot::new(None, None, Some(&db)).unwrap().export(&path).unwrap();
+
+        db.insert(b"key", b"after".to_vec()).unwrap();
+        db.insert(b"other", b"after".to_vec()).unwrap();
+        db.open_tree("outbox").unwrap().clear().unwrap();
+        db.open_tree("migration").unwrap().insert(b"marker", b"after".to_vec()).unwrap();
+
+        let snapshot = Snapshot::import(&path).unwrap();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        snapshot.restore(None, None, Some(&db)).unwrap();
+        assert_eq!(db.get(b"key").unwrap().unwrap().to_vec(), b"before".to_vec());
+        assert!(db.get(b"other").unwrap().is_none());
+        assert_eq!(db.open_tree("outbox").unwrap().get(b"item").unwrap().unwrap().to_vec(), b"before".to_vec());
+        // trees created after the snapshot are gone.
+        assert!(!db.tree_names().iter().any(|x| x.as_ref() == b"migration"));
+
+        // archives of another version are rejected.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..4].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(Snapshot::import(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,54 +1,357 @@
 use anyhow::Context;
-use std::collections::HashSet;
-use std::os::unix::net::{UnixListener,UnixStream};
-use std::io::{Read, Write};
-use std::thread::JoinHandle;
+use std::os::unix::net::UnixStream;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use cosmos_rust_package::tokio;
+use cosmos_rust_package::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use cosmos_rust_package::tokio::sync::watch;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
-pub trait Handler
+// Framed IPC over Unix domain sockets.
+//
+// Every message is a frame: magic (4 bytes), protocol version (u16), message type (u8) and
+// payload length (u32), all big endian, followed by the payload. A connection carries any number of
+// request/response pairs until the client closes it. Peers speaking another protocol version are
+// answered with an error frame and disconnected.
+//
+// The service handles connections concurrently on the tokio runtime, the handler runs on the blocking
+// pool. Failures are answered with a bincode encoded `ServiceError` in an error frame.
+
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"CRBS";
+// 2: error frames carry a `ServiceError`.
+pub const PROTOCOL_VERSION: u16 = 2;
+const HEADER_LEN: usize = 11;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MessageType {
+    Request,
+    Response,
+    Error,
+}
+
+impl MessageType {
+    fn to_byte(self) -> u8 {
+        match self {
+            MessageType::Request => 1,
+            MessageType::Response => 2,
+            MessageType::Error => 3,
+        }
+    }
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(MessageType::Request),
+            2 => Some(MessageType::Response),
+            3 => Some(MessageType::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ProtocolError {
+    #[error("not a cosmos-rust-bot socket peer (bad magic number)")]
+    BadMagic,
+    #[error("incompatible protocol version: peer speaks {remote}, expected {local}")]
+    IncompatibleVersion { local: u16, remote: u16 },
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("unexpected {0:?} frame")]
+    UnexpectedMessageType(MessageType),
+    #[error("message of {size} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { size: u32, max: u32 },
+}
+
+/// The error a socket service returns to the client.
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ServiceError {
+    #[error("{0}")]
+    Protocol(String),
+    #[error("message of {size} bytes exceeds the maximum of {max} bytes")]
+    MessageTooLarge { size: u32, max: u32 },
+    #[error("timed out while {0}")]
+    Timeout(String),
+    #[error("request failed: {0}")]
+    Handler(String),
+}
+
+impl From<&ProtocolError> for ServiceError {
+    fn from(err: &ProtocolError) -> Self {
+        match err {
+            ProtocolError::FrameTooLarge { size, max } => ServiceError::MessageTooLarge { size: *size, max: *max },
+            _ => ServiceError::Protocol(err.to_string()),
+        }
+    }
+}
+
+impl TryFrom<Vec<u8>> for ServiceError {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<ServiceError> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: ServiceError) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub message_type: MessageType,
+    pub payload: Vec<u8>,
+}
+
+pub fn encode_header(message_type: MessageType, length: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&PROTOCOL_MAGIC);
+    header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    header[6] = message_type.to_byte();
+    header[7..11].copy_from_slice(&length.to_be_bytes());
+    header
+}
+
+/// The message type and payload length, or why the peer is not compatible.
+pub fn decode_header(header: &[u8; HEADER_LEN], max_length: u32) -> Result<(MessageType, u32), ProtocolError> {
+    if header[0..4] != PROTOCOL_MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::IncompatibleVersion { local: PROTOCOL_VERSION, remote: version });
+    }
+    let message_type = MessageType::from_byte(header[6]).ok_or(ProtocolError::UnknownMessageType(header[6]))?;
+    let length = u32::from_be_bytes([header[7], header[8], header[9], header[10]]);
+    if length > max_length {
+        return Err(ProtocolError::FrameTooLarge { size: length, max: max_length });
+    }
+    Ok((message_type, length))
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, message_type: MessageType, payload: &[u8]) -> anyhow::Result<()> {
+    let length = u32::try_from(payload.len()).context("Payload too large for a frame")?;
+    writer.write_all(&encode_header(message_type, length)).context("Failed at writing onto the unix stream")?;
+    writer.write_all(payload).context("Failed at writing onto the unix stream")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next frame, None if the peer closed the connection between frames.
+pub fn read_frame<R: Read>(reader: &mut R, max_length: u32) -> anyhow::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => { return Ok(None); },
+        Err(err) => { return Err(err).context("Failed at reading the unix stream"); },
+    }
+    let (message_type, length) = decode_header(&header, max_length)?;
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).context("Failed at reading the unix stream")?;
+    Ok(Some(Frame { message_type, payload }))
+}
+
+pub trait Handler: Send + Sync
 {
-    fn process(&mut self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+    fn process(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketServiceConfig {
+    // a connection without a new request for this long is closed.
+    pub idle_timeout: Duration,
+    // reading the payload of a request, once its header arrived.
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_message_size: u32,
+}
+
+impl Default for SocketServiceConfig {
+    fn default() -> Self {
+        SocketServiceConfig {
+            idle_timeout: Duration::from_secs(5 * 60),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Returned by `spawn_socket_service`, stops the service.
+pub struct SocketServiceHandle {
+    socket_path: String,
+    shutdown: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl SocketServiceHandle {
+    /// Stops accepting connections, lets open connections finish their current request and removes the socket file.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.send(true).ok();
+        self.task.await?;
+        remove_socket_file(&self.socket_path)
+    }
 }
 
-pub fn spawn_socket_service(socket_path: &str, mut handler: Box<dyn Handler + Send>) -> JoinHandle<()>
+fn remove_socket_file(socket_path: &str) -> anyhow::Result<()> {
+    if std::fs::metadata(socket_path).is_ok() {
+        std::fs::remove_file(socket_path)
+            .with_context(|| format!("could not delete the socket at {:?}", socket_path))?;
+    }
+    Ok(())
+}
+
+/// Binds the socket (replacing a stale socket file) and serves it until the handle is shut down.
+/// Must be called from within a tokio runtime.
+pub fn spawn_socket_service(socket_path: &str, handler: Arc<dyn Handler>, config: SocketServiceConfig) -> anyhow::Result<SocketServiceHandle>
 {
-    let socket_path = socket_path.to_owned();
-    std::thread::spawn(move || {
+    remove_socket_file(socket_path)?;
+    let unix_listener = tokio::net::UnixListener::bind(socket_path)
+        .with_context(|| format!("Could not create the unix socket at {:?}", socket_path))?;
+    let (shutdown, mut shutdown_receiver) = watch::channel(false);
+    let connection_shutdown = shutdown_receiver.clone();
+
+    let task = tokio::spawn(async move {
+        let mut connections: Vec<tokio::task::JoinHandle<()>> = Vec::new();
         loop {
-            if std::fs::metadata(&socket_path).is_ok() {
-                //println!("A socket is already present. Deleting...");
-                std::fs::remove_file(&socket_path)
-                    .with_context(|| {
-                        format!("could not delete previous socket at {:?}", &socket_path)
-                    })
-                    .unwrap();
+            tokio::select! {
+                accepted = unix_listener.accept() => {
+                    match accepted {
+                        Ok((unix_stream, _socket_address)) => {
+                            connections.retain(|x| !x.is_finished());
+                            connections.push(tokio::spawn(handle_connection(unix_stream, handler.clone(), config.clone(), connection_shutdown.clone())));
+                        },
+                        Err(err) => {
+                            error!("Failed at accepting a connection on the unix listener: {}", err.to_string());
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        },
+                    }
+                },
+                _ = shutdown_receiver.changed() => { break; },
             }
+        }
+        for connection in connections {
+            connection.await.ok();
+        }
+    });
+    Ok(SocketServiceHandle { socket_path: socket_path.to_owned(), shutdown, task })
+}
 
-            let unix_listener = UnixListener::bind(&socket_path)
-                .context("Could not create the unix socket")
-                .unwrap();
+enum ReadOutcome {
+    Frame(Frame),
+    Closed,
+    Failed(ServiceError),
+}
 
-            loop {
-                let (unix_stream, _socket_address) = unix_listener
-                    .accept()
-                    .context("Failed at accepting a connection on the unix listener")
-                    .unwrap();
+async fn read_frame_async(unix_stream: &mut tokio::net::UnixStream, config: &SocketServiceConfig, shutdown: &mut watch::Receiver<bool>) -> ReadOutcome {
+    let mut header = [0u8; HEADER_LEN];
+    tokio::select! {
+        read = tokio::time::timeout(config.idle_timeout, unix_stream.read_exact(&mut header)) => {
+            match read {
+                Ok(Ok(_)) => {},
+                // idle, closed or broken, either way there is nobody to answer.
+                _ => { return ReadOutcome::Closed; },
+            }
+        },
+        _ = shutdown.changed() => { return ReadOutcome::Closed; },
+    }
+    let (message_type, length) = match decode_header(&header, config.max_message_size) {
+        Ok(decoded) => decoded,
+        Err(err) => { return ReadOutcome::Failed(ServiceError::from(&err)); },
+    };
+    let mut payload = vec![0u8; length as usize];
+    match tokio::time::timeout(config.read_timeout, unix_stream.read_exact(&mut payload)).await {
+        Ok(Ok(_)) => ReadOutcome::Frame(Frame { message_type, payload }),
+        Ok(Err(_)) => ReadOutcome::Closed,
+        Err(_elapsed) => ReadOutcome::Failed(ServiceError::Timeout("reading the request".to_string())),
+    }
+}
 
-                handle_stream(unix_stream, &mut handler).unwrap();
+async fn write_frame_async(unix_stream: &mut tokio::net::UnixStream, config: &SocketServiceConfig, message_type: MessageType, payload: &[u8]) -> anyhow::Result<()> {
+    let length = u32::try_from(payload.len()).context("Payload too large for a frame")?;
+    let mut bytes = encode_header(message_type, length).to_vec();
+    bytes.extend_from_slice(payload);
+    tokio::time::timeout(config.write_timeout, unix_stream.write_all(&bytes)).await
+        .map_err(|_| ServiceError::Timeout("writing the response".to_string()))?
+        .context("Failed at writing onto the unix stream")?;
+    Ok(())
+}
+
+async fn write_error(unix_stream: &mut tokio::net::UnixStream, config: &SocketServiceConfig, service_error: ServiceError) -> anyhow::Result<()> {
+    let payload: Vec<u8> = service_error.try_into()?;
+    write_frame_async(unix_stream, config, MessageType::Error, &payload).await
+}
+
+async fn handle_connection(mut unix_stream: tokio::net::UnixStream, handler: Arc<dyn Handler>, config: SocketServiceConfig, mut shutdown: watch::Receiver<bool>)
+{
+    loop {
+        let frame = match read_frame_async(&mut unix_stream, &config, &mut shutdown).await {
+            ReadOutcome::Frame(frame) => frame,
+            ReadOutcome::Closed => { return; },
+            // the stream can not be resynchronized after a bad frame.
+            ReadOutcome::Failed(service_error) => {
+                info!("Closing socket connection: {}", service_error.to_string());
+                write_error(&mut unix_stream, &config, service_error).await.ok();
+                return;
+            },
+        };
+        let result = if frame.message_type != MessageType::Request {
+            Err(ServiceError::from(&ProtocolError::UnexpectedMessageType(frame.message_type)))
+        } else {
+            let handler = handler.clone();
+            match tokio::task::spawn_blocking(move || handler.process(frame.payload)).await {
+                Ok(Ok(encoded)) => Ok(encoded),
+                Ok(Err(err)) => Err(ServiceError::Handler(err.to_string())),
+                Err(_join_error) => Err(ServiceError::Handler("the handler panicked".to_string())),
             }
+        };
+        let written = match result {
+            Ok(encoded) => write_frame_async(&mut unix_stream, &config, MessageType::Response, &encoded).await,
+            Err(service_error) => write_error(&mut unix_stream, &config, service_error).await,
+        };
+        if let Err(err) = written {
+            info!("Closing socket connection: {}", err.to_string());
+            return;
         }
-    })
+    }
 }
 
-fn handle_stream(mut unix_stream: UnixStream, handler: &mut Box<dyn Handler + Send>) -> anyhow::Result<()>
-{
-    let encoded: Vec<u8> = handler.process(get_bytes_from_stream(&mut unix_stream)?)?;
+/// A connection to a socket service that can be used for several requests.
+pub struct SocketClient {
+    unix_stream: UnixStream,
+}
 
-    unix_stream
-        .write(&encoded[..])
-        .context("Failed at writing onto the unix stream")?;
+impl SocketClient {
+    pub fn connect(socket_path: &str) -> anyhow::Result<Self> {
+        Ok(SocketClient { unix_stream: UnixStream::connect(socket_path).context("Could not create stream")? })
+    }
 
-    Ok(())
+    /// The response payload, errors reported by the service are returned as `ServiceError`.
+    pub fn send_bytes(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        write_frame(&mut self.unix_stream, MessageType::Request, request)?;
+        match read_frame(&mut self.unix_stream, u32::MAX)? {
+            Some(Frame { message_type: MessageType::Response, payload }) => Ok(payload),
+            Some(Frame { message_type: MessageType::Error, payload }) => {
+                let service_error = ServiceError::try_from(payload.clone())
+                    .unwrap_or_else(|_| ServiceError::Protocol(String::from_utf8_lossy(&payload).to_string()));
+                Err(service_error.into())
+            },
+            Some(Frame { message_type, .. }) => Err(ProtocolError::UnexpectedMessageType(message_type).into()),
+            None => Err(anyhow::anyhow!("The socket service closed the connection")),
+        }
+    }
+
+    pub fn send<T,S>(&mut self, request: T) -> anyhow::Result<S>
+        where
+            T: Serialize,
+            Vec<u8>: TryFrom<T>,
+            S: for<'a> Deserialize<'a> + TryFrom<Vec<u8>>,
+    {
+        let request: Vec<u8> = request.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))?;
+        self.send_bytes(&request)?.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))
+    }
 }
 
 pub fn client_send_request<T,S>(
@@ -61,30 +364,81 @@ pub fn client_send_request<T,S>(
         S: for<'a> Deserialize<'a> + TryFrom<Vec<u8>>,
 
 {
-    let mut unix_stream = UnixStream::connect(socket_path).context("Could not create stream")?;
-
-    write_request_and_shutdown(&mut unix_stream, request.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))?)?;
-    Ok(get_bytes_from_stream(&mut unix_stream)?.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))?)
+    SocketClient::connect(socket_path)?.send(request)
 }
 
-fn write_request_and_shutdown(
-    unix_stream: &mut UnixStream,
-    request: Vec<u8>,
-) -> anyhow::Result<()> {
-    unix_stream
-        .write(&request)
-        .context("Failed at writing onto the unix stream")?;
+#[cfg(test)]
+mod test {
 
-    unix_stream
-        .shutdown(std::net::Shutdown::Write)
-        .context("Could not shutdown writing on the stream")?;
-    Ok(())
-}
+    use super::{decode_header, encode_header, read_frame, write_frame, Frame, Handler, MessageType, ProtocolError, ServiceError, SocketClient, SocketServiceConfig, spawn_socket_service, PROTOCOL_VERSION};
+    use cosmos_rust_package::tokio;
+    use std::io::{Cursor, Write};
+    use std::sync::Arc;
 
-fn get_bytes_from_stream(unix_stream: &mut UnixStream) -> anyhow::Result<Vec<u8>> {
-    let mut bytes: Vec<u8> = Vec::new();
-    unix_stream
-        .read_to_end(&mut bytes)
-        .context("Failed at reading the unix stream")?;
-    Ok(bytes)
-}
\ No newline at end of file
+    struct Echo;
+    impl Handler for Echo {
+        fn process(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            match bytes.as_slice() {
+                b"" => anyhow::bail!("empty request"),
+                b"panic" => panic!("malformed request"),
+                _ => Ok(bytes),
+            }
+        }
+    }
+
+    fn service_error(err: anyhow::Error) -> ServiceError {
+        err.downcast::<ServiceError>().unwrap()
+    }
+
+    #[test]
+    pub fn frame_round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, MessageType::Request, b"first").unwrap();
+        write_frame(&mut buffer, MessageType::Response, b"").unwrap();
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_frame(&mut reader, 1024).unwrap(), Some(Frame { message_type: MessageType::Request, payload: b"first".to_vec() }));
+        assert_eq!(read_frame(&mut reader, 1024).unwrap(), Some(Frame { message_type: MessageType::Response, payload: Vec::new() }));
+        assert_eq!(read_frame(&mut reader, 1024).unwrap(), None);
+
+        let mut header = encode_header(MessageType::Request, 0);
+        header[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let err = decode_header(&header, 1024).unwrap_err();
+        assert_eq!(err, ProtocolError::IncompatibleVersion { local: PROTOCOL_VERSION, remote: PROTOCOL_VERSION + 1 });
+        assert!(err.to_string().starts_with("incompatible protocol version"));
+        assert_eq!(decode_header(b"{\"a\":1}\n\n\n\n", 1024).unwrap_err(), ProtocolError::BadMagic);
+        assert_eq!(decode_header(&encode_header(MessageType::Request, 2048), 1024).unwrap_err(), ProtocolError::FrameTooLarge { size: 2048, max: 1024 });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn concurrent_service_with_errors_and_shutdown() {
+        let dir = std::env::temp_dir().join(format!("crb_socket_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("echo_socket").to_string_lossy().to_string();
+        let config = SocketServiceConfig { max_message_size: 1024, ..SocketServiceConfig::default() };
+        let handle = spawn_socket_service(&socket_path, Arc::new(Echo), config).unwrap();
+
+        let path = socket_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut client = SocketClient::connect(&path).unwrap();
+            // a second connection is served while the first one stays open.
+            let mut other = SocketClient::connect(&path).unwrap();
+            assert_eq!(other.send_bytes(b"other").unwrap(), b"other".to_vec());
+
+            assert_eq!(client.send_bytes(b"one").unwrap(), b"one".to_vec());
+            assert_eq!(service_error(client.send_bytes(b"").unwrap_err()), ServiceError::Handler("empty request".to_string()));
+            assert_eq!(service_error(client.send_bytes(b"panic").unwrap_err()), ServiceError::Handler("the handler panicked".to_string()));
+            assert_eq!(client.send_bytes(b"two").unwrap(), b"two".to_vec());
+            assert_eq!(service_error(client.send_bytes(&[0u8; 2048]).unwrap_err()), ServiceError::MessageTooLarge { size: 2048, max: 1024 });
+
+            // an unframed (legacy) request is rejected with a readable error.
+            let mut legacy = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            legacy.write_all(b"legacy bincode request").unwrap();
+            let frame = read_frame(&mut legacy, u32::MAX).unwrap().unwrap();
+            assert_eq!(frame.message_type, MessageType::Error);
+        }).await.unwrap();
+
+        handle.shutdown().await.unwrap();
+        assert!(std::fs::metadata(&socket_path).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,271 @@
+use crate::utils::entry::*;
+use crate::utils::entry::db::CosmosRustBotStore;
+use crate::utils::entry::db::query::CosmosRustBotStoreInquirer;
+use crate::utils::entry::db::access;
+use crate::utils::hash::to_hex;
+use cosmos_rust_package::tokio;
+use cosmos_rust_package::tokio::sync::broadcast;
+use log::info;
+use std::net::IpAddr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Serialize,Deserialize};
+
+// Live updates of the result of an entries query.
+//
+// A single `StreamHub` watches the entry and index store. Entries and indices are written in
+// batches, therefore the events of a batch are collected for `BATCH_WINDOW` before the open
+// streams are signaled over a broadcast channel. Each `EntryStream` then evaluates its query again and
+// returns the difference to the previous result as insert and remove events. A stream that falls more
+// than `CHANGES_CAPACITY` batches behind is closed.
+// Entries are keyed by their content, a changed entry (e.g. the tally of a proposal) is removed and
+// inserted with its new key.
+// Open streams are limited per user (or client address, without credentials) and in total.
+
+const BATCH_WINDOW: Duration = Duration::from_millis(500);
+const CHANGES_CAPACITY: usize = 16;
+pub const MAX_STREAMS: usize = 256;
+pub const MAX_STREAMS_PER_OWNER: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum EntryEvent {
+    // the key is hex encoded.
+    Insert { key: String, entry: CosmosRustBotValue },
+    Remove { key: String },
+}
+
+impl EntryEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryEvent::Insert { .. } => "insert",
+            EntryEvent::Remove { .. } => "remove",
+        }
+    }
+}
+
+// the open streams are counted per user, or per client address without credentials.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum StreamOwner {
+    User(u64),
+    Client(IpAddr),
+}
+
+#[derive(Default)]
+struct OpenStreams {
+    total: usize,
+    owners: HashMap<StreamOwner, usize>,
+}
+
+/// Watches the store once for all streams, see `EntryStream::open`.
+pub struct StreamHub {
+    changes: broadcast::Sender<()>,
+    open: Arc<Mutex<OpenStreams>>,
+    max_streams: usize,
+    max_streams_per_owner: usize,
+}
+
+impl StreamHub {
+    pub fn new(cosmos_rust_bot_store: &CosmosRustBotStore) -> Self {
+        StreamHub::with_limits(cosmos_rust_bot_store, MAX_STREAMS, MAX_STREAMS_PER_OWNER)
+    }
+
+    pub fn with_limits(cosmos_rust_bot_store: &CosmosRustBotStore, max_streams: usize, max_streams_per_owner: usize) -> Self {
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        // entries and indices share the db, a new index membership changes a result as well.
+        let mut subscriber = cosmos_rust_bot_store.entry_store.0.get_tree().watch_prefix(vec![]);
+        let sender = changes.clone();
+        // ends with the db.
+        std::thread::spawn(move || {
+            while subscriber.next().is_some() {
+                loop {
+                    match subscriber.next_timeout(BATCH_WINDOW) {
+                        Ok(_event) => {},
+                        Err(RecvTimeoutError::Timeout) => { break; },
+                        Err(RecvTimeoutError::Disconnected) => { return; },
+                    }
+                }
+                // an error only means no stream is open.
+                sender.send(()).ok();
+            }
+        });
+        StreamHub { changes, open: Arc::new(Mutex::new(OpenStreams::default())), max_streams, max_streams_per_owner }
+    }
+
+    fn acquire(&self, owner: StreamOwner) -> Result<StreamSlot, QuotaExceeded> {
+        let user_hash = match owner {
+            StreamOwner::User(user_hash) => Some(user_hash),
+            StreamOwner::Client(_) => None,
+        };
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.max_streams {
+            return Err(QuotaExceeded { user_hash, kind: QuotaKind::ConcurrentStreams, limit: self.max_streams as u32, global: true });
+        }
+        let count = open.owners.entry(owner).or_insert(0);
+        if *count >= self.max_streams_per_owner {
+            return Err(QuotaExceeded { user_hash, kind: QuotaKind::ConcurrentStreams, limit: self.max_streams_per_owner as u32, global: false });
+        }
+        *count += 1;
+        open.total += 1;
+        Ok(StreamSlot { owner, open: self.open.clone() })
+    }
+}
+
+// released when the stream is dropped.
+struct StreamSlot {
+    owner: StreamOwner,
+    open: Arc<Mutex<OpenStreams>>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.owners.get_mut(&self.owner) {
+            *count -= 1;
+            if *count == 0 {
+                open.owners.remove(&self.owner);
+            }
+        }
+    }
+}
+
+pub struct EntryStream {
+    store: CosmosRustBotStore,
+    query_part: EntriesQueryPart,
+    role: Role,
+    changes: broadcast::Receiver<()>,
+    // keys of the entries sent so far.
+    known: HashSet<Vec<u8>>,
+    _slot: StreamSlot,
+}
+
+impl EntryStream {
+    /// Opens the stream with the current result as the first events.
+    /// A denied query (`AccessDenied`, `QuotaExceeded`) or an expired cursor is returned as the error.
+    pub fn open(hub: &StreamHub, owner: StreamOwner, cosmos_rust_bot_store: &CosmosRustBotStore, query_part: EntriesQueryPart, settings_part: SettingsPart) -> anyhow::Result<Result<(Self, Vec<EntryEvent>), CosmosRustBotValue>> {
+        let slot = match hub.acquire(owner) {
+            Ok(slot) => slot,
+            Err(exceeded) => { return Ok(Err(CosmosRustBotValue::QuotaExceeded(exceeded))); },
+        };
+        // subscribed before the first result to not miss an update.
+        let changes = hub.changes.subscribe();
+        let store = cosmos_rust_bot_store.clone();
+
+        let query = UserQuery {
+            query_part: QueryPart::EntriesQueryPart(query_part.clone()),
+            settings_part,
+        };
+        let result = CosmosRustBotStoreInquirer(&store).query(&query);
+        if let Some(denied @ (CosmosRustBotValue::AccessDenied(_) | CosmosRustBotValue::QuotaExceeded(_) | CosmosRustBotValue::CursorExpired(_))) = result.first() {
+            return Ok(Err(denied.clone()));
+        }
+        let role = access::get_role(&store.subscription_store, &query.settings_part);
+        let mut stream = EntryStream { store, query_part, role, changes, known: HashSet::new(), _slot: slot };
+        let events = diff(&mut stream.known, result);
+        Ok(Ok((stream, events)))
+    }
+
+    /// The changes of the result after the next batch of updates, empty if there was none within the timeout.
+    /// An error if the stream fell behind, the cursor expired or the store closed.
+    pub async fn next_events(&mut self, timeout: Duration) -> anyhow::Result<Vec<EntryEvent>> {
+        match tokio::time::timeout(timeout, self.changes.recv()).await {
+            Err(_elapsed) => { return Ok(Vec::new()); },
+            Ok(Ok(())) => {},
+            Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                info!("Closing an entry stream that fell {} updates behind", skipped);
+                return Err(anyhow::anyhow!("The stream fell behind by {} updates", skipped));
+            },
+            Ok(Err(broadcast::error::RecvError::Closed)) => { return Err(anyhow::anyhow!("The entry store closed the subscription")); },
+        }
+        let (store, query_part, role) = (self.store.clone(), self.query_part.clone(), self.role);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut result = CosmosRustBotStoreInquirer(&store).entries_query(&query_part);
+            access::retain_visible(role, &mut result);
+            result
+        }).await?;
+        if let Some(CosmosRustBotValue::CursorExpired(expired)) = result.first() {
+            return Err(anyhow::anyhow!(expired.message()));
+        }
+        Ok(diff(&mut self.known, result))
+    }
+}
+
+fn diff(known: &mut HashSet<Vec<u8>>, result: Vec<CosmosRustBotValue>) -> Vec<EntryEvent> {
+    let current: HashSet<Vec<u8>> = result.iter().map(|entry| entry.key()).collect();
+    let mut removed: Vec<&Vec<u8>> = known.difference(&current).collect();
+    removed.sort_unstable();
+    let mut events: Vec<EntryEvent> = removed.into_iter().map(|key| EntryEvent::Remove { key: to_hex(key) }).collect();
+    for entry in result {
+        let key = entry.key();
+        if !known.contains(&key) {
+            events.push(EntryEvent::Insert { key: to_hex(&key), entry });
+        }
+    }
+    *known = current;
+    events
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{diff, to_hex, EntryEvent, EntryStream, StreamHub, StreamOwner};
+    use crate::utils::entry::db::{CosmosRustBotStore, SubscriptionStore};
+    use crate::utils::entry::{CosmosRustBotValue, CustomData, Debug, EntriesQueryPart, Entry, Index, QuotaKind, SettingsPart, Value, ValueImperative};
+    use cosmos_rust_package::tokio;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn entry(key: &str, value: &str) -> CosmosRustBotValue {
+        CosmosRustBotValue::Entry(Entry::Value(Value {
+            timestamp: 0,
+            origin: "task_meta_data_gov_proposals".to_string(),
+            custom_data: CustomData::Debug(Debug { key: key.to_string(), value: value.to_string() }),
+            imperative: ValueImperative::Notify,
+        }))
+    }
+
+    #[test]
+    pub fn result_changes_as_events() {
+        let mut known = HashSet::new();
+        let first = diff(&mut known, vec![entry("1", "voting"), entry("2", "voting")]);
+        assert_eq!(first.iter().map(|x| x.name()).collect::<Vec<&str>>(), vec!["insert", "insert"]);
+        assert!(diff(&mut known, vec![entry("1", "voting"), entry("2", "voting")]).is_empty());
+
+        // the tally of 1 changed, 2 left the result.
+        let mut changed = diff(&mut known, vec![entry("1", "passed")]);
+        assert_eq!(changed.len(), 3);
+        assert_eq!(changed.pop(), Some(EntryEvent::Insert { key: to_hex(&entry("1", "passed").key()), entry: entry("1", "passed") }));
+        let mut removed = vec![to_hex(&entry("1", "voting").key()), to_hex(&entry("2", "voting").key())];
+        removed.sort_unstable();
+        assert_eq!(changed, removed.into_iter().map(|key| EntryEvent::Remove { key }).collect::<Vec<EntryEvent>>());
+    }
+
+    #[tokio::test]
+    pub async fn stream_an_inserted_entry() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        // quotas and audit records are written to the subscription store, which is not watched.
+        let subscription_db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = CosmosRustBotStore::new(db, SubscriptionStore::new(&subscription_db)).unwrap();
+        let hub = StreamHub::with_limits(&store, 2, 1);
+        let query_part: EntriesQueryPart = serde_json::from_value(serde_json::json!({"message": "test", "display": "default", "indices": ["test_index"], "filter": [], "order_by": "", "limit": 10})).unwrap();
+
+        let (mut stream, events) = EntryStream::open(&hub, StreamOwner::User(1), &store, query_part.clone(), SettingsPart::default()).unwrap().unwrap();
+        assert!(events.is_empty());
+        match EntryStream::open(&hub, StreamOwner::User(1), &store, query_part.clone(), SettingsPart::default()).unwrap() {
+            Err(CosmosRustBotValue::QuotaExceeded(exceeded)) => assert_eq!((exceeded.kind, exceeded.global), (QuotaKind::ConcurrentStreams, false)),
+            _ => panic!("a second stream of the same user is rejected"),
+        }
+
+        let item = entry("1", "voting");
+        let index = CosmosRustBotValue::Index(Index { name: "test_index".to_string(), list: vec![item.key()] });
+        store.update_items(vec![item.clone(), index]);
+        let events = stream.next_events(Duration::from_secs(10)).await.unwrap();
+        assert_eq!(events, vec![EntryEvent::Insert { key: to_hex(&item.key()), entry: item }]);
+
+        // closing the stream releases it.
+        drop(stream);
+        assert!(EntryStream::open(&hub, StreamOwner::User(1), &store, query_part, SettingsPart::default()).unwrap().is_ok());
+    }
+}
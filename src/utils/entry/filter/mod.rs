@@ -0,0 +1,242 @@
+use regex::Regex;
+use cosmos_rust_package::chrono::{Duration, Utc};
+
+// Filter predicates are encoded into the value of a filter `(field, value)` pair,
+// this keeps `EntriesQueryPart.filter` (and the stored subscriptions) compatible:
+//
+// "any"          matches everything
+// "x" or "==x"   equals
+// "!=x"          not equals
+// ">x" ">=x"     greater than (or equal), numeric if both sides are numbers, else lexicographic
+// "<x" "<=x"     less than (or equal)
+// "~x"           contains (case-insensitive)
+// "re:x"         regular expression
+// "in:a,b,c"     equals one of
+// "within:24h"   timestamp between now and now + duration (s,m,h,d,w), negative durations look back,
+//                "within:today" ends at midnight UTC.
+
+#[derive(Debug, Clone)]
+pub enum FilterPredicate {
+    Any,
+    Eq(String),
+    NotEq(String),
+    Gt(String),
+    GtEq(String),
+    Lt(String),
+    LtEq(String),
+    Contains(String),
+    Regex(Regex),
+    In(Vec<String>),
+    Within(i64,i64),
+}
+
+impl FilterPredicate {
+
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        Ok(if value == "any" {
+            FilterPredicate::Any
+        } else if let Some(v) = value.strip_prefix("==") {
+            FilterPredicate::Eq(v.to_string())
+        } else if let Some(v) = value.strip_prefix("!=") {
+            FilterPredicate::NotEq(v.to_string())
+        } else if let Some(v) = value.strip_prefix(">=") {
+            FilterPredicate::GtEq(v.to_string())
+        } else if let Some(v) = value.strip_prefix("<=") {
+            FilterPredicate::LtEq(v.to_string())
+        } else if let Some(v) = value.strip_prefix('>') {
+            FilterPredicate::Gt(v.to_string())
+        } else if let Some(v) = value.strip_prefix('<') {
+            FilterPredicate::Lt(v.to_string())
+        } else if let Some(v) = value.strip_prefix('~') {
+            FilterPredicate::Contains(v.to_lowercase())
+        } else if let Some(v) = value.strip_prefix("re:") {
+            FilterPredicate::Regex(Regex::new(v)?)
+        } else if let Some(v) = value.strip_prefix("in:") {
+            FilterPredicate::In(v.split(',').map(|x| x.trim().to_string()).collect())
+        } else if let Some(v) = value.strip_prefix("within:") {
+            let now = Utc::now().timestamp();
+            let until = if v == "today" {
+                now - now.rem_euclid(Duration::days(1).num_seconds()) + Duration::days(1).num_seconds()
+            } else {
+                now.checked_add(parse_duration(v)?).ok_or(anyhow::anyhow!("Error: duration out of range: {}", v))?
+            };
+            FilterPredicate::Within(now.min(until), now.max(until))
+        } else {
+            FilterPredicate::Eq(value.to_string())
+        })
+    }
+
+    pub fn matches(&self, field: &serde_json::Value) -> bool {
+        let text = match field {
+            serde_json::Value::String(s) => s.to_owned(),
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::Bool(b) => b.to_string(),
+            _ => { return matches!(self, FilterPredicate::Any); }
+        };
+        match self {
+            FilterPredicate::Any => true,
+            FilterPredicate::Eq(v) => &text == v,
+            FilterPredicate::NotEq(v) => &text != v,
+            FilterPredicate::Gt(v) => compare(&text, v).is_gt(),
+            FilterPredicate::GtEq(v) => compare(&text, v).is_ge(),
+            FilterPredicate::Lt(v) => compare(&text, v).is_lt(),
+            FilterPredicate::LtEq(v) => compare(&text, v).is_le(),
+            FilterPredicate::Contains(v) => text.to_lowercase().contains(v.as_str()),
+            FilterPredicate::Regex(r) => r.is_match(&text),
+            FilterPredicate::In(list) => list.contains(&text),
+            FilterPredicate::Within(from, until) => {
+                text.parse::<i64>().map(|t| *from <= t && t <= *until).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// A time-relative predicate ("within:"), its result changes as time passes.
+pub fn is_time_relative(value: &str) -> bool {
+    value.starts_with("within:")
+}
+
+fn compare(a: &str, b: &str) -> std::cmp::Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// e.g. 30m, 24h, -7d
+fn parse_duration(text: &str) -> anyhow::Result<i64> {
+    // the unit may be any character, split on a char boundary.
+    let (index, unit) = text.char_indices().last().ok_or(anyhow::anyhow!("Error: empty duration"))?;
+    let number = text[..index].parse::<i64>().map_err(|_| anyhow::anyhow!("Error: invalid duration: {}", text))?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        'w' => 60 * 60 * 24 * 7,
+        _ => { return Err(anyhow::anyhow!("Error: invalid duration unit: {}", text)); }
+    };
+    number.checked_mul(seconds).ok_or(anyhow::anyhow!("Error: duration out of range: {}", text))
+}
+
+/// Parses the textual filter syntax used by the Telegram commands into `EntriesQueryPart.filter`.
+///
+/// `fraud_risk < 0.3 and proposal_VotingEndTime within today or proposal_title contains "Osmosis incentives"`
+///
+/// Operators: `=`, `==`, `!=`, `>`, `>=`, `<`, `<=`, `contains`, `~`, `matches`, `in` (a,b,c), `within` (24h, -7d, today).
+/// `and` binds stronger than `or`.
+pub fn parse_filter(text: &str) -> anyhow::Result<Vec<Vec<(String, String)>>> {
+    let tokens = tokenize(text)?;
+    let mut filter: Vec<Vec<(String, String)>> = vec![Vec::new()];
+    let mut i = 0;
+    while i < tokens.len() {
+        if i + 2 >= tokens.len() {
+            return Err(anyhow::anyhow!("Error: incomplete filter expression at: {}", tokens[i..].join(" ")));
+        }
+        let (field, operator, value) = (&tokens[i], tokens[i + 1].to_lowercase(), &tokens[i + 2]);
+        let encoded = match operator.as_str() {
+            "=" | "==" => value.to_string(),
+            "!=" => format!("!={}", value),
+            ">" | ">=" | "<" | "<=" => format!("{}{}", operator, value),
+            "contains" | "~" => format!("~{}", value),
+            "matches" => format!("re:{}", value),
+            "in" => format!("in:{}", value),
+            "within" => format!("within:{}", value),
+            _ => { return Err(anyhow::anyhow!("Error: unknown filter operator: {}", operator)); }
+        };
+        // fail early on invalid regular expressions or durations.
+        FilterPredicate::parse(&encoded)?;
+        filter.last_mut().unwrap().push((field.to_string(), encoded));
+        i += 3;
+        if i < tokens.len() {
+            match tokens[i].to_lowercase().as_str() {
+                "and" => {},
+                "or" => { filter.push(Vec::new()); },
+                other => { return Err(anyhow::anyhow!("Error: expected 'and' or 'or', found: {}", other)); }
+            }
+            i += 1;
+            if i == tokens.len() {
+                return Err(anyhow::anyhow!("Error: filter expression ends with a connective"));
+            }
+        }
+    }
+    filter.retain(|x| !x.is_empty());
+    Ok(filter)
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<String>> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => { return Err(anyhow::anyhow!("Error: unterminated quote in filter expression")); }
+                }
+            }
+            tokens.push(token);
+        } else if "<>=!~".contains(c) {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if !"<>=!~".contains(c) { break; }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "<>=!~\"".contains(c) { break; }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{is_time_relative, parse_filter, FilterPredicate};
+
+    #[test]
+    pub fn parse_filter_expression() -> anyhow::Result<()> {
+        let filter = parse_filter("fraud_risk<0.3 and proposal_status in StatusVotingPeriod,StatusPassed or proposal_title contains \"Osmosis incentives\"")?;
+        assert_eq!(filter, vec![
+            vec![("fraud_risk".to_string(), "<0.3".to_string()), ("proposal_status".to_string(), "in:StatusVotingPeriod,StatusPassed".to_string())],
+            vec![("proposal_title".to_string(), "~Osmosis incentives".to_string())],
+        ]);
+        // re-evaluated as time passes.
+        let filter = parse_filter("proposal_VotingEndTime within today")?;
+        assert!(is_time_relative(&filter[0][0].1));
+        assert!(!is_time_relative("<0.3"));
+        assert!(parse_filter("fraud_risk <").is_err());
+        assert!(parse_filter("proposal_title matches \"(\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn match_predicates() -> anyhow::Result<()> {
+        assert!(FilterPredicate::parse("<0.3")?.matches(&serde_json::json!("0.25")));
+        assert!(!FilterPredicate::parse("<0.3")?.matches(&serde_json::json!("0.5")));
+        assert!(FilterPredicate::parse("!=terra2")?.matches(&serde_json::json!("osmosis")));
+        assert!(FilterPredicate::parse("107")?.matches(&serde_json::json!(107)));
+        assert!(FilterPredicate::parse("within:1h")?.matches(&serde_json::json!(cosmos_rust_package::chrono::Utc::now().timestamp() + 60)));
+        assert!(!FilterPredicate::parse("within:1h")?.matches(&serde_json::json!(cosmos_rust_package::chrono::Utc::now().timestamp() - 60)));
+        // stored subscriptions are parsed on every refresh, invalid durations must not panic.
+        assert!(FilterPredicate::parse("within:1é").is_err());
+        assert!(FilterPredicate::parse("within:é").is_err());
+        assert!(FilterPredicate::parse("within:").is_err());
+        assert!(FilterPredicate::parse("within:99999999999999999w").is_err());
+        assert!(FilterPredicate::parse(&format!("within:{}s", i64::MAX)).is_err());
+        Ok(())
+    }
+}
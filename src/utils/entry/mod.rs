@@ -1,6 +1,5 @@
 use serde::{Serialize,Deserialize};
 use std::cmp::Ordering;
-use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::convert::From;
@@ -17,6 +16,10 @@ use cosmos_rust_package::api::custom::types::staking::pool_ext::PoolExt;
 
 use minify_html::{Cfg, minify};
 
+use crate::utils::hash::stable_hash;
+use crate::utils::escape_html;
+use crate::utils::config::Config;
+
 use cosmos_rust_package::chrono::{DateTime, Utc};
 
 #[cfg(feature = "postproc")]
@@ -25,6 +28,9 @@ pub mod postproc;
 #[cfg(feature = "db")]
 pub mod db;
 
+pub mod filter;
+pub mod search;
+
 #[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct Maybe<T> {
     pub data: Result<T,MaybeError>,
@@ -191,6 +197,42 @@ pub struct ProposalData {
     pub proposal_blockchain_pool_details: Option<String>,
     pub proposal_tally_result_detail: Option<String>,
     pub proposal_submitted: String,
+    #[serde(default)]
+    pub proposal_similar: Vec<SimilarProposal>,
+    #[serde(default)]
+    pub proposal_resubmission_of: Option<Resubmission>,
+}
+
+// a proposal with a similar description, ranked by the cosine similarity of their embeddings.
+#[derive(Serialize,Deserialize,Debug, Clone,PartialEq, Hash)]
+pub struct SimilarProposal {
+    pub blockchain: String,
+    pub proposal_id: u64,
+    pub title: String,
+    pub status: String,
+    pub vetoed: bool,
+    pub similarity: String,
+}
+
+// an earlier proposal with the same or a nearly identical text.
+#[derive(Serialize,Deserialize,Debug, Clone,PartialEq, Hash)]
+pub struct Resubmission {
+    pub original: SimilarProposal,
+    pub identical_text: bool,
+}
+
+impl Resubmission {
+    pub fn message(&self) -> String {
+        format!("This appears to be a resubmission of #{} on {} ({}){}.",
+                self.original.proposal_id,
+                self.original.blockchain,
+                self.original.title,
+                if self.original.vetoed {
+                    ", which was rejected with veto".to_string()
+                } else {
+                    format!(", status: {}", self.original.status)
+                })
+    }
 }
 
 impl ProposalData {
@@ -203,12 +245,15 @@ impl ProposalData {
                tallying_param: Option<ParamsExt>,
                deposit_param: Option<ParamsExt>,
                voting_param: Option<ParamsExt>,
-               blockchain_pool: Option<PoolExt>
+               blockchain_pool: Option<PoolExt>,
+               similar: Vec<SimilarProposal>,
+               resubmission_of: Option<Resubmission>,
+               config: &Config
     ) -> Self {
 
         Self {
             proposal_preview_msg: proposal.proposal_preview_msg(fraud_classification.clone()),
-            proposal_api: format!("https://libreai.de/cosmos-governance-proposals/{}/{}.html",proposal.blockchain.name.to_lowercase(),proposal.get_proposal_id()),
+            proposal_api: config.proposal_url(&proposal.blockchain.name, proposal.get_proposal_id()),
             proposal_link: proposal.governance_proposal_link(),
             proposal_summary: summary,
             proposal_briefing: briefing,
@@ -240,6 +285,8 @@ impl ProposalData {
             proposal_blockchain_pool_details: blockchain_pool.as_ref().map(|pool_ext| pool_ext.get_pool_details()).flatten(),
             proposal_tally_result_detail: tally_result.as_ref().map(|t| t.tally_details()),
             proposal_submitted: proposal.proposal_submitted(),
+            proposal_similar: similar,
+            proposal_resubmission_of: resubmission_of,
         }
 
     }
@@ -536,6 +583,21 @@ impl ProposalData {
     }
 
 
+    // links to similar proposals and how they ended, empty if there are none.
+    fn similar_proposals_html(&self) -> String {
+        self.proposal_similar.iter().map(|x| {
+            format!("<a href=\"../{}/{}.html\">#{} {}</a> on {}: {}{} (similarity {})",
+                    x.blockchain.to_lowercase(),
+                    x.proposal_id,
+                    x.proposal_id,
+                    escape_html(&x.title),
+                    x.blockchain,
+                    x.status,
+                    if x.vetoed { ", vetoed" } else { "" },
+                    x.similarity)
+        }).collect::<Vec<String>>().join("</br>")
+    }
+
     pub fn generate_map(&self) ->  HashMap<&str,String> {
         let unavailable = "This feature is currently only available for legitimate governance proposals.️";
         let summary = if self.proposal_spam_likelihood.parse::<f64>().unwrap_or(0.0) >= 0.5 {
@@ -573,6 +635,8 @@ impl ProposalData {
                      format!("Last updated: {}",timestamp)
                  }),
                 ("proposal_submitted", format!("Submitted: {}",self.proposal_submitted)), 
+                ("proposal_similar", self.similar_proposals_html()),
+                ("proposal_resubmission", self.proposal_resubmission_of.as_ref().map(|x| x.message()).unwrap_or("".to_string())),
                 ("website_language_label","Language:".to_string()),
                 ("website_overview_button","🅘 Overview".to_string()),
                 ("website_briefing_button","⚡ Briefing".to_string()),
@@ -581,11 +645,14 @@ impl ProposalData {
                 ("website_deposit_param_label","⚙️ Deposit Parameters".to_string()),
                 ("website_voting_param_label","⚙️ Voting Parameters".to_string()),
                 ("website_tallying_param_label","⚙️ Tallying Parameters".to_string()),
+                ("website_similar_label","🔗 Similar Past Proposals".to_string()),
                 ("website_footer","This website was created by <a href=\"https://github.com/Philipp-Sc/cosmos-rust-bot/tree/development/workspace/cosmos-rust-bot#readme\">CosmosRustBot</a>.</br>Give <a href=\"https://github.com/Philipp-Sc/cosmos-rust-bot/issues\">Feedback</a>.".to_string()),
                 ("js_const_fraud_warning","⚠ WARNING: Moderate fraud risk. Stay safe! ⚠".to_string()),
                 ("js_const_fraud_alert","🚨 ALERT: High fraud risk. Remember, if it seems too good to be true, it probably is. 🚨".to_string()),
                 ("js_const_high_veto_alert","🚨 ALERT: High fraud risk. High percentage of NoWithVeto votes! 🚨".to_string()),
                 ("js_const_deposit_period_warning","⚠ CAUTION: Fraud risk during deposit period. ⚠".to_string()),
+                ("js_const_resubmission_alert","🚨 ALERT: Resubmission of a vetoed proposal. 🚨".to_string()),
+                ("js_const_resubmission_warning","⚠ CAUTION: Resubmitted proposal. ⚠".to_string()),
         ]);
         map
     }
@@ -623,6 +690,14 @@ impl ProposalData {
                 alertDiv.innerText = data['js_const_fraud_alert'];
                 document.getElementById('fraud-alert').appendChild(alertDiv);
             }
+            else if (resubmissionOfVetoed) {
+                document.getElementsByClassName('container')[0].style.backgroundColor = '#5c1421';
+                document.getElementsByClassName('description')[0].classList.add('description-alert');
+                const alertDiv = document.createElement('div');
+                alertDiv.classList.add('alert');
+                alertDiv.innerText = data['js_const_resubmission_alert'] + ' ' + data['proposal_resubmission'];
+                document.getElementById('fraud-alert').appendChild(alertDiv);
+            }
             else if (strongVeto >= 0.5) {
                 document.getElementsByClassName('container')[0].style.backgroundColor = '#5c1421';
                 document.getElementsByClassName('description')[0].classList.add('description-alert');
@@ -638,6 +713,13 @@ impl ProposalData {
                 warningDiv.innerText = data['js_const_fraud_warning'];
                 document.getElementById('fraud-alert').appendChild(warningDiv);
             }
+            else if (resubmission) {
+                document.getElementsByClassName('description')[0].classList.add('description-warning');
+                const warningDiv = document.createElement('div');
+                warningDiv.classList.add('warning');
+                warningDiv.innerText = data['js_const_resubmission_warning'] + ' ' + data['proposal_resubmission'];
+                document.getElementById('fraud-alert').appendChild(warningDiv);
+            }
             else if (depositPeriod) {
                 document.getElementsByClassName('description')[0].classList.add('description-warning');
                 const warningDiv = document.createElement('div');
@@ -711,7 +793,7 @@ impl ProposalData {
                     var element = document.getElementById(key);
                     element.innerHTML = data[key];
                 }}
-                else if (key == 'proposal_summary' || key == 'proposal_briefing' || key.includes('js_const'))
+                else if (key == 'proposal_summary' || key == 'proposal_briefing' || key == 'proposal_resubmission' || key.includes('js_const'))
                 {{
                 }}
                 else if (key == 'proposal_id') {{
@@ -739,6 +821,8 @@ impl ProposalData {
             const fraudRisk = {};
             const strongVeto = {};
             const depositPeriod = {};
+            const resubmission = {};
+            const resubmissionOfVetoed = {};
             const proposalData = {{
                               summary: data.proposal_summary,
                               briefing: data.proposal_briefing,
@@ -753,6 +837,8 @@ impl ProposalData {
                          self.fraud_risk,
                          self.proposal_spam_likelihood.parse::<f64>().unwrap_or(0.0),
                          self.proposal_in_deposit_period.to_string(),
+                         self.proposal_resubmission_of.is_some().to_string(),
+                         self.proposal_resubmission_of.as_ref().map(|x| x.original.vetoed).unwrap_or(false).to_string(),
                          js_onload);
 
         let output = format!(
@@ -853,6 +939,13 @@ impl ProposalData {
     </div>
  </div>
 
+ <div class=\"status-text-no-pre-warp content-is-empty\">
+     <div class=\"status-text-expandable\">
+      <span class=\"toggle\">►</span><span id=\"website_similar_label\">🔗 Similar Past Proposals</span>
+      <div id=\"proposal_similar\" class=\"init-class content\">ProposalSimilar</div>
+    </div>
+ </div>
+
     <div class=\"description\">
       <span id=\"proposal_description\" class=\"init-class\" style=\"white-space: pre-wrap\">ProposalDescription</span>
       <div class=\"show-more\">
@@ -952,10 +1045,11 @@ pub enum Entry {
 }
 
 impl Entry {
+    // the timestamp and imperative are not part of the identity of an entry, see `impl Hash for Value`.
     fn get_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
-        self.hash(&mut s);
-        s.finish()
+        match self {
+            Entry::Value(value) => stable_hash(&(&value.origin, &value.custom_data)),
+        }
     }
     pub fn get_prefix() -> Vec<u8> {
         let mut k: Vec<u8> = Vec::new();
@@ -964,7 +1058,7 @@ impl Entry {
     }
     pub fn get_key(&self) -> Vec<u8> {
         let mut k: Vec<u8> = Entry::get_prefix();
-        k.append(&mut self.get_hash().to_ne_bytes().to_vec());
+        k.append(&mut self.get_hash().to_be_bytes().to_vec());
         k
     }
 }
@@ -983,12 +1077,13 @@ pub struct Subscription {
     pub query: QueryPart,
     pub user_list: HashSet<u64>,
     pub list: Vec<Vec<u8>>,
+    // users without a schedule are notified immediately.
+    #[serde(default)]
+    pub schedules: HashMap<u64, DeliverySchedule>,
 }
 impl Subscription {
     fn get_hash(query_part: &QueryPart) -> u64 {
-        let mut s = DefaultHasher::new();
-        query_part.hash(&mut s);
-        s.finish()
+        query_part.get_hash()
     }
     fn calculate_hash(&self) -> u64 {
         Subscription::get_hash(&self.query)
@@ -1000,14 +1095,12 @@ impl Subscription {
     }
     pub fn get_key(&self) -> Vec<u8> {
         let mut k: Vec<u8> = Subscription::get_prefix();
-        k.append(&mut self.calculate_hash().to_ne_bytes().to_vec());
+        k.append(&mut self.calculate_hash().to_be_bytes().to_vec());
         k
     }
     pub fn get_key_for_entries_query(query: &EntriesQueryPart) -> Vec<u8> {
         let mut k: Vec<u8> = Subscription::get_prefix();
-        let mut s = DefaultHasher::new();
-        query.hash(&mut s);
-        k.append(&mut s.finish().to_ne_bytes().to_vec());
+        k.append(&mut query.get_hash().to_be_bytes().to_vec());
         k
     }
     pub fn add_user_hash(&mut self, user_hash: u64) {
@@ -1019,12 +1112,25 @@ impl Subscription {
     pub fn remove_user_hash(&mut self, user_hash: u64) -> bool {
         self.user_list.remove(&user_hash)
     }
+    // `None` (or immediate delivery without quiet hours) removes the schedule.
+    pub fn set_schedule(&mut self, user_hash: u64, schedule: Option<DeliverySchedule>) {
+        match schedule {
+            Some(schedule) if schedule != DeliverySchedule::default() => { self.schedules.insert(user_hash, schedule); },
+            _ => { self.schedules.remove(&user_hash); },
+        }
+    }
 }
 
+// only a salted hash of the token is stored, see `db::auth`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Registration {
-    pub token: u64,
     pub user_hash: u64,
+    // hex encoded SHA-256 of salt and token.
+    pub token_hash: String,
+    pub salt: String,
+    pub issued: i64,
+    pub expires: i64,
+    pub revoked: bool,
 }
 impl Registration {
     pub fn get_prefix() -> Vec<u8> {
@@ -1034,7 +1140,7 @@ impl Registration {
     }
     pub fn get_key_for_user_hash(user_hash: u64) -> Vec<u8> {
         let mut k: Vec<u8> = Registration::get_prefix();
-        k.append(&mut user_hash.to_ne_bytes().to_vec());
+        k.append(&mut user_hash.to_be_bytes().to_vec());
         k
     }
     pub fn get_key(&self) -> Vec<u8> {
@@ -1042,6 +1148,273 @@ impl Registration {
     }
 }
 
+// short-lived and single use, exchanged for a new token with a `LoginQueryPart`, see `db::auth`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LoginCode {
+    pub user_hash: u64,
+    // hex encoded SHA-256 of salt and code.
+    pub code_hash: String,
+    pub salt: String,
+    pub expires: i64,
+}
+impl LoginCode {
+    pub fn get_key_for_user_hash(user_hash: u64) -> Vec<u8> {
+        user_hash.to_be_bytes().to_vec()
+    }
+}
+impl TryFrom<Vec<u8>> for LoginCode {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+impl TryFrom<LoginCode> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: LoginCode) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum QuotaKind {
+    QueriesPerMinute,
+    ActiveSubscriptions,
+    NotificationsPerDay,
+    ConcurrentStreams,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct QuotaLimits {
+    pub queries_per_minute: u32,
+    pub active_subscriptions: u32,
+    pub notifications_per_day: u32,
+}
+
+// usage of a user, or of all users (`user_hash` None), stored next to the Registration, see `db::quota`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Quota {
+    pub user_hash: Option<u64>,
+    // overrides the default limits.
+    pub limits: Option<QuotaLimits>,
+    pub minute: i64,
+    pub queries: u32,
+    pub day: i64,
+    pub notifications: u32,
+    // day the user was last told that the notification quota is exhausted.
+    pub exceeded_reported_day: i64,
+}
+impl Quota {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"quota".to_vec());
+        k
+    }
+    pub fn get_key_for_user_hash(user_hash: Option<u64>) -> Vec<u8> {
+        let mut k: Vec<u8> = Quota::get_prefix();
+        match user_hash {
+            Some(user_hash) => k.append(&mut user_hash.to_be_bytes().to_vec()),
+            None => k.append(&mut b"global".to_vec()),
+        }
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        Quota::get_key_for_user_hash(self.user_hash)
+    }
+}
+
+// returned once when a token is issued or rotated, never stored.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct IssuedToken {
+    pub user_hash: u64,
+    pub token: String,
+    pub expires: i64,
+    // one-time code for the login link, the token itself is never part of a stored message.
+    pub login_code: String,
+}
+impl IssuedToken {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"issued_token".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = IssuedToken::get_prefix();
+        k.append(&mut self.user_hash.to_be_bytes().to_vec());
+        k
+    }
+}
+
+// subscription notifications of the user are also POSTed to the url, see `notification::webhook`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Webhook {
+    pub user_hash: u64,
+    pub url: String,
+    // shared secret for the HMAC signature, never part of a query result.
+    pub secret: String,
+}
+impl Webhook {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"webhook".to_vec());
+        k
+    }
+    pub fn get_key_for_user_hash(user_hash: u64) -> Vec<u8> {
+        let mut k: Vec<u8> = Webhook::get_prefix();
+        k.append(&mut user_hash.to_be_bytes().to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        Webhook::get_key_for_user_hash(self.user_hash)
+    }
+}
+
+// returned instead of a query result, turned into a Notify message for the user.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct QuotaExceeded {
+    // None for an anonymous query.
+    pub user_hash: Option<u64>,
+    pub kind: QuotaKind,
+    pub limit: u32,
+    // the limit of all users together was reached.
+    pub global: bool,
+}
+impl QuotaExceeded {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"quota_exceeded".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = QuotaExceeded::get_prefix();
+        k.append(&mut stable_hash(self).to_be_bytes().to_vec());
+        k
+    }
+    pub fn message(&self) -> String {
+        if self.global {
+            return match self.kind {
+                QuotaKind::QueriesPerMinute => "The bot is busy right now, please try again in a minute.".to_string(),
+                QuotaKind::ActiveSubscriptions => "No new subscriptions can be created at the moment.".to_string(),
+                QuotaKind::NotificationsPerDay => "The daily notification limit of the bot has been reached, notifications resume tomorrow.".to_string(),
+                QuotaKind::ConcurrentStreams => "Too many open streams, please try again later.".to_string(),
+            };
+        }
+        match self.kind {
+            QuotaKind::QueriesPerMinute => format!("Quota exceeded: at most {} queries per minute, please try again in a minute.", self.limit),
+            QuotaKind::ActiveSubscriptions => format!("Quota exceeded: at most {} active subscriptions, unsubscribe from one to add another.", self.limit),
+            QuotaKind::NotificationsPerDay => format!("Quota exceeded: at most {} notifications per day, notifications resume tomorrow.", self.limit),
+            QuotaKind::ConcurrentStreams => format!("Quota exceeded: at most {} open streams, close one to open another.", self.limit),
+        }
+    }
+}
+
+// declared from least to most privileged, see `db::access`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum Role {
+    Anonymous,
+    User,
+    Admin,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Anonymous => write!(f, "anonymous"),
+            Role::User => write!(f, "user"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+// role of a user other than the default, stored next to the Registration.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RoleAssignment {
+    pub user_hash: u64,
+    pub role: Role,
+}
+impl RoleAssignment {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"role".to_vec());
+        k
+    }
+    pub fn get_key_for_user_hash(user_hash: u64) -> Vec<u8> {
+        let mut k: Vec<u8> = RoleAssignment::get_prefix();
+        k.append(&mut user_hash.to_be_bytes().to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        RoleAssignment::get_key_for_user_hash(self.user_hash)
+    }
+}
+
+// returned instead of a query result, turned into a Notify message for the user.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct AccessDenied {
+    pub user_hash: Option<u64>,
+    pub required: Role,
+    // the query part or index that was denied.
+    pub resource: String,
+}
+impl AccessDenied {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"access_denied".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = AccessDenied::get_prefix();
+        k.append(&mut stable_hash(self).to_be_bytes().to_vec());
+        k
+    }
+    pub fn message(&self) -> String {
+        match self.required {
+            Role::Admin => format!("Access denied: {} is only available to admins.", self.resource),
+            _ => format!("Access denied: {} requires a registered user.", self.resource),
+        }
+    }
+}
+
+// the user's notifications are delivered to `channel` from now on, see `ChannelQueryPart`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelChanged {
+    pub user_hash: u64,
+    // "telegram", "matrix", "discord" or "email".
+    pub channel: String,
+}
+impl ChannelChanged {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"channel_changed".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = ChannelChanged::get_prefix();
+        k.append(&mut self.user_hash.to_be_bytes().to_vec());
+        k
+    }
+}
+
+// returned instead of a page, the entry of `EntriesQueryPart::cursor` is no longer part of the result.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CursorExpired {
+    pub cursor: Vec<u8>,
+}
+impl CursorExpired {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"cursor_expired".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = CursorExpired::get_prefix();
+        k.append(&mut self.cursor.to_owned());
+        k
+    }
+    pub fn message(&self) -> String {
+        "This page is no longer available, the results changed. Please run the query again.".to_string()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Authorization {
     pub is_authorized: bool,
@@ -1055,7 +1428,7 @@ impl Authorization {
     }
     pub fn get_key_for_user_hash(user_hash: u64) -> Vec<u8> {
         let mut k: Vec<u8> = Authorization::get_prefix();
-        k.append(&mut user_hash.to_ne_bytes().to_vec());
+        k.append(&mut user_hash.to_be_bytes().to_vec());
         k
     }
     pub fn get_key(&self) -> Vec<u8> {
@@ -1068,12 +1441,15 @@ pub struct Notification {
     pub query: UserQuery,
     pub entries: Vec<CosmosRustBotValue>,
     pub user_list: HashSet<u64>,
+    #[serde(default)]
+    pub schedules: HashMap<u64, DeliverySchedule>,
+    // identifies a subscription update, the notification service delivers it only once.
+    #[serde(default)]
+    pub delivery_key: Option<u64>,
 }
 impl Notification {
     pub fn calculate_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
-        self.query.hash(&mut s);
-        s.finish()
+        self.query.get_hash()
     }
     fn get_hash(&self) -> u64 {
         Notification::calculate_hash(self)
@@ -1085,14 +1461,12 @@ impl Notification {
     }
     pub fn get_key(&self) -> Vec<u8> {
         let mut k: Vec<u8> = Notification::get_prefix();
-        k.append(&mut self.get_hash().to_ne_bytes().to_vec());
+        k.append(&mut self.get_hash().to_be_bytes().to_vec());
         k
     }
     pub fn get_key_for_query(query: &QueryPart) -> Vec<u8> {
         let mut k: Vec<u8> = Notification::get_prefix();
-        let mut s = DefaultHasher::new();
-        query.hash(&mut s);
-        k.append(&mut s.finish().to_ne_bytes().to_vec());
+        k.append(&mut query.get_hash().to_be_bytes().to_vec());
         k
     }
 
@@ -1115,12 +1489,7 @@ pub struct Notify {
 }
 impl Notify {
     pub fn calculate_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
-        self.msg.hash(&mut s);
-        self.buttons.hash(&mut s);
-        self.timestamp.hash(&mut s);
-        self.user_hash.hash(&mut s);
-        s.finish()
+        stable_hash(&(&self.msg, &self.buttons, self.timestamp, self.user_hash))
     }
     fn get_hash(&self) -> u64 {
         Notify::calculate_hash(self)
@@ -1132,7 +1501,27 @@ impl Notify {
     }
     pub fn get_key(&self) -> Vec<u8> {
         let mut k: Vec<u8> = Notify::get_prefix();
-        k.append(&mut self.get_hash().to_ne_bytes().to_vec());
+        k.append(&mut self.get_hash().to_be_bytes().to_vec());
+        k
+    }
+}
+
+// sent by the frontend after it tried to deliver a Notify, see `notification::outbox`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NotifyAck {
+    pub notify_key: Vec<u8>,
+    pub delivered: bool,
+    pub error: Option<String>,
+}
+impl NotifyAck {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"notify_ack".to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = NotifyAck::get_prefix();
+        k.append(&mut self.notify_key.to_vec());
         k
     }
 }
@@ -1150,6 +1539,9 @@ impl UserQuery {
             settings_part: SettingsPart::default(),
         }
     }
+    pub fn get_hash(&self) -> u64 {
+        stable_hash(&(self.query_part.get_hash(), self.settings_part.get_hash()))
+    }
 }
 
 impl Hash for UserQuery {
@@ -1177,6 +1569,15 @@ pub struct SettingsPart {
     pub unsubscribe: Option<bool>,
     pub register: Option<bool>,
     pub user_hash: Option<u64>,
+    // applied to the subscription when subscribing.
+    #[serde(default)]
+    pub delivery: Option<DeliverySchedule>,
+    // with a RegisterQueryPart, revokes the token of the user.
+    #[serde(default)]
+    pub revoke: Option<bool>,
+    // token of the user, roles other than `Role::User` require it, see `db::access`.
+    #[serde(default)]
+    pub token: Option<String>,
 }
 impl Default for SettingsPart {
     fn default() -> Self {
@@ -1185,6 +1586,50 @@ impl Default for SettingsPart {
             unsubscribe: None,
             register: None,
             user_hash: None,
+            delivery: None,
+            revoke: None,
+            token: None,
+        }
+    }
+}
+impl SettingsPart {
+    // the token is not part of the key of a query.
+    pub fn get_hash(&self) -> u64 {
+        stable_hash(&(self.subscribe, self.unsubscribe, self.register, self.user_hash, &self.delivery, self.revoke))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum DeliveryMode {
+    Immediate,
+    HourlyDigest,
+    // local time of the user.
+    DailyDigest { hour: u8, minute: u8 },
+    // weekday 0 is Monday.
+    WeeklyDigest { weekday: u8, hour: u8, minute: u8 },
+}
+
+// local time of day in minutes since midnight, the range may wrap around midnight (e.g. 22:00 - 07:00).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct QuietHours {
+    pub from_minute: u16,
+    pub to_minute: u16,
+}
+
+// when subscription updates are delivered to a user, see `notification::digest`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct DeliverySchedule {
+    pub mode: DeliveryMode,
+    // time zone of the user as offset to UTC.
+    pub utc_offset_minutes: i32,
+    pub quiet_hours: Option<QuietHours>,
+}
+impl Default for DeliverySchedule {
+    fn default() -> Self {
+        Self {
+            mode: DeliveryMode::Immediate,
+            utc_offset_minutes: 0,
+            quiet_hours: None,
         }
     }
 }
@@ -1194,7 +1639,10 @@ pub enum QueryPart {
     RegisterQueryPart(RegisterQueryPart),
     AuthQueryPart(AuthQueryPart),
     EntriesQueryPart(EntriesQueryPart),
-    SubscriptionsQueryPart(SubscriptionsQueryPart)
+    SubscriptionsQueryPart(SubscriptionsQueryPart),
+    WebhookQueryPart(WebhookQueryPart),
+    LoginQueryPart(LoginQueryPart),
+    ChannelQueryPart(ChannelQueryPart),
 }
 
 impl Display for QueryPart {
@@ -1212,6 +1660,31 @@ impl Display for QueryPart {
             QueryPart::SubscriptionsQueryPart(_subscriptions_query_part) => {
                 write!(f, "SubscriptionsQueryPart(..)")
             }
+            QueryPart::WebhookQueryPart(_webhook_query_part) => {
+                write!(f, "WebhookQueryPart(..)")
+            }
+            QueryPart::LoginQueryPart(_login_query_part) => {
+                write!(f, "LoginQueryPart(..)")
+            }
+            QueryPart::ChannelQueryPart(channel_query_part) => {
+                write!(f, "ChannelQueryPart({})", channel_query_part.name())
+            }
+        }
+    }
+}
+
+impl QueryPart {
+    // tokens, webhook secrets, login codes and channel settings are not part of the key of a query,
+    // an entries query has the key of its subscription.
+    pub fn get_hash(&self) -> u64 {
+        match self {
+            QueryPart::EntriesQueryPart(q) => q.get_hash(),
+            QueryPart::SubscriptionsQueryPart(q) => stable_hash(&("subscriptions", &q.message)),
+            QueryPart::RegisterQueryPart(_) => stable_hash("register"),
+            QueryPart::AuthQueryPart(q) => stable_hash(&("auth", q.user_hash)),
+            QueryPart::WebhookQueryPart(q) => stable_hash(&("webhook", &q.url)),
+            QueryPart::LoginQueryPart(q) => stable_hash(&("login", q.user_hash)),
+            QueryPart::ChannelQueryPart(q) => stable_hash(&("channel", q.name())),
         }
     }
 }
@@ -1231,10 +1704,39 @@ impl Hash for QueryPart {
             QueryPart::AuthQueryPart(q) => {
                 q.hash(state);
             },
+            QueryPart::WebhookQueryPart(q) => {
+                q.hash(state);
+            },
+            QueryPart::LoginQueryPart(q) => {
+                q.hash(state);
+            },
+            QueryPart::ChannelQueryPart(q) => {
+                q.hash(state);
+            },
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum OrderDirection {
+    Asc,
+    Desc,
+}
+impl Default for OrderDirection {
+    fn default() -> Self {
+        // indices are stored in descending order.
+        OrderDirection::Desc
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct OrderBy {
+    pub field: String,
+    pub direction: OrderDirection,
+}
+
+// `#[serde(default)]` only applies to JSON (HTTP gateway). Bincode (sockets, stores) requires every field,
+// values stored with a previous layout are rewritten by `migration::migrate_subscription_layout`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct EntriesQueryPart {
     pub message: String,
@@ -1243,6 +1745,38 @@ pub struct EntriesQueryPart {
     pub filter: Vec<Vec<(String, String)>>,
     pub order_by: String,
     pub limit: usize,
+    // direction applied to the `order_by` index.
+    #[serde(default)]
+    pub order_direction: OrderDirection,
+    // multi-key ordering by entry fields (e.g. chain first, then end time), takes precedence over `order_by`.
+    #[serde(default)]
+    pub order: Vec<OrderBy>,
+    // key of the last entry of the previous page, the result starts after it.
+    #[serde(default)]
+    pub cursor: Option<Vec<u8>>,
+    #[serde(default)]
+    pub offset: usize,
+    // full-text search, results are ranked by relevance unless `order` is given.
+    #[serde(default)]
+    pub search: Option<String>,
+    // ranks the results by similarity, applied after `search`.
+    #[serde(default)]
+    pub semantic: Option<SemanticQuery>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum SemanticQuery {
+    // entries similar to the first entry matching the filter,
+    // e.g. [("proposal_blockchain","osmosis"),("proposal_id","123")]
+    SimilarTo(Vec<(String, String)>),
+    // natural-language query, embedded with the same model as the entries.
+    Text(String),
+}
+impl EntriesQueryPart {
+    // identifies subscriptions, pagination is not part of the identity of a query.
+    pub fn get_hash(&self) -> u64 {
+        stable_hash(&(&self.message, &self.display, &self.indices, &self.filter, &self.order_by, self.limit as u64, &self.order_direction, &self.order, &self.search, &self.semantic))
+    }
 }
 impl Hash for EntriesQueryPart {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -1266,15 +1800,50 @@ pub struct RegisterQueryPart {}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
 pub struct AuthQueryPart {
-    pub token: u64,
+    // hex encoded, see `db::auth`.
+    pub token: String,
     pub user_hash: u64,
 }
 
+// exchanges the one-time code of the login link for a new token.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct LoginQueryPart {
+    pub user_hash: u64,
+    pub code: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
 pub struct SubscriptionsQueryPart {
     pub message: String,
 }
 
+// registers the webhook of the user, an empty url removes it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct WebhookQueryPart {
+    pub url: String,
+    pub secret: String,
+}
+
+// routes the notifications of the user to another channel, see `db::notification::channel`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum ChannelQueryPart {
+    Telegram,
+    Matrix { homeserver: String, access_token: String, room_id: String },
+    Discord { webhook_url: String },
+    // the summary is sent weekly, see `EmailConfig::new`.
+    Email { address: String },
+}
+impl ChannelQueryPart {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelQueryPart::Telegram => "telegram",
+            ChannelQueryPart::Matrix { .. } => "matrix",
+            ChannelQueryPart::Discord { .. } => "discord",
+            ChannelQueryPart::Email { .. } => "email",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct UserMetaData {
     pub timestamp: i64,
@@ -1287,15 +1856,13 @@ pub struct UserMetaData {
 }
 impl UserMetaData {
     pub fn user_hash(user_id: u64) -> u64 {
-        let mut s = DefaultHasher::new();
-        user_id.hash(&mut s);
-        s.finish()
+        stable_hash(&user_id)
     }
     fn get_hash(&self) -> u64 {
         UserMetaData::user_hash(self.user_id)
     }
     pub fn get_key(&self) -> Vec<u8> {
-        self.get_hash().to_ne_bytes().to_vec()
+        self.get_hash().to_be_bytes().to_vec()
     }
 }
 
@@ -1304,6 +1871,7 @@ pub enum CosmosRustServerValue {
     Notification(Notification),
     Notify(Notify),
     UserMetaData(UserMetaData),
+    NotifyAck(NotifyAck),
 }
 
 impl TryFrom<Vec<u8>> for CosmosRustServerValue {
@@ -1326,6 +1894,7 @@ impl CosmosRustServerValue {
             CosmosRustServerValue::Notification(entry) => entry.get_key(),
             CosmosRustServerValue::Notify(entry) => entry.get_key(),
             CosmosRustServerValue::UserMetaData(entry) => entry.get_key(),
+            CosmosRustServerValue::NotifyAck(entry) => entry.get_key(),
         }
     }
 }
@@ -1336,23 +1905,124 @@ pub struct Index {
     pub list: Vec<Vec<u8>>,
 }
 impl Index {
-    fn calculate_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
-        self.name.as_str().hash(&mut s);
-        self.list.hash(&mut s);
-        s.finish()
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"index".to_vec());
+        k
+    }
+    // keyed by name, the store compares the list to detect changes.
+    pub fn get_key_for_name(name: &str) -> Vec<u8> {
+        let mut k: Vec<u8> = Index::get_prefix();
+        k.append(&mut stable_hash(name).to_be_bytes().to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        Index::get_key_for_name(&self.name)
+    }
+}
+
+// definition of an index over all entries with `field`, ordered by its value.
+// The store maintains it incrementally as entries are added or removed and supports range scans.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub struct SortedIndex {
+    pub name: String,
+    pub field: String,
+}
+impl SortedIndex {
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"sorted".to_vec());
+        k
+    }
+    pub fn get_key_for_name(name: &str) -> Vec<u8> {
+        let mut k: Vec<u8> = SortedIndex::get_prefix();
+        k.append(&mut stable_hash(name).to_be_bytes().to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        SortedIndex::get_key_for_name(&self.name)
+    }
+    /// Order preserving byte encoding of a field value, consistent with `compare_field_values`:
+    /// numbers (also when encoded as strings) sort numerically and before all other values,
+    /// which sort lexicographically. Integers above 2^53 lose precision.
+    pub fn encode_value(value: &serde_json::Value) -> Option<Vec<u8>> {
+        let text = match value {
+            serde_json::Value::Null => { return None; },
+            serde_json::Value::String(s) => s.to_owned(),
+            other => other.to_string(),
+        };
+        let mut k: Vec<u8> = Vec::new();
+        match text.parse::<f64>() {
+            Ok(number) if !number.is_nan() => {
+                let bits = number.to_bits();
+                let ordered = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+                k.push(0u8);
+                k.append(&mut ordered.to_be_bytes().to_vec());
+            },
+            _ => {
+                k.push(1u8);
+                k.append(&mut text.into_bytes());
+                k.push(0u8);
+            }
+        }
+        Some(k)
     }
+}
+// inverted index used for full-text search, see `search`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+pub enum SearchIndex {
+    // entry keys containing the term, with the term frequency.
+    Term { term: String, postings: Vec<(Vec<u8>, u32)> },
+    // number of tokens of every indexed entry.
+    Documents { lengths: Vec<(Vec<u8>, u32)> },
+}
+impl SearchIndex {
     fn get_hash(&self) -> u64 {
-        Index::calculate_hash(self)
+        stable_hash(self)
     }
     pub fn get_prefix() -> Vec<u8> {
         let mut k: Vec<u8> = Vec::new();
-        k.append(&mut b"index".to_vec());
+        k.append(&mut b"search".to_vec());
+        k
+    }
+    // tokens are never empty, the empty term identifies the documents.
+    pub fn get_prefix_for_term(term: &str) -> Vec<u8> {
+        let mut k: Vec<u8> = SearchIndex::get_prefix();
+        k.append(&mut stable_hash(term).to_be_bytes().to_vec());
         k
     }
     pub fn get_key(&self) -> Vec<u8> {
-        let mut k: Vec<u8> = Index::get_prefix();
-        k.append(&mut self.get_hash().to_ne_bytes().to_vec());
+        let mut k: Vec<u8> = match self {
+            SearchIndex::Term { term, .. } => SearchIndex::get_prefix_for_term(term),
+            SearchIndex::Documents { .. } => SearchIndex::get_prefix_for_term(""),
+        };
+        k.append(&mut self.get_hash().to_be_bytes().to_vec());
+        k
+    }
+}
+// embedding of an entry, used for semantic search, see `search::vector`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Embedding {
+    pub entry_key: Vec<u8>,
+    pub vector: Vec<f32>,
+}
+impl Embedding {
+    fn get_hash(&self) -> u64 {
+        stable_hash(self)
+    }
+    pub fn get_prefix() -> Vec<u8> {
+        let mut k: Vec<u8> = Vec::new();
+        k.append(&mut b"embedding".to_vec());
+        k
+    }
+    pub fn get_prefix_for_entry(entry_key: &[u8]) -> Vec<u8> {
+        let mut k: Vec<u8> = Embedding::get_prefix();
+        k.append(&mut stable_hash(entry_key).to_be_bytes().to_vec());
+        k
+    }
+    pub fn get_key(&self) -> Vec<u8> {
+        let mut k: Vec<u8> = Embedding::get_prefix_for_entry(&self.entry_key);
+        k.append(&mut self.get_hash().to_be_bytes().to_vec());
         k
     }
 }
@@ -1363,6 +2033,17 @@ pub enum CosmosRustBotValue {
     Subscription(Subscription),
     Registration(Registration),
     Authorization(Authorization),
+    SearchIndex(SearchIndex),
+    Embedding(Embedding),
+    SortedIndex(SortedIndex),
+    Quota(Quota),
+    QuotaExceeded(QuotaExceeded),
+    Webhook(Webhook),
+    IssuedToken(IssuedToken),
+    RoleAssignment(RoleAssignment),
+    AccessDenied(AccessDenied),
+    ChannelChanged(ChannelChanged),
+    CursorExpired(CursorExpired),
 }
 
 impl TryFrom<Vec<u8>> for CosmosRustBotValue {
@@ -1387,6 +2068,17 @@ impl CosmosRustBotValue {
             CosmosRustBotValue::Subscription(sub) => sub.get_key(),
             CosmosRustBotValue::Registration(reg) => reg.get_key(),
             CosmosRustBotValue::Authorization(auth) => auth.get_key(),
+            CosmosRustBotValue::SearchIndex(search_index) => search_index.get_key(),
+            CosmosRustBotValue::Embedding(embedding) => embedding.get_key(),
+            CosmosRustBotValue::SortedIndex(index) => index.get_key(),
+            CosmosRustBotValue::Quota(quota) => quota.get_key(),
+            CosmosRustBotValue::QuotaExceeded(exceeded) => exceeded.get_key(),
+            CosmosRustBotValue::Webhook(webhook) => webhook.get_key(),
+            CosmosRustBotValue::IssuedToken(issued) => issued.get_key(),
+            CosmosRustBotValue::RoleAssignment(assignment) => assignment.get_key(),
+            CosmosRustBotValue::AccessDenied(denied) => denied.get_key(),
+            CosmosRustBotValue::ChannelChanged(changed) => changed.get_key(),
+            CosmosRustBotValue::CursorExpired(expired) => expired.get_key(),
         }
     }
     pub fn get(&self, field: &str) -> serde_json::Value {
@@ -1410,8 +2102,10 @@ impl CosmosRustBotValue {
                 &_ => serde_json::Value::Null,
             },
             CosmosRustBotValue::Registration(val) => match field {
-                "token" => serde_json::json!(val.token),
                 "user_hash" => serde_json::json!(val.user_hash),
+                "issued" => serde_json::json!(val.issued),
+                "expires" => serde_json::json!(val.expires),
+                "revoked" => serde_json::json!(val.revoked),
                 &_ => serde_json::Value::Null,
             },
             CosmosRustBotValue::Authorization(val) => match field {
@@ -1419,6 +2113,63 @@ impl CosmosRustBotValue {
                 "user_hash" => serde_json::json!(val.user_hash),
                 &_ => serde_json::Value::Null,
             },
+            CosmosRustBotValue::SearchIndex(val) => match (field, val) {
+                ("term", SearchIndex::Term { term, .. }) => serde_json::json!(term),
+                ("postings", SearchIndex::Term { postings, .. }) => serde_json::json!(postings),
+                ("lengths", SearchIndex::Documents { lengths }) => serde_json::json!(lengths),
+                _ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::Embedding(val) => match field {
+                "entry_key" => serde_json::json!(val.entry_key),
+                "vector" => serde_json::json!(val.vector),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::SortedIndex(val) => match field {
+                "name" => serde_json::json!(val.name),
+                "field" => serde_json::json!(val.field),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::Quota(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "queries" => serde_json::json!(val.queries),
+                "notifications" => serde_json::json!(val.notifications),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::QuotaExceeded(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "limit" => serde_json::json!(val.limit),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::Webhook(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "url" => serde_json::json!(val.url),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::IssuedToken(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "expires" => serde_json::json!(val.expires),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::RoleAssignment(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "role" => serde_json::json!(val.role.to_string()),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::AccessDenied(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "required" => serde_json::json!(val.required.to_string()),
+                "resource" => serde_json::json!(val.resource),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::ChannelChanged(val) => match field {
+                "user_hash" => serde_json::json!(val.user_hash),
+                "channel" => serde_json::json!(val.channel),
+                &_ => serde_json::Value::Null,
+            },
+            CosmosRustBotValue::CursorExpired(val) => match field {
+                "cursor" => serde_json::json!(val.cursor),
+                &_ => serde_json::Value::Null,
+            },
         }
     }
     pub fn add_variants_of_memberships(view: &mut Vec<CosmosRustBotValue>, fields: Vec<&str>) {
@@ -1480,9 +2231,9 @@ impl CosmosRustBotValue {
             list: have_field,
         }
     }
+    // adds the definition of a sorted index, the ordering itself is maintained by the store.
     pub fn add_index(entries: &mut Vec<CosmosRustBotValue>, field: &str, name: &str) {
-        let index = CosmosRustBotValue::create_index(entries, field, name);
-        entries.push(CosmosRustBotValue::Index(index));
+        entries.push(CosmosRustBotValue::SortedIndex(SortedIndex { name: name.to_string(), field: field.to_string() }));
     }
     pub fn create_index(entries: &Vec<CosmosRustBotValue>, field: &str, name: &str) -> Index {
         let mut have_field = entries
@@ -1495,56 +2246,94 @@ impl CosmosRustBotValue {
                 return None;
             })
             .collect::<Vec<(Vec<u8>, serde_json::Value)>>();
-        have_field.sort_by(|(_, first), (_, second)| match (first, second) {
-            (serde_json::Value::String(f), serde_json::Value::String(s)) => {
-                match (f.parse::<u64>(),s.parse::<u64>()) {
-                    (Ok(ff), Ok(ss)) => {
-                        ff.cmp(&ss)
-                    },
-                    _ => {
-                        match (f.parse::<f64>(),s.parse::<f64>()) {
-                            (Ok(ff), Ok(ss)) => {
-                                ff.total_cmp(&ss)
-                            },
-                            _ => {
-                                f.cmp(s)
-                            }
+        have_field.sort_by(|(_, first), (_, second)| compare_field_values(first, second));
+        Index {
+            name: name.to_string(),
+            list: have_field.into_iter().rev().map(|(key, _)| key).collect(),
+        }
+    }
+}
+
+// ordering used by indices: numbers (also when encoded as strings) numerically, everything else lexicographically.
+pub fn compare_field_values(first: &serde_json::Value, second: &serde_json::Value) -> Ordering {
+    match (first, second) {
+        (serde_json::Value::String(f), serde_json::Value::String(s)) => {
+            match (f.parse::<u64>(),s.parse::<u64>()) {
+                (Ok(ff), Ok(ss)) => {
+                    ff.cmp(&ss)
+                },
+                _ => {
+                    match (f.parse::<f64>(),s.parse::<f64>()) {
+                        (Ok(ff), Ok(ss)) => {
+                            ff.total_cmp(&ss)
+                        },
+                        _ => {
+                            f.cmp(s)
                         }
                     }
                 }
-            },
-            (serde_json::Value::Number(f), serde_json::Value::Number(s)) => {
-                if f.is_u64() && s.is_u64() {
-                    f.as_u64().unwrap().cmp(&s.as_u64().unwrap())
-                } else if f.is_i64() && s.is_i64() {
-                    f.as_i64().unwrap().cmp(&s.as_i64().unwrap())
-                } else if f.is_f64() && s.is_f64() {
-                    f.as_f64().unwrap().total_cmp(&s.as_f64().unwrap())
-                } else {
-                    Ordering::Equal
-                }
             }
-            _ => {
-                match (first.to_string().parse::<u64>(),second.to_string().parse::<u64>()) {
-                    (Ok(ff), Ok(ss)) => {
-                        ff.cmp(&ss)
-                    },
-                    _ => {
-                        match (first.to_string().parse::<f64>(),second.to_string().parse::<f64>()) {
-                            (Ok(ff), Ok(ss)) => {
-                                ff.total_cmp(&ss)
-                            },
-                            _ => {
-                                first.to_string().cmp(&second.to_string())
-                            }
+        },
+        (serde_json::Value::Number(f), serde_json::Value::Number(s)) => {
+            if f.is_u64() && s.is_u64() {
+                f.as_u64().unwrap().cmp(&s.as_u64().unwrap())
+            } else if f.is_i64() && s.is_i64() {
+                f.as_i64().unwrap().cmp(&s.as_i64().unwrap())
+            } else if f.is_f64() && s.is_f64() {
+                f.as_f64().unwrap().total_cmp(&s.as_f64().unwrap())
+            } else {
+                Ordering::Equal
+            }
+        }
+        _ => {
+            match (first.to_string().parse::<u64>(),second.to_string().parse::<u64>()) {
+                (Ok(ff), Ok(ss)) => {
+                    ff.cmp(&ss)
+                },
+                _ => {
+                    match (first.to_string().parse::<f64>(),second.to_string().parse::<f64>()) {
+                        (Ok(ff), Ok(ss)) => {
+                            ff.total_cmp(&ss)
+                        },
+                        _ => {
+                            first.to_string().cmp(&second.to_string())
                         }
                     }
                 }
-            },
-        });
-        Index {
-            name: name.to_string(),
-            list: have_field.into_iter().rev().map(|(key, _)| key).collect(),
-        }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{AuthQueryPart, ChannelQueryPart, EntriesQueryPart, QueryPart, SettingsPart, Subscription, UserQuery, WebhookQueryPart};
+
+    fn entries_query() -> EntriesQueryPart {
+        serde_json::from_value(serde_json::json!({"message": "gov prpsl all", "display": "default", "indices": ["proposal_status_voting"], "filter": [], "order_by": "", "limit": 10})).unwrap()
+    }
+
+    #[test]
+    pub fn query_keys_exclude_secrets_and_pagination() {
+        let auth = |token: &str| QueryPart::AuthQueryPart(AuthQueryPart { token: token.to_string(), user_hash: 1 });
+        assert_eq!(auth("a").get_hash(), auth("b").get_hash());
+        let webhook = |secret: &str| QueryPart::WebhookQueryPart(WebhookQueryPart { url: "https://example.com".to_string(), secret: secret.to_string() });
+        assert_eq!(webhook("a").get_hash(), webhook("b").get_hash());
+        let email = |address: &str| QueryPart::ChannelQueryPart(ChannelQueryPart::Email { address: address.to_string() });
+        assert_eq!(email("a@example.com").get_hash(), email("b@example.com").get_hash());
+
+        let query = |token: Option<&str>| UserQuery {
+            query_part: auth("a"),
+            settings_part: SettingsPart { user_hash: Some(1), token: token.map(|x| x.to_string()), ..SettingsPart::default() },
+        };
+        assert_eq!(query(None).get_hash(), query(Some("token")).get_hash());
+
+        let first_page = entries_query();
+        let second_page = EntriesQueryPart { offset: 10, cursor: Some(vec![1]), ..entries_query() };
+        assert_eq!(first_page.get_hash(), second_page.get_hash());
+        assert_ne!(first_page.get_hash(), EntriesQueryPart { limit: 5, ..entries_query() }.get_hash());
+        // an entries query has the key of its subscription.
+        assert_eq!(Subscription::get_key_for_entries_query(&first_page)[12..], QueryPart::EntriesQueryPart(first_page).get_hash().to_be_bytes());
     }
 }
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use cosmos_rust_package::chrono::Utc;
 use crate::utils::entry::*;
+use crate::utils::entry::search::add_search_index;
+use crate::utils::entry::search::vector::VectorIndex;
 use strum::IntoEnumIterator;
 use crate::utils::entry::db::{RetrievalMethod, TaskMemoryStore};
 use crate::utils::response::{ResponseResult, BlockchainQuery, FraudClassification, ProposalDataResult};
@@ -12,10 +14,16 @@ use crate::blockchain::cosmos::gov::{get_key_for_params, get_key_for_tally_resul
 use crate::blockchain::cosmos::staking::get_key_for_pool;
 use crate::services::fraud_detection::get_key_for_fraud_detection;
 use crate::services::gpt3::get_key_for_gpt3;
+use crate::services::proposal_embedding::ProposalEmbeddingIndex;
 
 
 const PROPOSAL_DATA_RESULT: &str = "ProposalDataResult";
 
+const NUMBER_OF_SIMILAR_PROPOSALS: usize = 5;
+const MIN_SIMILARITY: f32 = 0.8;
+// near-duplicates, e.g. the same text with a changed recipient address or amount.
+const MIN_RESUBMISSION_SIMILARITY: f32 = 0.97;
+
 /// # Governance Proposal Notifications
 ///
 /// This method generates the entries for the governance proposal notifications.
@@ -34,6 +42,7 @@ pub fn governance_proposal_notifications(task_store: &TaskMemoryStore) -> Vec<Co
     });
 
     CosmosRustBotValue::add_variants_of_memberships(&mut view, vec!["proposal_blockchain","proposal_status","proposal_type"]);
+    add_search_index(&mut view, vec!["proposal_title","proposal_description"]);
     view
 }
 /// # Adds proposals
@@ -48,6 +57,8 @@ fn add_proposals(view: &mut Vec<CosmosRustBotValue>, task_store: &TaskMemoryStor
 
     //let mut proposals_for_csv: Vec<ProposalData> = Vec::new();
 
+    let embedding_index = ProposalEmbeddingIndex::load(task_store);
+
     let mut list_proposal_hash: Vec<u64> = if let Ok(Maybe { data: Ok(ResponseResult::ProposalDataResult(ProposalDataResult{list_proposal_hash: list})), timestamp}) = task_store.get(PROPOSAL_DATA_RESULT, &RetrievalMethod::Get){
         list
     }else{
@@ -137,7 +148,10 @@ fn add_proposals(view: &mut Vec<CosmosRustBotValue>, task_store: &TaskMemoryStor
                     tallying_param,
                     deposit_param,
                     voting_param,
-                    blockchain_pool
+                    blockchain_pool,
+                    embedding_index.similar(hash, NUMBER_OF_SIMILAR_PROPOSALS, MIN_SIMILARITY),
+                    embedding_index.resubmission_of(hash, MIN_RESUBMISSION_SIMILARITY),
+                    task_store.config()
                     );
 
                 if fraud_classification.is_some() || (proposal.status!=ProposalStatus::StatusVotingPeriod && proposal.status!=ProposalStatus::StatusDepositPeriod) {
@@ -154,6 +168,11 @@ fn add_proposals(view: &mut Vec<CosmosRustBotValue>, task_store: &TaskMemoryStor
                                             ValueImperative::Notify
                                         }
                         })));
+
+                        if let Some(vector) = embedding_index.index.get(&hash) {
+                            let entry_key = view.last().unwrap().key();
+                            view.push(CosmosRustBotValue::Embedding(Embedding { entry_key, vector: vector.clone() }));
+                        }
                 }
 
                 // proposals_for_csv.push(data);
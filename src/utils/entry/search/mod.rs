@@ -0,0 +1,190 @@
+pub mod vector;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::utils::entry::{CosmosRustBotValue, SearchIndex};
+
+// Full-text search over entry fields (e.g. proposal titles and descriptions).
+//
+// The inverted index is part of the view, one `SearchIndex::Term` per token and one `SearchIndex::Documents`
+// with the document lengths. Queries are ranked with BM25.
+
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+const STOP_WORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "has", "have", "in", "is", "it", "its", "of",
+    "on", "or", "that", "the", "this", "to", "was", "were", "will", "with", "we", "our", "you", "your", "not", "but",
+];
+
+/// Splits the text into lowercase, stemmed tokens. Stop words are dropped.
+/// Tokens containing digits (ids, addresses, amounts) are kept as they are.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .filter(|x| !STOP_WORDS.contains(&x.as_str()))
+        .map(|x| if x.chars().any(|c| c.is_ascii_digit()) { x } else { stem(&x) })
+        .collect()
+}
+
+fn is_vowel(word: &[char], i: usize) -> bool {
+    match word[i] {
+        'a' | 'e' | 'i' | 'o' | 'u' => true,
+        'y' => i > 0 && !is_vowel(word, i - 1),
+        _ => false,
+    }
+}
+
+fn contains_vowel(word: &[char]) -> bool {
+    (0..word.len()).any(|i| is_vowel(word, i))
+}
+
+fn ends_with_double_consonant(word: &[char]) -> bool {
+    let n = word.len();
+    n >= 2 && word[n - 1] == word[n - 2] && !is_vowel(word, n - 1)
+}
+
+/// Light English stemmer (steps 1a-1c of the Porter stemmer),
+/// enough to match plurals and verb forms like "incentives"/"incentive" or "upgraded"/"upgrade".
+pub fn stem(word: &str) -> String {
+    let mut w: Vec<char> = word.chars().collect();
+    if w.len() <= 3 || !w.iter().all(|c| c.is_ascii_alphabetic()) {
+        return word.to_string();
+    }
+    let ends_with = |w: &Vec<char>, suffix: &str| w.len() > suffix.len() && w.iter().rev().zip(suffix.chars().rev()).all(|(a, b)| *a == b);
+    let truncate = |w: &mut Vec<char>, n: usize| { let len = w.len() - n; w.truncate(len); };
+
+    // step 1a
+    if ends_with(&w, "sses") || ends_with(&w, "ies") {
+        truncate(&mut w, 2);
+    } else if ends_with(&w, "s") && !ends_with(&w, "ss") && !ends_with(&w, "us") {
+        truncate(&mut w, 1);
+    }
+
+    // step 1b
+    let mut restore_e = false;
+    if ends_with(&w, "eed") {
+        truncate(&mut w, 1);
+    } else if ends_with(&w, "ed") && contains_vowel(&w[..w.len() - 2]) {
+        truncate(&mut w, 2);
+        restore_e = true;
+    } else if ends_with(&w, "ing") && contains_vowel(&w[..w.len() - 3]) {
+        truncate(&mut w, 3);
+        restore_e = true;
+    }
+    if restore_e {
+        if ends_with(&w, "at") || ends_with(&w, "bl") || ends_with(&w, "iz") {
+            w.push('e');
+        } else if ends_with_double_consonant(&w) && !matches!(w[w.len() - 1], 'l' | 's' | 'z') {
+            w.pop();
+        } else if w.len() == 3 && !is_vowel(&w, 0) && is_vowel(&w, 1) && !is_vowel(&w, 2) && !matches!(w[2], 'w' | 'x' | 'y') {
+            w.push('e');
+        }
+    }
+
+    // step 1c
+    if ends_with(&w, "y") && contains_vowel(&w[..w.len() - 1]) {
+        truncate(&mut w, 1);
+        w.push('i');
+    }
+
+    // "incentive" and "incentives" both end up as "incentiv"
+    if ends_with(&w, "e") && w.len() > 4 {
+        w.pop();
+    }
+    w.into_iter().collect()
+}
+
+/// Adds the inverted index over the given text fields of all entries in the view.
+pub fn add_search_index(view: &mut Vec<CosmosRustBotValue>, fields: Vec<&str>) {
+    let mut postings: HashMap<String, Vec<(Vec<u8>, u32)>> = HashMap::new();
+    let mut lengths: Vec<(Vec<u8>, u32)> = Vec::new();
+
+    for item in view.iter().filter(|x| matches!(x, CosmosRustBotValue::Entry(_))) {
+        let text = fields.iter()
+            .filter_map(|field| item.get(field).as_str().map(|x| x.to_string()))
+            .collect::<Vec<String>>()
+            .join("\n");
+        if text.is_empty() {
+            continue;
+        }
+        let key = item.key();
+        let tokens = tokenize(&text);
+        let mut term_frequencies: HashMap<String, u32> = HashMap::new();
+        for token in &tokens {
+            *term_frequencies.entry(token.to_owned()).or_insert(0) += 1;
+        }
+        for (term, tf) in term_frequencies {
+            postings.entry(term).or_insert_with(Vec::new).push((key.clone(), tf));
+        }
+        lengths.push((key, tokens.len() as u32));
+    }
+
+    // sorted, so that the key of an unchanged term stays the same.
+    lengths.sort_unstable();
+    for (term, mut list) in postings {
+        list.sort_unstable();
+        view.push(CosmosRustBotValue::SearchIndex(SearchIndex::Term { term, postings: list }));
+    }
+    view.push(CosmosRustBotValue::SearchIndex(SearchIndex::Documents { lengths }));
+}
+
+/// Ranks the documents containing at least one of the query terms by BM25, best match first.
+/// `get_term` returns the postings of a term, if it is indexed.
+pub fn rank<F>(query: &str, lengths: &Vec<(Vec<u8>, u32)>, get_term: F) -> Vec<(Vec<u8>, f64)>
+    where F: Fn(&str) -> Option<Vec<(Vec<u8>, u32)>>
+{
+    let n = lengths.len() as f64;
+    if n == 0.0 {
+        return Vec::new();
+    }
+    let document_lengths: HashMap<&Vec<u8>, u32> = lengths.iter().map(|(k, l)| (k, *l)).collect();
+    let avg_length = lengths.iter().map(|(_, l)| *l as f64).sum::<f64>() / n;
+
+    let mut scores: HashMap<Vec<u8>, f64> = HashMap::new();
+    let terms = tokenize(query).into_iter().collect::<HashSet<String>>();
+    for term in terms {
+        if let Some(postings) = get_term(&term) {
+            let df = postings.len() as f64;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (key, tf) in postings {
+                let length = *document_lengths.get(&key).unwrap_or(&0) as f64;
+                let tf = tf as f64;
+                let score = idf * (tf * (BM25_K1 + 1.0)) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * length / avg_length));
+                *scores.entry(key).or_insert(0.0) += score;
+            }
+        }
+    }
+    let mut ranked = scores.into_iter().collect::<Vec<(Vec<u8>, f64)>>();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{rank, stem, tokenize};
+
+    #[test]
+    pub fn tokenize_and_stem() {
+        assert_eq!(tokenize("Osmosis Incentives for the pools"), vec!["osmosi", "incentiv", "pool"]);
+        assert_eq!(stem("incentive"), stem("incentives"));
+        assert_eq!(stem("upgraded"), stem("upgrade"));
+        assert_eq!(stem("stopping"), "stop");
+        assert_eq!(tokenize("send to osmo1x2w87cvt5mqjncav4lxy8yfreynn273x34qlwy"), vec!["send", "osmo1x2w87cvt5mqjncav4lxy8yfreynn273x34qlwy"]);
+    }
+
+    #[test]
+    pub fn rank_by_bm25() {
+        let lengths = vec![(b"a".to_vec(), 10), (b"b".to_vec(), 10), (b"c".to_vec(), 100)];
+        let get_term = |term: &str| match term {
+            "osmosi" => Some(vec![(b"a".to_vec(), 1), (b"c".to_vec(), 1)]),
+            "incentiv" => Some(vec![(b"a".to_vec(), 2), (b"b".to_vec(), 1), (b"c".to_vec(), 2)]),
+            _ => None,
+        };
+        let ranked = rank("osmosis incentives", &lengths, get_term);
+        assert_eq!(ranked.iter().map(|x| x.0.clone()).collect::<Vec<Vec<u8>>>(), vec![b"a".to_vec(), b"c".to_vec(), b"b".to_vec()]);
+        assert!(rank("unknown", &lengths, get_term).is_empty());
+    }
+}
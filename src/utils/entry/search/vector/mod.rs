@@ -0,0 +1,172 @@
+// Nearest neighbour search over embeddings.
+//
+// `BruteForceIndex` compares the query with every vector, which is fast enough for a few thousand proposals.
+// An approximate index (e.g. HNSW) can implement `VectorIndex` once this is no longer the case.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+
+pub trait VectorIndex<K> {
+    fn insert(&mut self, key: K, vector: Vec<f32>);
+    /// Returns the `k` most similar keys with their cosine similarity, best match first.
+    fn nearest(&self, query: &[f32], k: usize) -> Vec<(K, f32)> {
+        self.nearest_matching(query, k, |_, _| true)
+    }
+    /// Like `nearest`, only keys accepted by `filter` (key, similarity) are considered.
+    fn nearest_matching<F: Fn(&K, f32) -> bool>(&self, query: &[f32], k: usize, filter: F) -> Vec<(K, f32)>;
+    fn get(&self, key: &K) -> Option<&Vec<f32>>;
+}
+
+pub struct BruteForceIndex<K> {
+    items: Vec<(K, Vec<f32>)>,
+    // position of each key in `items`.
+    positions: HashMap<K, usize>,
+}
+
+impl<K> BruteForceIndex<K> {
+    pub fn new() -> Self {
+        BruteForceIndex { items: Vec::new(), positions: HashMap::new() }
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+}
+
+impl<K: Clone + Eq + Hash> VectorIndex<K> for BruteForceIndex<K> {
+    fn insert(&mut self, key: K, vector: Vec<f32>) {
+        // vectors are normalized once, the similarity is then a dot product.
+        let vector = normalize(vector);
+        match self.positions.get(&key) {
+            Some(position) => { self.items[*position].1 = vector; },
+            None => {
+                self.positions.insert(key.clone(), self.items.len());
+                self.items.push((key, vector));
+            },
+        }
+    }
+
+    fn nearest_matching<F: Fn(&K, f32) -> bool>(&self, query: &[f32], k: usize, filter: F) -> Vec<(K, f32)> {
+        if k == 0 {
+            return Vec::new();
+        }
+        let query = normalize(query.to_vec());
+        // min-heap of the best `k` so far, the worst of them is replaced.
+        let mut best: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        for (position, (key, vector)) in self.items.iter().enumerate() {
+            let similarity = dot_product(&query, vector);
+            if similarity.is_nan() || !filter(key, similarity) {
+                continue;
+            }
+            let candidate = Candidate { similarity, position };
+            if best.len() < k {
+                best.push(Reverse(candidate));
+            } else if best.peek().map(|worst| candidate > worst.0).unwrap_or(false) {
+                best.pop();
+                best.push(Reverse(candidate));
+            }
+        }
+        best.into_sorted_vec().into_iter()
+            .map(|Reverse(candidate)| (self.items[candidate.position].0.clone(), candidate.similarity))
+            .collect()
+    }
+
+    fn get(&self, key: &K) -> Option<&Vec<f32>> {
+        self.positions.get(key).map(|position| &self.items[*position].1)
+    }
+}
+
+// ordered by similarity, ties by insertion order (earlier first).
+struct Candidate {
+    similarity: f32,
+    position: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity.total_cmp(&other.similarity).then_with(|| other.position.cmp(&self.position))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Cosine similarity of two vectors of the index, which are normalized on insert.
+pub fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    dot_product(a, b)
+}
+
+fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>()
+}
+
+pub fn normalize(vector: Vec<f32>) -> Vec<f32> {
+    let norm = dot_product(&vector, &vector).sqrt();
+    if norm == 0.0 {
+        return vector;
+    }
+    vector.into_iter().map(|x| x / norm).collect()
+}
+
+/// Averages the chunk embeddings of a document into one normalized document embedding.
+pub fn mean_pool(vectors: &Vec<Vec<f32>>) -> Option<Vec<f32>> {
+    let dimension = vectors.first()?.len();
+    if dimension == 0 || vectors.iter().any(|x| x.len() != dimension) {
+        return None;
+    }
+    let mut sum = vec![0f32; dimension];
+    for vector in vectors {
+        for (s, x) in sum.iter_mut().zip(vector) {
+            *s += x;
+        }
+    }
+    Some(normalize(sum))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{mean_pool, BruteForceIndex, VectorIndex};
+
+    #[test]
+    pub fn nearest_by_cosine_similarity() {
+        let mut index = BruteForceIndex::new();
+        index.insert("x", vec![2.0, 0.0]);
+        index.insert("y", vec![0.0, 1.0]);
+        index.insert("xy", vec![1.0, 1.0]);
+        index.insert("zero", vec![0.0, 0.0]);
+
+        let nearest = index.nearest(&[1.0, 0.1], 10);
+        // the zero vector has no direction, its similarity is 0.
+        assert_eq!(nearest.iter().map(|(key, _)| *key).collect::<Vec<&str>>(), vec!["x", "xy", "y", "zero"]);
+        assert!((nearest[0].1 - 0.995).abs() < 0.001);
+        assert_eq!(index.nearest(&[1.0, 0.1], 2), nearest[..2].to_vec());
+        assert!(index.nearest(&[1.0, 0.1], 0).is_empty());
+        assert_eq!(index.nearest_matching(&[1.0, 0.1], 2, |key, similarity| *key != "x" && similarity > 0.5).iter().map(|(key, _)| *key).collect::<Vec<&str>>(), vec!["xy"]);
+
+        // re-inserting a key replaces its vector.
+        index.insert("x", vec![0.0, -1.0]);
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get(&"x"), Some(&vec![0.0, -1.0]));
+        assert_eq!(index.nearest(&[1.0, 0.1], 1)[0].0, "xy");
+    }
+
+    #[test]
+    pub fn mean_pool_is_normalized() {
+        let pooled = mean_pool(&vec![vec![3.0, 0.0], vec![0.0, 3.0]]).unwrap();
+        assert!(pooled.iter().all(|x| (x - 0.5f32.sqrt()).abs() < 1e-6));
+        assert_eq!(mean_pool(&vec![vec![1.0, 0.0], vec![1.0]]), None);
+        assert_eq!(mean_pool(&vec![]), None);
+    }
+}
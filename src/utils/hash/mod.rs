@@ -0,0 +1,57 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use bincode::Options;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::Serialize;
+
+// Hashes used for persisted keys must not change between toolchains or architectures.
+// `DefaultHasher` and the byte stream of `std::hash::Hash` (string terminators, length prefixes and
+// enum discriminants) give no such guarantee, therefore all keys are derived from a truncated SHA-256
+// of the bincode encoding with fixed-width integers in little-endian byte order.
+// Types that exclude fields from their identity (e.g. secrets) hash an explicit tuple of the other fields.
+
+fn canonical_bytes<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .serialize(value)
+        // only sequences of unknown length fail, keys never contain them.
+        .expect("key values have a bincode encoding")
+}
+
+pub fn stable_hash<T: Serialize + ?Sized>(value: &T) -> u64 {
+    let mut digest = Sha256::new();
+    digest.input(&canonical_bytes(value));
+    let mut out = [0u8; 32];
+    digest.result(&mut out);
+    u64::from_be_bytes(out[..8].try_into().unwrap())
+}
+
+// lowercase, e.g. for digests and keys in URLs.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// only used to locate keys written before the switch to `stable_hash`.
+pub fn legacy_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    value.hash(&mut s);
+    s.finish()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::stable_hash;
+
+    #[test]
+    pub fn stable_hash_is_fixed() {
+        // SHA-256 of the little-endian u64.
+        assert_eq!(stable_hash(&42u64), 17078935153597280966);
+        // SHA-256 of the u64 length followed by the bytes, the same for &str and String.
+        assert_eq!(stable_hash("cosmos"), 13846735128284809200);
+        assert_eq!(stable_hash(&"cosmos".to_string()), 13846735128284809200);
+        assert_eq!(stable_hash(&vec![1u8, 2, 3]), stable_hash(&[1u8, 2, 3][..]));
+    }
+}
@@ -1,5 +1,7 @@
 
 pub mod entry;
+pub mod hash;
+pub mod config;
 
 #[cfg(any(feature = "interface", feature = "postproc"))]
 pub mod response;
@@ -17,6 +19,10 @@ pub fn duration_to_string(duration: cosmos_rust_package::chrono::Duration) -> St
     let minutes = (duration.num_seconds() / 60) % 60;
     format!("{}d, {}h, {}m",days, hours, minutes)
 }
+// text and attribute values in generated HTML (proposal pages, emails, Matrix messages).
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
 pub fn timestamp_now_to_string() -> String {
     let dt = Utc::now();//.timestamp()
     let now = dt.format("%d/%m/%y %H:%M:%S");
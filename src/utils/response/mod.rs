@@ -23,6 +23,7 @@ pub enum ResponseResult {
     ProposalDataResult(ProposalDataResult),
     LinkToTextResult(LinkToTextResult),
     LinkToTextResultStatus(LinkToTextResultStatus),
+    ProposalEmbedding(ProposalEmbedding),
 }
 
 #[derive(Serialize,Deserialize,Debug, Clone)]
@@ -110,6 +111,12 @@ impl LinkToTextResult {
     }
 }
 
+// mean of the embeddings of the title and description chunks.
+#[derive(Serialize,Deserialize,Debug, Clone)]
+pub struct ProposalEmbedding {
+    pub embedding: Vec<f32>,
+}
+
 #[derive(Serialize,Deserialize,Debug, Clone)]
 pub struct LinkToTextResultStatus {
     pub number_of_results: usize,